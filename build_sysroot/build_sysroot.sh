@@ -21,6 +21,8 @@ fi
 # Build libs
 mkdir -p sysroot/lib/rustlib/$TARGET_TRIPLE/lib/
 export RUSTFLAGS="$RUSTFLAGS -Z force-unstable-if-unmarked --sysroot sysroot"
+# The sysroot is built with panic=unwind, so it can be used with both panic strategies
+export RUSTC_WRAPPER="$(pwd)/rustc_wrapper.sh"
 if [[ "$1" == "--release" ]]; then
     channel='release'
     RUSTFLAGS="$RUSTFLAGS -Zmir-opt-level=3" cargo build --target $TARGET_TRIPLE --release
@@ -0,0 +1,16 @@
+#!/bin/bash
+# Used as `RUSTC_WRAPPER` by build_sysroot.sh. Cargo can't set the panic strategy per crate, but
+# like when building the official sysroot the panic_abort runtime has to be compiled with
+# `-Cpanic=abort` for crates using panic=abort to link against it.
+rustc="$1"
+shift
+
+prev_arg=""
+for arg in "$@"; do
+    if [[ "$prev_arg" == "--crate-name" && "$arg" == "panic_abort" ]]; then
+        exec "$rustc" "$@" -Cpanic=abort
+    fi
+    prev_arg="$arg"
+done
+
+exec "$rustc" "$@"
@@ -18,5 +18,6 @@ else
     cargo build
 fi
 
-export RUSTFLAGS='-Zalways-encode-mir -Cpanic=abort -Cdebuginfo=2 -Zcodegen-backend='$(pwd)'/target/'$channel'/librustc_codegen_cranelift.'$dylib_ext
-RUSTC="rustc $RUSTFLAGS -L crate=target/out --out-dir target/out"
+export RUSTFLAGS='-Zalways-encode-mir -Cdebuginfo=2 -Zcodegen-backend='$(pwd)'/target/'$channel'/librustc_codegen_cranelift.'$dylib_ext
+RUSTC="rustc $RUSTFLAGS -Cpanic=abort -L crate=target/out --out-dir target/out"
+RUSTC_UNWIND="rustc $RUSTFLAGS -Cpanic=unwind -L crate=target/out --out-dir target/out"
@@ -0,0 +1,39 @@
+// Compiled with panic=unwind. Checks that panics unwind through frames with values that need to be
+// dropped and are caught by `catch_unwind`.
+
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountDrop;
+
+impl Drop for CountDrop {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[inline(never)]
+fn check_small(value: usize) -> usize {
+    let _guard = CountDrop;
+    if value > 1 {
+        panic!("value too large: {}", value);
+    }
+    value
+}
+
+fn main() {
+    assert_eq!(panic::catch_unwind(|| check_small(1)).unwrap(), 1);
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+    // Use the argument count to prevent const propagation from removing the panic
+    let value = std::env::args().count() + 1;
+    let payload = panic::catch_unwind(|| check_small(value)).unwrap_err();
+    assert_eq!(
+        payload.downcast_ref::<String>().map(|msg| &**msg),
+        Some("value too large: 2")
+    );
+    // The guard was dropped while unwinding out of `check_small`
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+}
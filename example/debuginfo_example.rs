@@ -0,0 +1,20 @@
+// Built with `-Cdebuginfo=2 -O` by test.sh, which checks the emitted debuginfo using readelf.
+
+mod inner {
+    #[inline(never)]
+    pub fn sum_of_squares(count: usize) -> usize {
+        let mut sum = 0;
+        for i in 0..count {
+            let square = i * i;
+            sum += square;
+        }
+        sum
+    }
+}
+
+fn main() {
+    let count = std::env::args().count() + 3;
+    let sum = inner::sum_of_squares(count);
+    assert_eq!(sum, 14);
+    println!("{}", sum);
+}
@@ -0,0 +1,13 @@
+// Used as both staticlib and cdylib by test.sh
+
+#[no_mangle]
+pub extern "C" fn ffi_lib_sum(ptr: *const u32, len: usize) -> u32 {
+    let slice = unsafe { std::slice::from_raw_parts(ptr, len) };
+    slice.iter().map(|&n| add(n, 0)).sum()
+}
+
+// Not exported from the cdylib
+#[inline(never)]
+pub fn add(a: u32, b: u32) -> u32 {
+    a + b
+}
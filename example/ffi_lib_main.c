@@ -0,0 +1,12 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <stddef.h>
+
+uint32_t ffi_lib_sum(const uint32_t *ptr, size_t len);
+
+int main() {
+    uint32_t nums[] = {1, 2, 3, 4};
+    uint32_t sum = ffi_lib_sum(nums, 4);
+    printf("%u\n", sum);
+    return sum == 10 ? 0 : 1;
+}
@@ -0,0 +1,20 @@
+// Built twice by test.sh with `-Cincremental`. The value returned by `changed::value` is modified
+// in between, so only the cgu of the `changed` module has to be recompiled.
+
+mod unchanged {
+    #[inline(never)]
+    pub fn value() -> u32 {
+        40
+    }
+}
+
+mod changed {
+    #[inline(never)]
+    pub fn value() -> u32 {
+        1
+    }
+}
+
+fn main() {
+    println!("{}", unchanged::value() + changed::value());
+}
@@ -0,0 +1,31 @@
+// Every function called by the spawned threads is compiled lazily on its first call, which has to
+// happen on the rustc thread owning the `TyCtxt`.
+
+use std::sync::mpsc;
+use std::thread;
+
+fn square(x: u64) -> u64 {
+    x * x
+}
+
+fn main() {
+    let (tx, rx) = mpsc::channel();
+    let threads = (0..4u64)
+        .map(|i| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                tx.send(square(i)).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut squares = rx.iter().collect::<Vec<_>>();
+    squares.sort();
+    assert_eq!(squares, [0, 1, 4, 9]);
+    println!("{:?}", squares);
+}
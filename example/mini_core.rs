@@ -293,6 +293,14 @@ pub fn panic(&(_msg, _file, _line, _col): &(&'static str, &'static str, u32, u32
     }
 }
 
+#[lang = "panic_bounds_check"]
+fn panic_bounds_check(&(_file, _line, _col): &(&'static str, u32, u32), _index: usize, _len: usize) -> ! {
+    unsafe {
+        libc::puts("Panicking\0" as *const str as *const u8);
+        intrinsics::abort();
+    }
+}
+
 #[lang = "eh_personality"]
 fn eh_personality() -> ! {
     loop {}
@@ -128,6 +128,7 @@ fn main() {
 
     unsafe {
         printf("Hello %s\n\0" as *const str as *const i8, "printf\0" as *const str as *const i8);
+        printf("%d %.1f %.2f\n\0" as *const str as *const i8, 42i32, 0.5f64, 1.25f64);
 
         let hello: &[u8] = b"Hello\0" as &[u8; 6];
         let ptr: *const u8 = hello as *const [u8] as *const u8;
@@ -0,0 +1,9 @@
+// Checks the message and location printed when an `Assert` terminator fails.
+
+fn main() {
+    let array = [1u8, 2, 3];
+    // Use the argument count as index to prevent const propagation from catching the error
+    let index = std::env::args().count() + 2;
+    let value = array[index];
+    std::process::exit(value as i32);
+}
@@ -0,0 +1,20 @@
+// Compiled as proc-macro by test.sh and used by proc_macro_example.rs
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+
+#[proc_macro_derive(Answer)]
+pub fn derive_answer(input: TokenStream) -> TokenStream {
+    let input = input.to_string();
+    let name = input
+        .split_whitespace()
+        .skip_while(|&word| word != "struct")
+        .nth(1)
+        .unwrap()
+        .trim_end_matches(';');
+
+    format!("impl {} {{ fn answer() -> u32 {{ 42 }} }}", name)
+        .parse()
+        .unwrap()
+}
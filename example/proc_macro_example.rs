@@ -0,0 +1,9 @@
+#[macro_use]
+extern crate proc_macro_derive;
+
+#[derive(Answer)]
+struct Question;
+
+fn main() {
+    assert_eq!(Question::answer(), 42);
+}
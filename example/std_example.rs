@@ -1,4 +1,4 @@
-#![feature(core_intrinsics)]
+#![feature(core_intrinsics, repr_simd, platform_intrinsics)]
 
 use std::io::Write;
 
@@ -17,6 +17,90 @@ fn main() {
     ONCE.call_once(|| {});
 
     LoopState::Continue(()) == LoopState::Break(());
+
+    let a: u128 = 0xFFFF_FFFF_FFFF_FFFF_0000_0000_0000_0001;
+    let b: u128 = 0x1_0000_0000;
+    assert_eq!(a.wrapping_mul(b), 0xFFFF_FFFF_0000_0000_0000_0001_0000_0000);
+    assert_eq!(a / b, 0xFFFF_FFFF_FFFF_FFFF_0000_0000);
+    assert_eq!(a % b, 1);
+    assert_eq!(a >> 64, 0xFFFF_FFFF_FFFF_FFFF);
+    assert_eq!(1u128 << 100, 0x10_0000_0000_0000_0000_0000_0000);
+    assert!(a > b);
+    assert_eq!((-1i128).leading_zeros(), 0);
+    assert_eq!(b.trailing_zeros(), 32);
+    assert_eq!((-5i128) as f64, -5.0);
+    assert_eq!(1e20f64 as u128, 100_000_000_000_000_000_000);
+    println!("{}", 2u128.pow(100));
+
+    assert_eq!(200u8.checked_add(56), None);
+    assert_eq!(200u8.checked_add(55), Some(255));
+    assert_eq!((-100i8).checked_sub(29), None);
+    assert_eq!(i32::min_value().checked_mul(-1), None);
+    assert_eq!(0x1_0000_0000u64.checked_mul(0x1_0000_0000), None);
+    assert_eq!((-1i64).checked_mul(i64::min_value()), None);
+    assert_eq!(1u16.checked_shl(16), None);
+    assert_eq!(a.checked_add(a), None);
+    assert_eq!(a.checked_mul(b), None);
+    assert_eq!(b.checked_mul(b), Some(0x1_0000_0000_0000_0000));
+    assert_eq!(i128::min_value().checked_sub(1), None);
+    assert_eq!(i128::max_value().checked_mul(-1), Some(-i128::max_value()));
+    assert_eq!(1u128.checked_shr(128), None);
+    assert_eq!(neg_i128(1 << 100), -0x10_0000_0000_0000_0000_0000_0000);
+    assert_eq!(wide_discr(Wide::Neg), -1);
+    assert_eq!(wide_discr(Wide::Big), 1 << 100);
+
+    test_simd();
+}
+
+#[inline(never)]
+fn neg_i128(a: i128) -> i128 {
+    -a
+}
+
+#[repr(i128)]
+#[derive(Copy, Clone)]
+enum Wide {
+    Neg = -1,
+    Big = 1 << 100,
+}
+
+#[inline(never)]
+fn wide_discr(wide: Wide) -> i128 {
+    wide as i128
+}
+
+#[repr(simd)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct i32x4(i32, i32, i32, i32);
+
+#[repr(simd)]
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct f32x4(f32, f32, f32, f32);
+
+extern "platform-intrinsic" {
+    fn simd_add<T>(x: T, y: T) -> T;
+    fn simd_mul<T>(x: T, y: T) -> T;
+    fn simd_eq<T, U>(x: T, y: T) -> U;
+    fn simd_cast<T, U>(x: T) -> U;
+    fn simd_shuffle4<T, U>(x: T, y: T, idx: [u32; 4]) -> U;
+    fn simd_insert<T, E>(x: T, idx: u32, val: E) -> T;
+    fn simd_extract<T, E>(x: T, idx: u32) -> E;
+}
+
+fn test_simd() {
+    let a = i32x4(1, 2, 3, 4);
+    let b = i32x4(10, 2, 30, 4);
+    unsafe {
+        assert_eq!(simd_add(a, b), i32x4(11, 4, 33, 8));
+        assert_eq!(simd_mul(a, b), i32x4(10, 4, 90, 16));
+        assert_eq!(simd_eq::<i32x4, i32x4>(a, b), i32x4(0, -1, 0, -1));
+        assert_eq!(simd_cast::<i32x4, f32x4>(a), f32x4(1.0, 2.0, 3.0, 4.0));
+        assert_eq!(simd_shuffle4::<i32x4, i32x4>(a, b, [7, 0, 5, 2]), i32x4(4, 1, 2, 3));
+        assert_eq!(simd_insert(a, 2, 42i32), i32x4(1, 2, 42, 4));
+        assert_eq!(simd_extract::<i32x4, i32>(b, 2), 30);
+    }
 }
 
 #[derive(PartialEq)]
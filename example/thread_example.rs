@@ -0,0 +1,74 @@
+#![feature(atomic_min_max)]
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const THREADS: usize = 8;
+const ITERATIONS: usize = 100_000;
+
+thread_local! {
+    static LOCAL_COUNTER: Cell<usize> = Cell::new(0);
+}
+
+fn main() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicIsize::new(isize::min_value()));
+    let mutex_counter = Arc::new(Mutex::new(0usize));
+    let ready = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+
+    let threads = (0..THREADS)
+        .map(|thread_id| {
+            let counter = counter.clone();
+            let max = max.clone();
+            let mutex_counter = mutex_counter.clone();
+            let ready = ready.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                // Start all threads at the same time to maximize contention
+                while !ready.load(Ordering::Acquire) {
+                    thread::yield_now();
+                }
+
+                for i in 0..ITERATIONS {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    max.fetch_max((i * THREADS + thread_id) as isize, Ordering::SeqCst);
+                    *mutex_counter.lock().unwrap() += 1;
+                    LOCAL_COUNTER.with(|local| local.set(local.get() + 1));
+                }
+                assert_eq!(LOCAL_COUNTER.with(|local| local.get()), ITERATIONS);
+
+                // Increment once more using a compare exchange loop
+                let mut spin = 0usize;
+                while counter
+                    .compare_exchange_weak(spin, spin + 1, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_err()
+                {
+                    spin = counter.load(Ordering::SeqCst);
+                }
+
+                tx.send(thread_id).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+
+    ready.store(true, Ordering::Release);
+
+    let mut finished = rx.iter().collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    finished.sort();
+    assert_eq!(finished, (0..THREADS).collect::<Vec<_>>());
+    assert_eq!(counter.load(Ordering::SeqCst), THREADS * ITERATIONS + THREADS);
+    assert_eq!(max.load(Ordering::SeqCst), (ITERATIONS * THREADS - 1) as isize);
+    assert_eq!(*mutex_counter.lock().unwrap(), THREADS * ITERATIONS);
+    assert_eq!(LOCAL_COUNTER.with(|local| local.get()), 0);
+    assert_eq!(thread::current().name(), Some("main"));
+}
+
@@ -7,19 +7,91 @@ use rustc_target::spec::abi::Abi;
 
 use crate::prelude::*;
 
+mod sysv;
+
 #[derive(Copy, Clone, Debug)]
 enum PassMode {
     NoPass,
     ByVal(Type),
+    ByValPair(Type, Type),
     ByRef,
+    /// Only used by the C abi: the value is split into one or two eightbytes passed in registers.
+    Cast(Type, Option<Type>),
+    /// Only used by the C abi: the value is copied to the stack.
+    ByValStack(Size),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum EmptySinglePair<T> {
+    Empty,
+    Single(T),
+    Pair(T, T),
+}
+
+impl<T> IntoIterator for EmptySinglePair<T> {
+    type Item = T;
+    type IntoIter = EmptySinglePairIter<T>;
+
+    fn into_iter(self) -> EmptySinglePairIter<T> {
+        EmptySinglePairIter(self)
+    }
+}
+
+impl<T> EmptySinglePair<T> {
+    fn map<U>(self, mut f: impl FnMut(T) -> U) -> EmptySinglePair<U> {
+        match self {
+            Empty => Empty,
+            Single(v) => Single(f(v)),
+            Pair(a, b) => Pair(f(a), f(b)),
+        }
+    }
+}
+
+struct EmptySinglePairIter<T>(EmptySinglePair<T>);
+
+impl<T> Iterator for EmptySinglePairIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match std::mem::replace(&mut self.0, Empty) {
+            Empty => None,
+            Single(v) => Some(v),
+            Pair(a, b) => {
+                self.0 = Single(b);
+                Some(a)
+            }
+        }
+    }
 }
 
+impl<T: std::fmt::Debug> EmptySinglePair<T> {
+    fn assert_single(self) -> T {
+        match self {
+            Single(v) => v,
+            _ => panic!("Called assert_single on {:?}", self)
+        }
+    }
+
+    fn assert_pair(self) -> (T, T) {
+        match self {
+            Pair(a, b) => (a, b),
+            _ => panic!("Called assert_pair on {:?}", self)
+        }
+    }
+}
+
+use self::EmptySinglePair::*;
+
 impl PassMode {
-    fn get_param_ty(self, fx: &FunctionCx<impl Backend>) -> Type {
+    fn get_param_ty(self, tcx: TyCtxt) -> EmptySinglePair<Type> {
         match self {
-            PassMode::NoPass => unimplemented!("pass mode nopass"),
-            PassMode::ByVal(clif_type) => clif_type,
-            PassMode::ByRef => fx.pointer_type,
+            PassMode::NoPass => Empty,
+            PassMode::ByVal(clif_type) => Single(clif_type),
+            PassMode::ByValPair(a, b) => Pair(a, b),
+            PassMode::ByRef => Single(pointer_ty(tcx)),
+            PassMode::Cast(a, None) => Single(a),
+            PassMode::Cast(a, Some(b)) => Pair(a, b),
+            PassMode::ByValStack(_) => bug!("ByValStack has a variable amount of params"),
         }
     }
 }
@@ -31,7 +103,7 @@ pub fn scalar_to_clif_type(tcx: TyCtxt, scalar: Scalar) -> Type {
             Integer::I16 => types::I16,
             Integer::I32 => types::I32,
             Integer::I64 => types::I64,
-            Integer::I128 => unimpl!("u/i128"),
+            Integer::I128 => types::I128,
         }
         Primitive::Float(flt) => match flt {
             FloatTy::F32 => types::F32,
@@ -43,9 +115,14 @@ pub fn scalar_to_clif_type(tcx: TyCtxt, scalar: Scalar) -> Type {
 
 fn get_pass_mode<'a, 'tcx: 'a>(
     tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    abi: Abi,
     ty: Ty<'tcx>,
     is_return: bool,
 ) -> PassMode {
+    if sysv::is_sysv_c_abi(tcx, abi) {
+        return sysv::get_pass_mode(tcx, ty, is_return);
+    }
+
     let layout = tcx
         .layout_of(ParamEnv::reveal_all().and(ty))
         .unwrap();
@@ -68,8 +145,18 @@ fn get_pass_mode<'a, 'tcx: 'a>(
             }
             layout::Abi::Scalar(scalar) => PassMode::ByVal(scalar_to_clif_type(tcx, scalar.clone())),
 
-            // FIXME implement ScalarPair and Vector Abi in a cg_llvm compatible way
-            layout::Abi::ScalarPair(_, _) => PassMode::ByRef,
+            layout::Abi::ScalarPair(a, b) => {
+                let a = scalar_to_clif_type(tcx, a.clone());
+                let b = scalar_to_clif_type(tcx, b.clone());
+                if is_return && (a == types::I128 || b == types::I128) {
+                    // Cranelift can only return values in two registers
+                    PassMode::ByRef
+                } else {
+                    PassMode::ByValPair(a, b)
+                }
+            }
+
+            // FIXME implement Vector Abi in a cg_llvm compatible way
             layout::Abi::Vector { .. } => PassMode::ByRef,
 
             layout::Abi::Aggregate { .. } => PassMode::ByRef,
@@ -79,12 +166,24 @@ fn get_pass_mode<'a, 'tcx: 'a>(
 
 fn adjust_arg_for_abi<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    abi: Abi,
     arg: CValue<'tcx>,
-) -> Value {
-    match get_pass_mode(fx.tcx, arg.layout().ty, false) {
-        PassMode::NoPass => unimplemented!("pass mode nopass"),
-        PassMode::ByVal(_) => arg.load_scalar(fx),
-        PassMode::ByRef => arg.force_stack(fx),
+) -> Vec<Value> {
+    match get_pass_mode(fx.tcx, abi, arg.layout().ty, false) {
+        PassMode::NoPass => vec![],
+        PassMode::ByVal(_) => vec![arg.load_scalar(fx)],
+        PassMode::ByValPair(_, _) => {
+            let (a, b) = arg.load_value_pair(fx);
+            vec![a, b]
+        }
+        PassMode::ByRef => vec![arg.force_stack(fx)],
+        PassMode::Cast(a, b) => {
+            let types = Some(a).into_iter().chain(b).collect::<Vec<_>>();
+            sysv::load_eightbytes(fx, arg, &types)
+        }
+        PassMode::ByValStack(size) => {
+            sysv::load_eightbytes(fx, arg, &sysv::stack_word_types(size))
+        }
     }
 }
 
@@ -106,24 +205,30 @@ fn clif_sig_from_fn_sig<'a, 'tcx: 'a>(
             (CallConv::SystemV, inputs, sig.output())
         }
         Abi::System => bug!("system abi should be selected elsewhere"),
-        Abi::RustIntrinsic => (CallConv::SystemV, sig.inputs().to_vec(), sig.output()),
+        Abi::RustIntrinsic | Abi::PlatformIntrinsic => {
+            (CallConv::SystemV, sig.inputs().to_vec(), sig.output())
+        }
         _ => unimplemented!("unsupported abi {:?}", sig.abi),
     };
 
+    if sysv::is_sysv_c_abi(tcx, sig.abi) {
+        return sysv::clif_sig(tcx, &inputs, output);
+    }
+
     let inputs = inputs
         .into_iter()
-        .filter_map(|ty| match get_pass_mode(tcx, ty, false) {
-            PassMode::ByVal(clif_ty) => Some(clif_ty),
-            PassMode::NoPass => unimplemented!("pass mode nopass"),
-            PassMode::ByRef => Some(pointer_ty(tcx)),
-        });
+        .flat_map(|ty| get_pass_mode(tcx, sig.abi, ty, false).get_param_ty(tcx).into_iter());
 
-    let (params, returns) = match get_pass_mode(tcx, output, true) {
+    let (params, returns) = match get_pass_mode(tcx, sig.abi, output, true) {
         PassMode::NoPass => (inputs.map(AbiParam::new).collect(), vec![]),
         PassMode::ByVal(ret_ty) => (
             inputs.map(AbiParam::new).collect(),
             vec![AbiParam::new(ret_ty)],
         ),
+        PassMode::ByValPair(ret_ty_a, ret_ty_b) => (
+            inputs.map(AbiParam::new).collect(),
+            vec![AbiParam::new(ret_ty_a), AbiParam::new(ret_ty_b)],
+        ),
         PassMode::ByRef => {
             (
                 Some(pointer_ty(tcx)) // First param is place to put return val
@@ -134,6 +239,9 @@ fn clif_sig_from_fn_sig<'a, 'tcx: 'a>(
                 vec![],
             )
         }
+        mode @ PassMode::Cast(..) | mode @ PassMode::ByValStack(_) => {
+            bug!("{:?} for {:?} abi return", mode, sig.abi)
+        }
     };
 
     Signature {
@@ -188,16 +296,32 @@ pub fn ty_fn_sig<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>, ty: Ty<'tcx>) -> ty::FnS
     tcx.normalize_erasing_late_bound_regions(ParamEnv::reveal_all(), &sig)
 }
 
+/// Virtual calls pass the data pointer of `self` as thin pointer, like cg_llvm does
+fn virtual_fn_sig<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>, sig: FnSig<'tcx>) -> FnSig<'tcx> {
+    let thin_self = tcx.mk_mut_ptr(tcx.mk_unit());
+    tcx.mk_fn_sig(
+        iter::once(thin_self).chain(sig.inputs()[1..].iter().cloned()),
+        sig.output(),
+        sig.variadic,
+        sig.unsafety,
+        sig.abi,
+    )
+}
+
+/// For variadic functions the signature only contains the fixed arguments. Calls add the variadic
+/// arguments themselves, see `codegen_call_inner`.
 pub fn get_function_name_and_sig<'a, 'tcx>(
     tcx: TyCtxt<'a, 'tcx, 'tcx>,
     inst: Instance<'tcx>,
-    support_vararg: bool
 ) -> (String, Signature) {
     assert!(!inst.substs.needs_infer() && !inst.substs.has_param_types());
     let fn_ty = inst.ty(tcx);
     let fn_sig = ty_fn_sig(tcx, fn_ty);
-    if fn_sig.variadic && !support_vararg {
-        unimpl!("Variadic function definitions are not yet supported");
+    if fn_sig.variadic && !sysv::is_sysv_c_abi(tcx, fn_sig.abi) {
+        unimpl!(
+            "Calling variadic functions is only supported for the x86_64 System V C abi, found {:?}",
+            fn_sig.abi
+        );
     }
     let sig = clif_sig_from_fn_sig(tcx, fn_sig);
     (tcx.symbol_name(inst).as_str().to_string(), sig)
@@ -209,7 +333,7 @@ pub fn import_function<'a, 'tcx: 'a>(
     module: &mut Module<impl Backend>,
     inst: Instance<'tcx>,
 ) -> FuncId {
-    let (name, sig) = get_function_name_and_sig(tcx, inst, true);
+    let (name, sig) = get_function_name_and_sig(tcx, inst);
     module
         .declare_function(&name, Linkage::Import, &sig)
         .unwrap()
@@ -228,7 +352,7 @@ impl<'a, 'tcx: 'a, B: Backend + 'a> FunctionCx<'a, 'tcx, B> {
         func_ref
     }
 
-    fn lib_call(
+    pub fn lib_call(
         &mut self,
         name: &str,
         input_tys: Vec<types::Type>,
@@ -304,7 +428,7 @@ fn add_arg_comment<'a, 'tcx: 'a>(
     msg: &str,
     local: mir::Local,
     local_field: Option<usize>,
-    param: Option<Value>,
+    params: EmptySinglePair<Value>,
     pass_mode: PassMode,
     ssa: crate::analyze::Flags,
     ty: Ty<'tcx>,
@@ -314,21 +438,21 @@ fn add_arg_comment<'a, 'tcx: 'a>(
     } else {
         Cow::Borrowed("")
     };
-    let param = if let Some(param) = param {
-        Cow::Owned(format!("= {:?}", param))
-    } else {
-        Cow::Borrowed("-")
+    let params = match params {
+        Empty => Cow::Borrowed("-"),
+        Single(param) => Cow::Owned(format!("= {:?}", param)),
+        Pair(param_a, param_b) => Cow::Owned(format!("= {:?}, {:?}", param_a, param_b)),
     };
     let pass_mode = format!("{:?}", pass_mode);
     fx.add_global_comment(format!(
-        "{msg:5} {local:>3}{local_field:<5} {param:10} {pass_mode:20} {ssa:10} {ty:?}",
-        msg=msg, local=format!("{:?}", local), local_field=local_field, param=param, pass_mode=pass_mode, ssa=format!("{:?}", ssa), ty=ty,
+        "{msg:5} {local:>3}{local_field:<5} {params:10} {pass_mode:36} {ssa:10} {ty:?}",
+        msg=msg, local=format!("{:?}", local), local_field=local_field, params=params, pass_mode=pass_mode, ssa=format!("{:?}", ssa), ty=ty,
     ));
 }
 
 #[cfg(debug_assertions)]
 fn add_local_header_comment(fx: &mut FunctionCx<impl Backend>) {
-    fx.add_global_comment(format!("msg   loc.idx    param    pass mode            ssa flags  ty"));
+    fx.add_global_comment(format!("msg   loc.idx    param    pass mode                            ssa flags  ty"));
 }
 
 fn local_place<'a, 'tcx: 'a>(
@@ -378,17 +502,39 @@ fn cvalue_for_param<'a, 'tcx: 'a>(
     ssa_flags: crate::analyze::Flags,
 ) -> CValue<'tcx> {
     let layout = fx.layout_of(arg_ty);
-    let pass_mode = get_pass_mode(fx.tcx, arg_ty, false);
-    let clif_type = pass_mode.get_param_ty(fx);
-    let ebb_param = fx.bcx.append_ebb_param(start_ebb, clif_type);
+    let pass_mode = get_pass_mode(fx.tcx, fx.self_sig().abi, arg_ty, false);
+
+    if let PassMode::ByValStack(size) = pass_mode {
+        let ebb_params = sysv::stack_word_types(size)
+            .into_iter()
+            .map(|t| fx.bcx.append_ebb_param(start_ebb, t))
+            .collect::<Vec<_>>();
+
+        #[cfg(debug_assertions)]
+        add_arg_comment(fx, "arg", local, local_field, Empty, pass_mode, ssa_flags, arg_ty);
+
+        return sysv::store_eightbytes(fx, &ebb_params, layout);
+    }
+
+    let clif_types = pass_mode.get_param_ty(fx.tcx);
+    let ebb_params = clif_types.map(|t| fx.bcx.append_ebb_param(start_ebb, t));
 
     #[cfg(debug_assertions)]
-    add_arg_comment(fx, "arg", local, local_field, Some(ebb_param), pass_mode, ssa_flags, arg_ty);
+    add_arg_comment(fx, "arg", local, local_field, ebb_params, pass_mode, ssa_flags, arg_ty);
 
     match pass_mode {
         PassMode::NoPass => unimplemented!("pass mode nopass"),
-        PassMode::ByVal(_) => CValue::ByVal(ebb_param, layout),
-        PassMode::ByRef => CValue::ByRef(ebb_param, layout),
+        PassMode::ByVal(_) => CValue::ByVal(ebb_params.assert_single(), layout),
+        PassMode::ByValPair(_, _) => {
+            let (a, b) = ebb_params.assert_pair();
+            CValue::ByValPair(a, b, layout)
+        }
+        PassMode::ByRef => CValue::ByRef(ebb_params.assert_single(), layout),
+        PassMode::Cast(_, _) => {
+            let ebb_params = ebb_params.into_iter().collect::<Vec<_>>();
+            sysv::store_eightbytes(fx, &ebb_params, layout)
+        }
+        PassMode::ByValStack(_) => unreachable!(),
     }
 }
 
@@ -402,17 +548,18 @@ pub fn codegen_fn_prelude<'a, 'tcx: 'a>(
     fx.add_global_comment(format!("ssa {:?}", ssa_analyzed));
 
     let ret_layout = fx.layout_of(fx.return_type());
-    let output_pass_mode = get_pass_mode(fx.tcx, fx.return_type(), true);
+    let output_pass_mode = get_pass_mode(fx.tcx, fx.self_sig().abi, fx.return_type(), true);
     let ret_param = match output_pass_mode {
         PassMode::NoPass => None,
-        PassMode::ByVal(_) => None,
+        PassMode::ByVal(_) | PassMode::ByValPair(_, _) | PassMode::Cast(_, _) => None,
         PassMode::ByRef => Some(fx.bcx.append_ebb_param(start_ebb, fx.pointer_type)),
+        PassMode::ByValStack(_) => unreachable!(),
     };
 
     #[cfg(debug_assertions)]
     {
         add_local_header_comment(fx);
-        add_arg_comment(fx, "ret", RETURN_PLACE, None, ret_param, output_pass_mode, ssa_analyzed[&RETURN_PLACE], ret_layout.ty);
+        add_arg_comment(fx, "ret", RETURN_PLACE, None, ret_param.map(Single).unwrap_or(Empty), output_pass_mode, ssa_analyzed[&RETURN_PLACE], ret_layout.ty);
     }
 
     enum ArgKind<'tcx> {
@@ -458,6 +605,8 @@ pub fn codegen_fn_prelude<'a, 'tcx: 'a>(
 
     fx.bcx.switch_to_block(start_ebb);
 
+    crate::unwind::codegen_landing_pads_prelude(fx);
+
     match output_pass_mode {
         PassMode::NoPass => {
             fx.local_map.insert(RETURN_PLACE, CPlace::NoPlace(ret_layout));
@@ -470,6 +619,9 @@ pub fn codegen_fn_prelude<'a, 'tcx: 'a>(
 
             local_place(fx, RETURN_PLACE, ret_layout, is_ssa);
         }
+        PassMode::ByValPair(_, _) | PassMode::Cast(_, _) => {
+            local_place(fx, RETURN_PLACE, ret_layout, false);
+        }
         PassMode::ByRef => {
             fx.local_map.insert(
                 RETURN_PLACE,
@@ -520,17 +672,18 @@ pub fn codegen_fn_prelude<'a, 'tcx: 'a>(
 pub fn codegen_terminator_call<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     func: &Operand<'tcx>,
-    args: &[Operand<'tcx>],
+    mir_args: &[Operand<'tcx>],
     destination: &Option<(Place<'tcx>, BasicBlock)>,
+    cleanup: Option<BasicBlock>,
 ) {
     let fn_ty = fx.monomorphize(&func.ty(fx.mir, fx.tcx));
     let sig = ty_fn_sig(fx.tcx, fn_ty);
 
     // Unpack arguments tuple for closures
     let args = if sig.abi == Abi::RustCall {
-        assert_eq!(args.len(), 2, "rust-call abi requires two arguments");
-        let self_arg = trans_operand(fx, &args[0]);
-        let pack_arg = trans_operand(fx, &args[1]);
+        assert_eq!(mir_args.len(), 2, "rust-call abi requires two arguments");
+        let self_arg = trans_operand(fx, &mir_args[0]);
+        let pack_arg = trans_operand(fx, &mir_args[1]);
         let mut args = Vec::new();
         args.push(self_arg);
         match pack_arg.layout().ty.sty {
@@ -543,7 +696,7 @@ pub fn codegen_terminator_call<'a, 'tcx: 'a>(
         }
         args
     } else {
-        args.into_iter()
+        mir_args.into_iter()
             .map(|arg| trans_operand(fx, arg))
             .collect::<Vec<_>>()
     };
@@ -562,7 +715,7 @@ pub fn codegen_terminator_call<'a, 'tcx: 'a>(
 
         match instance.def {
             InstanceDef::Intrinsic(_) => {
-                crate::intrinsics::codegen_intrinsic_call(fx, def_id, substs, args, destination);
+                crate::intrinsics::codegen_intrinsic_call(fx, def_id, substs, mir_args, args, destination);
                 return;
             }
             InstanceDef::DropGlue(_, None) => {
@@ -582,6 +735,7 @@ pub fn codegen_terminator_call<'a, 'tcx: 'a>(
         fn_ty,
         args,
         destination.map(|(place, _)| place),
+        cleanup,
     );
 
     if let Some((_, dest)) = destination {
@@ -598,26 +752,52 @@ pub fn codegen_call_inner<'a, 'tcx: 'a>(
     fn_ty: Ty<'tcx>,
     args: Vec<CValue<'tcx>>,
     ret_place: Option<CPlace<'tcx>>,
+    cleanup: Option<BasicBlock>,
 ) {
-    let fn_sig = ty_fn_sig(fx.tcx, fn_ty);
+    let instance = match fn_ty.sty {
+        ty::FnDef(def_id, substs) => {
+            Some(Instance::resolve(fx.tcx, ParamEnv::reveal_all(), def_id, substs).unwrap())
+        }
+        _ => None,
+    };
+
+    let fn_sig = match instance {
+        Some(Instance {
+            def: InstanceDef::Virtual(_, _),
+            ..
+        }) => virtual_fn_sig(fx.tcx, ty_fn_sig(fx.tcx, fn_ty)),
+        _ => ty_fn_sig(fx.tcx, fn_ty),
+    };
 
     let ret_layout = fx.layout_of(fn_sig.output());
 
-    let output_pass_mode = get_pass_mode(fx.tcx, fn_sig.output(), true);
+    // FIXME find a cleaner way to support varargs
+    let (args, variadic_sig) = if fn_sig.variadic {
+        if !sysv::is_sysv_c_abi(fx.tcx, fn_sig.abi) {
+            unimpl!("Variadic call for non System V C abi {:?}", fn_sig.abi);
+        }
+        let fixed_args = fn_sig.inputs().len();
+        let args = args
+            .into_iter()
+            .enumerate()
+            .map(|(i, arg)| if i < fixed_args { arg } else { sysv::promote_variadic_arg(fx, arg) })
+            .collect::<Vec<_>>();
+        let arg_tys = args.iter().map(|arg| arg.layout().ty).collect::<Vec<_>>();
+        let sig = sysv::clif_sig_variadic_call(fx.tcx, &arg_tys, fn_sig.output());
+        (args, Some(sig))
+    } else {
+        (args, None)
+    };
+
+    let output_pass_mode = get_pass_mode(fx.tcx, fn_sig.abi, fn_sig.output(), true);
     let return_ptr = match output_pass_mode {
         PassMode::NoPass => None,
         PassMode::ByRef => match ret_place {
             Some(ret_place) => Some(ret_place.to_addr(fx)),
             None => Some(fx.bcx.ins().iconst(fx.pointer_type, 43)),
         },
-        PassMode::ByVal(_) => None,
-    };
-
-    let instance = match fn_ty.sty {
-        ty::FnDef(def_id, substs) => {
-            Some(Instance::resolve(fx.tcx, ParamEnv::reveal_all(), def_id, substs).unwrap())
-        }
-        _ => None,
+        PassMode::ByVal(_) | PassMode::ByValPair(_, _) | PassMode::Cast(_, _) => None,
+        PassMode::ByValStack(_) => unreachable!(),
     };
 
     //   | Indirect call target
@@ -629,54 +809,58 @@ pub fn codegen_call_inner<'a, 'tcx: 'a>(
             ..
         }) => {
             let (ptr, method) = crate::vtable::get_ptr_and_method_ref(fx, args[0], idx);
-            (Some(method), Some(ptr))
+            (Some(method), vec![ptr])
         }
 
         // Normal call
-        Some(_) => (None, args.get(0).map(|arg| adjust_arg_for_abi(fx, *arg))),
+        Some(_) => (
+            None,
+            args.get(0).map(|arg| adjust_arg_for_abi(fx, fn_sig.abi, *arg)).unwrap_or_default(),
+        ),
 
         // Indirect call
         None => {
             let func = trans_operand(fx, func.expect("indirect call without func Operand")).load_scalar(fx);
-            (Some(func), args.get(0).map(|arg| adjust_arg_for_abi(fx, *arg)))
+            (
+                Some(func),
+                args.get(0).map(|arg| adjust_arg_for_abi(fx, fn_sig.abi, *arg)).unwrap_or_default(),
+            )
         }
     };
 
-    let call_args: Vec<Value> = return_ptr
+    let mut call_args: Vec<Value> = return_ptr
         .into_iter()
         .chain(first_arg)
         .chain(
             args.into_iter()
                 .skip(1)
-                .map(|arg| adjust_arg_for_abi(fx, arg)),
+                .map(|arg| adjust_arg_for_abi(fx, fn_sig.abi, arg))
+                .flatten(),
         )
         .collect::<Vec<_>>();
 
+    if let Some(variadic_sig) = &variadic_sig {
+        let used_sse_regs = sysv::used_sse_regs(variadic_sig);
+        call_args.push(fx.bcx.ins().iconst(types::I8, used_sse_regs as i64));
+    }
+
     let call_inst = if let Some(func_ref) = func_ref {
-        let sig = fx.bcx.import_signature(clif_sig_from_fn_sig(fx.tcx, fn_sig));
+        let sig = variadic_sig.clone().unwrap_or_else(|| clif_sig_from_fn_sig(fx.tcx, fn_sig));
+        let sig = fx.bcx.import_signature(sig);
         fx.bcx.ins().call_indirect(sig, func_ref, &call_args)
     } else {
         let func_ref = fx.get_function_ref(instance.expect("non-indirect call on non-FnDef type"));
         fx.bcx.ins().call(func_ref, &call_args)
     };
 
-    // FIXME find a cleaner way to support varargs
-    if fn_sig.variadic {
-        if fn_sig.abi != Abi::C {
-            unimpl!("Variadic call for non-C abi {:?}", fn_sig.abi);
-        }
+    // The declared signature of a variadic function only contains the fixed arguments
+    if let Some(variadic_sig) = variadic_sig {
         let sig_ref = fx.bcx.func.dfg.call_signature(call_inst).unwrap();
-        let abi_params = call_args.into_iter().map(|arg| {
-            let ty = fx.bcx.func.dfg.value_type(arg);
-            if !ty.is_int() {
-                // FIXME set %al to upperbound on float args once floats are supported
-                unimpl!("Non int ty {:?} for variadic call", ty);
-            }
-            AbiParam::new(ty)
-        }).collect::<Vec<AbiParam>>();
-        fx.bcx.func.dfg.signatures[sig_ref].params = abi_params;
+        fx.bcx.func.dfg.signatures[sig_ref] = variadic_sig;
     }
 
+    crate::unwind::codegen_cleanup_edge(fx, call_inst, cleanup);
+
     match output_pass_mode {
         PassMode::NoPass => {}
         PassMode::ByVal(_) => {
@@ -685,7 +869,22 @@ pub fn codegen_call_inner<'a, 'tcx: 'a>(
                 ret_place.write_cvalue(fx, CValue::ByVal(results[0], ret_layout));
             }
         }
+        PassMode::ByValPair(_, _) => {
+            if let Some(ret_place) = ret_place {
+                let results = fx.bcx.inst_results(call_inst);
+                ret_place.write_cvalue(fx, CValue::ByValPair(results[0], results[1], ret_layout));
+            }
+        }
+        PassMode::Cast(_, _) => {
+            if let Some(ret_place) = ret_place {
+                let results = fx.bcx.inst_results(call_inst).to_vec();
+                let ret_val = sysv::store_eightbytes(fx, &results, ret_layout);
+                ret_place.write_cvalue(fx, ret_val);
+            }
+        }
+        // For the C abi the return place is returned again, but we already know it
         PassMode::ByRef => {}
+        PassMode::ByValStack(_) => unreachable!(),
     }
 }
 
@@ -693,30 +892,54 @@ pub fn codegen_drop<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     drop_place: CPlace<'tcx>,
     drop_fn_ty: Ty<'tcx>,
+    cleanup: Option<BasicBlock>,
 ) {
     let (ptr, vtable) = drop_place.to_addr_maybe_unsized(fx);
     let drop_fn = crate::vtable::drop_fn_of_obj(fx, vtable.unwrap());
 
-    let fn_sig = ty_fn_sig(fx.tcx, drop_fn_ty);
+    let fn_sig = virtual_fn_sig(fx.tcx, ty_fn_sig(fx.tcx, drop_fn_ty));
 
-    match get_pass_mode(fx.tcx, fn_sig.output(), true) {
+    match get_pass_mode(fx.tcx, fn_sig.abi, fn_sig.output(), true) {
         PassMode::NoPass => {},
         _ => unreachable!(),
     };
 
     let sig = fx.bcx.import_signature(clif_sig_from_fn_sig(fx.tcx, fn_sig));
-    fx.bcx.ins().call_indirect(sig, drop_fn, &[ptr]);
+    let call_inst = fx.bcx.ins().call_indirect(sig, drop_fn, &[ptr]);
+    crate::unwind::codegen_cleanup_edge(fx, call_inst, cleanup);
 }
 
 pub fn codegen_return(fx: &mut FunctionCx<impl Backend>) {
-    match get_pass_mode(fx.tcx, fx.return_type(), true) {
-        PassMode::NoPass | PassMode::ByRef => {
+    let abi = fx.self_sig().abi;
+    match get_pass_mode(fx.tcx, abi, fx.return_type(), true) {
+        PassMode::NoPass => {
             fx.bcx.ins().return_(&[]);
         }
+        PassMode::ByRef => {
+            if sysv::is_sysv_c_abi(fx.tcx, abi) {
+                // The C abi requires the return place to be returned
+                let ret_ptr = fx.get_local_place(RETURN_PLACE).to_addr(fx);
+                fx.bcx.ins().return_(&[ret_ptr]);
+            } else {
+                fx.bcx.ins().return_(&[]);
+            }
+        }
         PassMode::ByVal(_) => {
             let place = fx.get_local_place(RETURN_PLACE);
             let ret_val = place.to_cvalue(fx).load_scalar(fx);
             fx.bcx.ins().return_(&[ret_val]);
         }
+        PassMode::ByValPair(_, _) => {
+            let place = fx.get_local_place(RETURN_PLACE);
+            let (ret_val_a, ret_val_b) = place.to_cvalue(fx).load_value_pair(fx);
+            fx.bcx.ins().return_(&[ret_val_a, ret_val_b]);
+        }
+        PassMode::Cast(a, b) => {
+            let place = fx.get_local_place(RETURN_PLACE);
+            let types = Some(a).into_iter().chain(b).collect::<Vec<_>>();
+            let ret_vals = sysv::load_eightbytes(fx, place.to_cvalue(fx), &types);
+            fx.bcx.ins().return_(&ret_vals);
+        }
+        PassMode::ByValStack(_) => unreachable!(),
     }
 }
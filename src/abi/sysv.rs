@@ -0,0 +1,382 @@
+//! The System V x86_64 calling convention, used for `extern "C"` functions.
+//!
+//! Every argument is split into eightbytes, which are classified as INTEGER or SSE. When there are
+//! enough registers left, the eightbytes are passed in general purpose or xmm registers. Otherwise
+//! or when the value is classified as MEMORY, it is copied to the stack ("byval"). Return values
+//! classified as MEMORY are written to a pointer passed by the caller ("sret"), which the callee
+//! returns again in `rax`.
+//!
+//! Adapted from https://github.com/rust-lang/rust/blob/f1e2fa8f0469aac1ea69dd5b6164e1d198d57934/src/librustc_target/abi/call/x86_64.rs
+
+use cranelift::codegen::ir::{ArgumentExtension, ArgumentLoc};
+use cranelift::codegen::isa::RegUnit;
+use rustc::ty::layout::{LayoutCx, Primitive};
+
+use super::*;
+
+/// `rdi`, `rsi`, `rdx`, `rcx`, `r8` and `r9`
+const MAX_INT_REGS: usize = 6;
+/// `xmm0` to `xmm7`
+const MAX_SSE_REGS: usize = 8;
+/// Variadic calls pass an upper bound of the number of used sse registers in `al`
+const RAX: RegUnit = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Class {
+    Int,
+    Sse,
+    SseUp,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Memory;
+
+pub(super) fn is_sysv_c_abi(tcx: TyCtxt, abi: Abi) -> bool {
+    let target = &tcx.sess.target.target;
+    abi == Abi::C && target.arch == "x86_64" && !target.options.is_like_windows
+}
+
+fn classify<'a, 'tcx: 'a>(
+    cx: &LayoutCx<'tcx, TyCtxt<'a, 'tcx, 'tcx>>,
+    layout: TyLayout<'tcx>,
+    cls: &mut [Option<Class>],
+    off: Size,
+) -> Result<(), Memory> {
+    if !off.is_aligned(layout.align.abi) {
+        if !layout.is_zst() {
+            return Err(Memory);
+        }
+        return Ok(());
+    }
+
+    let mut c = match layout.abi {
+        layout::Abi::Uninhabited => return Ok(()),
+
+        layout::Abi::Scalar(ref scalar) => match scalar.value {
+            Primitive::Int(..) | Primitive::Pointer => Class::Int,
+            Primitive::Float(_) => Class::Sse,
+        },
+
+        layout::Abi::Vector { .. } => Class::Sse,
+
+        layout::Abi::ScalarPair(..) | layout::Abi::Aggregate { .. } => {
+            match layout.variants {
+                layout::Variants::Single { .. } => {
+                    for i in 0..layout.fields.count() {
+                        let field_off = off + layout.fields.offset(i);
+                        classify(cx, layout.field(cx, i), cls, field_off)?;
+                    }
+                    return Ok(());
+                }
+                _ => return Err(Memory),
+            }
+        }
+    };
+
+    // Fill in `cls` for scalars (Int/Sse) and vectors (Sse).
+    let first = (off.bytes() / 8) as usize;
+    let last = ((off.bytes() + layout.size.bytes() - 1) / 8) as usize;
+    for cls in &mut cls[first..=last] {
+        *cls = Some(cls.map_or(c, |old| old.min(c)));
+
+        // Everything after the first Sse "eightbyte"
+        // component is the upper half of a register.
+        if c == Class::Sse {
+            c = Class::SseUp;
+        }
+    }
+
+    Ok(())
+}
+
+fn classify_eightbytes<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    layout: TyLayout<'tcx>,
+) -> Result<Vec<Class>, Memory> {
+    let mut n = ((layout.size.bytes() + 7) / 8) as usize;
+    if n > 2 {
+        // Values larger than two eightbytes are only passed in registers when they are vectors,
+        // which are not yet supported.
+        return Err(Memory);
+    }
+
+    let cx = LayoutCx {
+        tcx,
+        param_env: ParamEnv::reveal_all(),
+    };
+    let mut cls = [None; 2];
+    classify(&cx, layout, &mut cls[..n], Size::ZERO)?;
+
+    if n == 2 && cls[1].is_none() {
+        // The second eightbyte is only padding
+        n = 1;
+    }
+
+    Ok(cls[..n]
+        .iter()
+        .map(|cls| match cls {
+            None => Class::Int,
+            // Vectors are not yet supported, so pass upper halves in their own register
+            Some(Class::SseUp) => Class::Sse,
+            Some(cls) => *cls,
+        })
+        .collect())
+}
+
+/// The clif type in which the eightbyte at `offset` is passed
+fn eightbyte_type(cls: Class, size: Size, offset: u64) -> Type {
+    let rest = size.bytes() - offset;
+    match cls {
+        Class::Int => match rest {
+            1 => types::I8,
+            2 => types::I16,
+            3..=4 => types::I32,
+            _ => types::I64,
+        },
+        Class::Sse if rest <= 4 => types::F32,
+        Class::Sse => types::F64,
+        Class::SseUp => unreachable!(),
+    }
+}
+
+pub(super) fn get_pass_mode<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    ty: Ty<'tcx>,
+    is_return: bool,
+) -> PassMode {
+    let layout = tcx.layout_of(ParamEnv::reveal_all().and(ty)).unwrap();
+    assert!(!layout.is_unsized());
+
+    if layout.is_zst() || layout.abi.is_uninhabited() {
+        return PassMode::NoPass;
+    }
+
+    match &layout.abi {
+        layout::Abi::Scalar(scalar) => PassMode::ByVal(scalar_to_clif_type(tcx, scalar.clone())),
+        layout::Abi::Vector { .. } => {
+            unimpl!("Passing vector {:?} to or from an extern \"C\" fn is not yet supported", ty)
+        }
+        _ => match classify_eightbytes(tcx, layout) {
+            Ok(cls) => {
+                let a = eightbyte_type(cls[0], layout.size, 0);
+                let b = cls.get(1).map(|&cls| eightbyte_type(cls, layout.size, 8));
+                PassMode::Cast(a, b)
+            }
+            Err(Memory) if is_return => PassMode::ByRef,
+            Err(Memory) => PassMode::ByValStack(layout.size),
+        },
+    }
+}
+
+/// Sign or zero extend integers smaller than 32 bits like clang does
+fn abi_param_for_scalar<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    ty: Ty<'tcx>,
+    clif_ty: Type,
+) -> AbiParam {
+    let layout = tcx.layout_of(ParamEnv::reveal_all().and(ty)).unwrap();
+    let extension = match layout.abi {
+        layout::Abi::Scalar(ref scalar) => match scalar.value {
+            Primitive::Int(int, signed) if int.size().bits() < 32 => {
+                if signed {
+                    ArgumentExtension::Sext
+                } else {
+                    ArgumentExtension::Uext
+                }
+            }
+            _ => ArgumentExtension::None,
+        },
+        _ => unreachable!(),
+    };
+    AbiParam {
+        extension,
+        ..AbiParam::new(clif_ty)
+    }
+}
+
+fn stack_words(size: Size) -> u32 {
+    ((size.bytes() + 7) / 8) as u32
+}
+
+/// Cranelift only assigns stack slots to arguments for which it runs out of registers, while
+/// values classified as MEMORY need to be passed on the stack even when there are registers left.
+/// Because of that all arguments passed on the stack get an explicit location.
+fn push_stack_param(params: &mut Vec<AbiParam>, stack_offset: &mut u32, param: AbiParam) {
+    let size = std::cmp::max(param.value_type.bytes(), 8);
+    params.push(AbiParam {
+        location: ArgumentLoc::Stack(*stack_offset as i32),
+        ..param
+    });
+    *stack_offset += size;
+}
+
+pub(super) fn clif_sig<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    inputs: &[Ty<'tcx>],
+    output: Ty<'tcx>,
+) -> Signature {
+    let mut int_regs = MAX_INT_REGS;
+    let mut sse_regs = MAX_SSE_REGS;
+    let mut stack_offset = 0;
+
+    let mut params = Vec::new();
+    let mut returns = Vec::new();
+
+    match get_pass_mode(tcx, output, true) {
+        PassMode::NoPass => {}
+        PassMode::ByVal(clif_ty) => returns.push(abi_param_for_scalar(tcx, output, clif_ty)),
+        PassMode::Cast(a, b) => returns.extend(Some(a).into_iter().chain(b).map(AbiParam::new)),
+        PassMode::ByRef => {
+            // The caller passes the return place in `rdi` and the callee returns it in `rax`
+            params.push(AbiParam::new(pointer_ty(tcx)));
+            returns.push(AbiParam::new(pointer_ty(tcx)));
+            int_regs -= 1;
+        }
+        mode @ PassMode::ByValPair(_, _) | mode @ PassMode::ByValStack(_) => {
+            bug!("{:?} for C abi return", mode)
+        }
+    }
+
+    for &ty in inputs {
+        let clif_params = match get_pass_mode(tcx, ty, false) {
+            PassMode::NoPass => continue,
+            PassMode::ByVal(clif_ty) => vec![abi_param_for_scalar(tcx, ty, clif_ty)],
+            PassMode::Cast(a, b) => Some(a).into_iter().chain(b).map(AbiParam::new).collect(),
+            PassMode::ByValStack(size) => {
+                let layout = tcx.layout_of(ParamEnv::reveal_all().and(ty)).unwrap();
+                if layout.align.abi.bytes() > 8 {
+                    stack_offset = (stack_offset + 15) / 16 * 16;
+                }
+                for _ in 0..stack_words(size) {
+                    push_stack_param(&mut params, &mut stack_offset, AbiParam::new(types::I64));
+                }
+                continue;
+            }
+            mode @ PassMode::ByValPair(_, _) | mode @ PassMode::ByRef => {
+                bug!("{:?} for C abi argument", mode)
+            }
+        };
+
+        let needed_sse = clif_params.iter().filter(|param| param.value_type.is_float()).count();
+        let needed_int = clif_params
+            .iter()
+            .map(|param| match param.value_type {
+                types::I128 => 2,
+                ty if ty.is_float() => 0,
+                _ => 1,
+            })
+            .sum::<usize>();
+
+        if needed_int <= int_regs && needed_sse <= sse_regs {
+            int_regs -= needed_int;
+            sse_regs -= needed_sse;
+            params.extend(clif_params);
+        } else {
+            // Not enough registers left, so the whole value is passed on the stack
+            for param in clif_params {
+                if param.value_type == types::I128 {
+                    unimpl!("Passing i128 on the stack to an extern \"C\" fn is not yet supported");
+                }
+                push_stack_param(&mut params, &mut stack_offset, param);
+            }
+        }
+    }
+
+    Signature {
+        params,
+        returns,
+        call_conv: CallConv::SystemV,
+    }
+}
+
+/// Like `clif_sig`, but `inputs` contains the types of both the fixed and the variadic arguments.
+/// The last param is the `al` value, see `used_sse_regs`.
+pub(super) fn clif_sig_variadic_call<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    inputs: &[Ty<'tcx>],
+    output: Ty<'tcx>,
+) -> Signature {
+    let mut sig = clif_sig(tcx, inputs, output);
+    sig.params.push(AbiParam {
+        location: ArgumentLoc::Reg(RAX),
+        ..AbiParam::new(types::I8)
+    });
+    sig
+}
+
+/// The number of sse registers used to pass the arguments of a call with signature `sig`
+pub(super) fn used_sse_regs(sig: &Signature) -> usize {
+    sig.params
+        .iter()
+        .filter(|param| param.value_type.is_float() && !param.location.is_assigned())
+        .count()
+}
+
+/// The default argument promotions of C promote `float` to `double` for variadic arguments
+pub(super) fn promote_variadic_arg<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    arg: CValue<'tcx>,
+) -> CValue<'tcx> {
+    match arg.layout().ty.sty {
+        ty::Float(FloatTy::F32) => {
+            let val = arg.load_scalar(fx);
+            let promoted = fx.bcx.ins().fpromote(types::F64, val);
+            CValue::ByVal(promoted, fx.layout_of(fx.tcx.types.f64))
+        }
+        _ => arg,
+    }
+}
+
+/// Copy `arg` to a stack slot padded to a multiple of eight bytes, so that loading whole
+/// eightbytes doesn't read out of bounds.
+fn padded_stack_copy<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    arg: CValue<'tcx>,
+) -> Value {
+    let layout = arg.layout();
+    let stack_slot = fx.bcx.create_stack_slot(StackSlotData {
+        kind: StackSlotKind::ExplicitSlot,
+        size: stack_words(layout.size) * 8,
+        offset: None,
+    });
+    let addr = fx.bcx.ins().stack_addr(fx.pointer_type, stack_slot, 0);
+    CPlace::Addr(addr, None, layout).write_cvalue(fx, arg);
+    addr
+}
+
+/// The eightbytes of a value with pass mode `Cast` or `ByValStack`
+pub(super) fn load_eightbytes<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    arg: CValue<'tcx>,
+    types: &[Type],
+) -> Vec<Value> {
+    let addr = padded_stack_copy(fx, arg);
+    types
+        .iter()
+        .enumerate()
+        .map(|(i, &ty)| fx.bcx.ins().load(ty, MemFlags::new(), addr, i as i32 * 8))
+        .collect()
+}
+
+/// Inverse of `load_eightbytes`
+pub(super) fn store_eightbytes<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    eightbytes: &[Value],
+    layout: TyLayout<'tcx>,
+) -> CValue<'tcx> {
+    let stack_slot = fx.bcx.create_stack_slot(StackSlotData {
+        kind: StackSlotKind::ExplicitSlot,
+        size: eightbytes.len() as u32 * 8,
+        offset: None,
+    });
+    let addr = fx.bcx.ins().stack_addr(fx.pointer_type, stack_slot, 0);
+    for (i, &val) in eightbytes.iter().enumerate() {
+        fx.bcx.ins().store(MemFlags::new(), val, addr, i as i32 * 8);
+    }
+    CValue::ByRef(addr, layout)
+}
+
+/// The clif types of the stack words of a value with pass mode `ByValStack`
+pub(super) fn stack_word_types(size: Size) -> Vec<Type> {
+    vec![types::I64; stack_words(size) as usize]
+}
@@ -15,6 +15,16 @@ use rustc_allocator::{AllocatorTy, ALLOCATOR_METHODS};
 
 /// Returns whether an allocator shim was created
 pub fn codegen(sess: &Session, module: &mut Module<impl Backend + 'static>) -> bool {
+    if let Some(kind) = shim_kind(sess) {
+        codegen_inner(module, kind);
+        true
+    } else {
+        false
+    }
+}
+
+/// The kind of allocator the shim forwards to, or `None` when no shim is needed
+pub fn shim_kind(sess: &Session) -> Option<AllocatorKind> {
     let any_dynamic_crate = sess
         .dependency_formats
         .borrow()
@@ -24,12 +34,9 @@ pub fn codegen(sess: &Session, module: &mut Module<impl Backend + 'static>) -> b
             list.iter().any(|&linkage| linkage == Linkage::Dynamic)
         });
     if any_dynamic_crate {
-        false
-    } else if let Some(kind) = *sess.allocator_kind.get() {
-        codegen_inner(module, kind);
-        true
+        None
     } else {
-        false
+        *sess.allocator_kind.get()
     }
 }
 
@@ -1,6 +1,13 @@
-use std::collections::HashMap;
+//! Creation of static archives like rlibs and staticlibs.
+//!
+//! The archive is written by hand instead of using `ar::Builder`, because the symbol index the
+//! linker needs to find the members defining a symbol is built in-process from the symbol tables
+//! of the members, instead of running `ranlib` afterwards. All headers use zero as timestamp,
+//! owner and group, so creating an archive is deterministic.
+
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
 use crate::prelude::*;
 
@@ -13,88 +20,311 @@ pub struct ArchiveConfig<'a> {
 
 pub struct ArchiveBuilder<'a> {
     cfg: ArchiveConfig<'a>,
-    src_archive: Option<ar::Archive<File>>,
-    src_entries: HashMap<String, usize>,
-    builder: ar::Builder<File>,
+    /// The name and contents of all members in the order they will be written
+    entries: Vec<(String, Vec<u8>)>,
     update_symbols: bool,
 }
 
 impl<'a> ArchiveBuilder<'a> {
     pub fn new(cfg: ArchiveConfig<'a>) -> Self {
-        let (src_archive, src_entries) = if let Some(src) = &cfg.src {
-            let mut archive = ar::Archive::new(File::open(src).unwrap());
-            let mut entries = HashMap::new();
-
-            let mut i = 0;
-            while let Some(entry) = archive.next_entry() {
-                let entry = entry.unwrap();
-                entries.insert(
-                    String::from_utf8(entry.header().identifier().to_vec()).unwrap(),
-                    i,
-                );
-                i += 1;
-            }
-
-            (Some(archive), entries)
+        let entries = if let Some(src) = &cfg.src {
+            read_archive(src).unwrap_or_else(|err| {
+                cfg.sess.fatal(&format!("failed to read archive {}: {}", src.display(), err))
+            })
         } else {
-            (None, HashMap::new())
+            Vec::new()
         };
 
-        let builder = ar::Builder::new(File::create(&cfg.dst).unwrap());
-
         ArchiveBuilder {
             cfg,
-            src_archive,
-            src_entries,
-            builder,
+            entries,
             update_symbols: false,
         }
     }
 
     pub fn src_files(&self) -> Vec<String> {
-        self.src_entries.keys().cloned().collect()
+        self.entries.iter().map(|(name, _)| name.clone()).collect()
     }
 
     pub fn remove_file(&mut self, name: &str) {
-        let file = self.src_entries.remove(name);
-        assert!(
-            file.is_some(),
-            "Tried to remove file not existing in src archive",
-        );
+        let index = self.entries.iter().position(|(entry_name, _)| entry_name == name);
+        let index = index.expect("Tried to remove file not existing in src archive");
+        self.entries.remove(index);
+    }
+
+    /// Add a single file, using its file name as name of the archive member.
+    pub fn add_file(&mut self, file: &Path) {
+        let name = file.file_name().unwrap().to_str().unwrap().to_string();
+        let data = std::fs::read(file).unwrap_or_else(|err| {
+            self.cfg.sess.fatal(&format!("error reading {}: {}", file.display(), err))
+        });
+        self.entries.push((name, data));
+    }
+
+    /// Add an archive member with the given contents.
+    pub fn add_data(&mut self, name: &str, data: Vec<u8>) {
+        self.entries.push((name.to_string(), data));
+    }
+
+    /// Add all members of the archive `archive` for which `skip` returns false.
+    pub fn add_archive<F>(&mut self, archive: &Path, mut skip: F)
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let entries = read_archive(archive).unwrap_or_else(|err| {
+            self.cfg.sess.fatal(&format!("failed to read archive {}: {}", archive.display(), err))
+        });
+        self.entries.extend(entries.into_iter().filter(|(name, _)| !skip(name)));
+    }
+
+    /// Add the members of the native static library `name`, which is searched for in the library
+    /// search paths.
+    pub fn add_native_library(&mut self, name: &str) {
+        let sess = self.cfg.sess;
+        let options = &sess.target.target.options;
+        let file_name = format!("{}{}{}", options.staticlib_prefix, name, options.staticlib_suffix);
+        let path = self
+            .cfg
+            .lib_search_paths
+            .iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| {
+                sess.fatal(&format!(
+                    "could not find native static library `{}`, perhaps an -L flag is missing?",
+                    name
+                ))
+            });
+        self.add_archive(&path, |_| false);
     }
 
     pub fn update_symbols(&mut self) {
         self.update_symbols = true;
     }
 
-    pub fn build(mut self) {
-        // Add files from original archive
-        if let Some(mut src_archive) = self.src_archive {
-            for (_entry_name, entry_idx) in self.src_entries.into_iter() {
-                let entry = src_archive.jump_to_entry(entry_idx).unwrap();
-                let orig_header = entry.header();
-                let mut header =
-                    ar::Header::new(orig_header.identifier().to_vec(), orig_header.size());
-                header.set_mtime(orig_header.mtime());
-                header.set_uid(orig_header.uid());
-                header.set_gid(orig_header.gid());
-                header.set_mode(orig_header.mode());
-                self.builder.append(&header, entry).unwrap();
+    pub fn build(self) {
+        let kind = if self.cfg.sess.target.target.options.is_like_osx {
+            ArchiveKind::Bsd
+        } else {
+            ArchiveKind::Gnu
+        };
+
+        let symbols = if self.update_symbols {
+            self.entries
+                .iter()
+                .map(|(_name, data)| object_symbols(data, true))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let res = File::create(&self.cfg.dst)
+            .and_then(|file| write_archive(io::BufWriter::new(file), kind, &self.entries, &symbols));
+        if let Err(err) = res {
+            self.cfg.sess.fatal(&format!(
+                "failed to write archive {}: {}",
+                self.cfg.dst.display(),
+                err
+            ));
+        }
+    }
+}
+
+/// Read the name and contents of all members of an archive, except for the symbol index.
+pub fn read_archive(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = ar::Archive::new(File::open(path)?);
+    let mut entries = Vec::new();
+    while let Some(entry) = archive.next_entry() {
+        let mut entry = entry?;
+        let name = String::from_utf8(entry.header().identifier().to_vec())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // The symbol index is rebuilt when writing the archive
+        if name == "/" || name.starts_with("__.SYMDEF") {
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        entries.push((name, data));
+    }
+    Ok(entries)
+}
+
+/// The names of all global symbols defined by an object file, or when `defined` is false, the
+/// names of all global symbols it references without defining them. Members which are not object
+/// files, like the metadata of rlibs, don't have any symbols.
+pub fn object_symbols(data: &[u8], defined: bool) -> Vec<String> {
+    use goblin::elf::section_header::SHN_UNDEF;
+    use goblin::elf::sym::{STB_GLOBAL, STB_WEAK};
+
+    const STB_GNU_UNIQUE: u8 = 10;
+    const N_EXT: u8 = 0x01;
+    const N_TYPE: u8 = 0x0e;
+    const N_UNDF: u8 = 0x00;
+
+    match goblin::Object::parse(data) {
+        Ok(goblin::Object::Elf(elf)) => elf
+            .syms
+            .iter()
+            .filter(|sym| {
+                let bind = sym.st_bind();
+                (bind == STB_GLOBAL || bind == STB_WEAK || bind == STB_GNU_UNIQUE)
+                    && (sym.st_shndx != SHN_UNDEF as usize) == defined
+            })
+            .filter_map(|sym| match elf.strtab.get(sym.st_name) {
+                Some(Ok(name)) if !name.is_empty() => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        Ok(goblin::Object::Mach(goblin::mach::Mach::Binary(macho))) => macho
+            .symbols()
+            .filter_map(|sym| sym.ok())
+            .filter(|(_name, nlist)| {
+                nlist.n_type & N_EXT != 0 && (nlist.n_type & N_TYPE != N_UNDF) == defined
+            })
+            .map(|(name, _nlist)| name.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ArchiveKind {
+    /// Used by GNU ar on Linux. Long member names are stored in the `//` member and the symbol
+    /// index is stored in the `/` member.
+    Gnu,
+    /// Used on macOS. Long member names are stored in front of the member contents and the
+    /// symbol index is stored in the `__.SYMDEF` member.
+    Bsd,
+}
+
+const HEADER_SIZE: usize = 60;
+
+fn write_header(w: &mut impl Write, name: &str, size: usize) -> io::Result<()> {
+    writeln!(w, "{:<16}{:<12}{:<6}{:<6}{:<8o}{:<10}`", name, 0, 0, 0, 0o644, size)
+}
+
+fn padding(size: usize) -> usize {
+    size % 2
+}
+
+fn write_archive(
+    mut w: impl Write,
+    kind: ArchiveKind,
+    entries: &[(String, Vec<u8>)],
+    symbols: &[Vec<String>],
+) -> io::Result<()> {
+    // GNU long names are `/<offset in the long names member>`
+    let mut long_names = Vec::new();
+    let mut header_names = Vec::with_capacity(entries.len());
+    for (name, _data) in entries {
+        let is_short = name.len() <= 15 && !name.contains(' ') && !name.contains('/');
+        header_names.push(match kind {
+            ArchiveKind::Gnu if is_short => format!("{}/", name),
+            ArchiveKind::Gnu => {
+                let header_name = format!("/{}", long_names.len());
+                long_names.extend_from_slice(name.as_bytes());
+                long_names.extend_from_slice(b"/\n");
+                header_name
+            }
+            ArchiveKind::Bsd if is_short => name.clone(),
+            ArchiveKind::Bsd => format!("#1/{}", name.len()),
+        });
+    }
+
+    // The size of a member including its header, BSD long name and padding
+    let member_size = |(name, data): &(String, Vec<u8>), header_name: &String| {
+        let name_size = if header_name.starts_with("#1/") { name.len() } else { 0 };
+        let size = name_size + data.len();
+        HEADER_SIZE + size + padding(size)
+    };
+
+    let mut symbol_names = Vec::new();
+    let symbol_count = symbols.iter().map(|syms| syms.len()).sum::<usize>();
+    for name in symbols.iter().flat_map(|syms| syms) {
+        symbol_names.extend_from_slice(name.as_bytes());
+        symbol_names.push(0);
+    }
+    let symbol_table_size = match kind {
+        ArchiveKind::Gnu => 4 + 4 * symbol_count + symbol_names.len(),
+        ArchiveKind::Bsd => {
+            while symbol_names.len() % 4 != 0 {
+                symbol_names.push(0);
+            }
+            4 + 8 * symbol_count + 4 + symbol_names.len()
+        }
+    };
+
+    let mut offset = 8;
+    if !symbols.is_empty() {
+        offset += HEADER_SIZE + symbol_table_size + padding(symbol_table_size);
+    }
+    if !long_names.is_empty() {
+        offset += HEADER_SIZE + long_names.len() + padding(long_names.len());
+    }
+    let mut member_offsets = Vec::with_capacity(entries.len());
+    for (entry, header_name) in entries.iter().zip(&header_names) {
+        member_offsets.push(offset as u32);
+        offset += member_size(entry, header_name);
+    }
+    if offset > u32::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::Other, "archive is larger than 4GB"));
+    }
+
+    w.write_all(b"!<arch>\n")?;
+
+    if !symbols.is_empty() {
+        match kind {
+            ArchiveKind::Gnu => {
+                write_header(&mut w, "/", symbol_table_size)?;
+                w.write_all(&(symbol_count as u32).to_be_bytes())?;
+                for (&member_offset, syms) in member_offsets.iter().zip(symbols) {
+                    for _ in syms {
+                        w.write_all(&member_offset.to_be_bytes())?;
+                    }
+                }
             }
+            ArchiveKind::Bsd => {
+                write_header(&mut w, "__.SYMDEF", symbol_table_size)?;
+                w.write_all(&(8 * symbol_count as u32).to_le_bytes())?;
+                let mut name_offset = 0u32;
+                for (&member_offset, syms) in member_offsets.iter().zip(symbols) {
+                    for name in syms {
+                        w.write_all(&name_offset.to_le_bytes())?;
+                        w.write_all(&member_offset.to_le_bytes())?;
+                        name_offset += name.len() as u32 + 1;
+                    }
+                }
+                w.write_all(&(symbol_names.len() as u32).to_le_bytes())?;
+            }
+        }
+        w.write_all(&symbol_names)?;
+        if padding(symbol_table_size) != 0 {
+            w.write_all(b"\n")?;
         }
+    }
 
-        // Finalize archive
-        std::mem::drop(self.builder);
+    if !long_names.is_empty() {
+        write_header(&mut w, "//", long_names.len())?;
+        w.write_all(&long_names)?;
+        if padding(long_names.len()) != 0 {
+            w.write_all(b"\n")?;
+        }
+    }
 
-        // Run ranlib to be able to link the archive
-        let status = std::process::Command::new("ranlib")
-            .arg(self.cfg.dst)
-            .status()
-            .expect("Couldn't run ranlib");
-        assert!(
-            status.success(),
-            "Ranlib exited with code {:?}",
-            status.code()
-        );
+    for ((name, data), header_name) in entries.iter().zip(&header_names) {
+        let size = if header_name.starts_with("#1/") {
+            write_header(&mut w, header_name, name.len() + data.len())?;
+            w.write_all(name.as_bytes())?;
+            name.len() + data.len()
+        } else {
+            write_header(&mut w, header_name, data.len())?;
+            data.len()
+        };
+        w.write_all(data)?;
+        if padding(size) != 0 {
+            w.write_all(b"\n")?;
+        }
     }
+
+    w.flush()
 }
@@ -46,11 +46,15 @@ pub fn trans_mono_item<'a, 'clif, 'tcx: 'a, B: Backend + 'static>(
             trans_fn(cx, inst, linkage);
         }
         MonoItem::Static(def_id) => {
-            crate::constant::codegen_static(&mut cx.ccx, def_id);
+            if crate::tls::is_thread_local(tcx, def_id) {
+                crate::tls::codegen_tls_static(tcx, &mut cx.global_asm, def_id);
+            } else {
+                crate::constant::codegen_static(&mut cx.ccx, def_id);
+            }
+        }
+        MonoItem::GlobalAsm(node_id) => {
+            crate::inline_asm::codegen_global_asm(tcx, &mut cx.global_asm, node_id);
         }
-        MonoItem::GlobalAsm(node_id) => tcx
-            .sess
-            .fatal(&format!("Unimplemented global asm mono item {:?}", node_id)),
     }
 }
 
@@ -65,20 +69,30 @@ fn trans_fn<'a, 'clif, 'tcx: 'a, B: Backend + 'static>(
     let mir = tcx.instance_mir(instance.def);
 
     // Step 2. Declare function
-    let (name, sig) = get_function_name_and_sig(tcx, instance, false);
+    // Calling variadic functions is supported, but there is no `va_list` lowering to read the
+    // variadic arguments in their definition.
+    if ty_fn_sig(tcx, instance.ty(tcx)).variadic {
+        unimpl!("Variadic function definitions are not yet supported");
+    }
+    let (name, sig) = get_function_name_and_sig(tcx, instance);
     let func_id = cx.module
         .declare_function(&name, linkage, &sig)
         .unwrap();
-    let mut debug_context = cx.debug_context.as_mut().map(|debug_context| FunctionDebugContext::new(
+    let debug_context = cx.debug_context.as_mut().map(|debug_context| FunctionDebugContext::new(
         tcx,
         debug_context,
         mir,
+        instance,
         &name,
         &sig,
     ));
 
     // Step 3. Make FunctionBuilder
     let mut func = Function::with_name_signature(ExternalName::user(0, 0), sig);
+    if debug_context.is_some() {
+        // Needed to track the locations of SSA locals
+        func.dfg.collect_debug_info();
+    }
     let mut func_ctx = FunctionBuilderContext::new();
     let mut bcx = FunctionBuilder::new(&mut func, &mut func_ctx);
 
@@ -91,6 +105,15 @@ fn trans_fn<'a, 'clif, 'tcx: 'a, B: Backend + 'static>(
 
     // Step 5. Make FunctionCx
     let pointer_type = cx.module.target_config().pointer_type();
+    let has_landing_pads = cx
+        .unwind_context
+        .as_ref()
+        .map_or(false, |unwind_context| unwind_context.has_landing_pads());
+    let landing_pads = if has_landing_pads {
+        Some(crate::unwind::LandingPads::new(&mut bcx, pointer_type))
+    } else {
+        None
+    };
     let clif_comments = crate::pretty_clif::CommentWriter::new(tcx, instance);
 
     let mut fx = FunctionCx {
@@ -104,11 +127,16 @@ fn trans_fn<'a, 'clif, 'tcx: 'a, B: Backend + 'static>(
         bcx,
         ebb_map,
         local_map: HashMap::new(),
+        landing_pads,
 
         clif_comments,
         constants: &mut cx.ccx,
         caches: &mut cx.caches,
         source_info_set: indexmap::IndexSet::new(),
+        trap_on_assert: cx.trap_on_assert,
+        cgu_name: &cx.cgu_name,
+        global_asm: &mut cx.global_asm,
+        inline_asm_index: 0,
     };
 
     // Step 6. Codegen function
@@ -117,36 +145,99 @@ fn trans_fn<'a, 'clif, 'tcx: 'a, B: Backend + 'static>(
         codegen_fn_content(&mut fx);
     });
     let source_info_set = fx.source_info_set.clone();
+    let local_map = fx.local_map.clone();
+    let call_sites = fx.landing_pads.take().map(|landing_pads| landing_pads.call_sites).unwrap_or_default();
 
     // Step 7. Write function to file for debugging
     #[cfg(debug_assertions)]
     fx.write_clif_file();
 
     // Step 8. Verify function
-    verify_func(tcx, fx.clif_comments, &func);
+    verify_func(tcx, fx.clif_comments, &func, cx.module.isa());
+
+    // Step 9. Collect the debuginfo which needs the `TyCtxt`
+    let debug = debug_context.map(|debug_context| {
+        debug_context.prepare(tcx, &func, &source_info_set, mir, &local_map)
+    });
+
+    // Step 10. Queue the function for compilation, see `compile_functions`
+    cx.functions.push(UncompiledFunction {
+        func_id,
+        name,
+        func,
+        debug,
+        call_sites,
+    });
+}
+
+/// A function whose clif ir is generated, but which isn't compiled yet. Compiling it doesn't
+/// need the `TyCtxt` anymore, so for cgus this happens on a worker thread.
+pub struct UncompiledFunction {
+    func_id: FuncId,
+    name: String,
+    func: Function,
+    debug: Option<crate::debuginfo::FunctionDebugInfo>,
+    call_sites: Vec<crate::unwind::CallSite>,
+}
+
+impl UncompiledFunction {
+    /// A function without debuginfo or landing pads, like the entry wrapper
+    pub fn new(func_id: FuncId, name: String, func: Function) -> Self {
+        UncompiledFunction {
+            func_id,
+            name,
+            func,
+            debug: None,
+            call_sites: Vec::new(),
+        }
+    }
+}
 
-    // Step 9. Define function
-    cx.caches.context.func = func;
-    cx.module.define_function(func_id, &mut cx.caches.context).unwrap();
+/// Optimize and compile `functions`, define them in `module` and add their debuginfo and unwind
+/// info. Returns an error when an optimization pass fails.
+pub fn compile_functions(
+    module: &mut Module<impl Backend>,
+    mut debug_unit: Option<&mut crate::debuginfo::DebugUnit>,
+    mut unwind_context: Option<&mut UnwindContext>,
+    passes: &[crate::optimize::Pass],
+    functions: Vec<UncompiledFunction>,
+) -> Result<(), String> {
+    let mut context = Context::new();
+    for function in functions {
+        // Step 1. Optimize and define function
+        context.func = function.func;
+        crate::optimize::optimize_function(&mut context, module.isa(), passes)?;
+        module.define_function(function.func_id, &mut context).unwrap();
+
+        // Step 2. Define debuginfo for function
+        if let (Some(debug), Some(debug_unit)) = (function.debug, debug_unit.as_mut()) {
+            debug.define(debug_unit, &context, module.isa());
+        }
 
-    // Step 10. Define debuginfo for function
-    let context = &cx.caches.context;
-    let isa = cx.module.isa();
-    debug_context.as_mut().map(|x| x.define(tcx, context, isa, &source_info_set));
+        // Step 3. Define unwind info for function
+        if let Some(unwind_context) = unwind_context.as_mut() {
+            unwind_context.add_function(module, &function.name, &context, &function.call_sites);
+        }
 
-    // Step 11. Clear context to make it usable for the next function
-    cx.caches.context.clear();
+        // Step 4. Clear context to make it usable for the next function
+        context.clear();
+    }
+    Ok(())
 }
 
-fn verify_func(tcx: TyCtxt, writer: crate::pretty_clif::CommentWriter, func: &Function) {
-    let flags = settings::Flags::new(settings::builder());
-    match ::cranelift::codegen::verify_function(&func, &flags) {
+fn verify_func(
+    tcx: TyCtxt,
+    writer: crate::pretty_clif::CommentWriter,
+    func: &Function,
+    isa: &dyn isa::TargetIsa,
+) {
+    match ::cranelift::codegen::verify_function(&func, isa) {
         Ok(_) => {}
         Err(err) => {
             tcx.sess.err(&format!("{:?}", err));
             let pretty_error = ::cranelift::codegen::print_errors::pretty_verifier_error(
                 &func,
-                None,
+                Some(isa),
                 Some(Box::new(&writer)),
                 err,
             );
@@ -158,8 +249,8 @@ fn verify_func(tcx: TyCtxt, writer: crate::pretty_clif::CommentWriter, func: &Fu
 
 fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>) {
     for (bb, bb_data) in fx.mir.basic_blocks().iter_enumerated() {
-        if bb_data.is_cleanup {
-            // Unwinding after panicking is not supported
+        if bb_data.is_cleanup && fx.landing_pads.is_none() {
+            // Unwinding is disabled, so cleanup blocks are unreachable
             continue;
         }
 
@@ -197,9 +288,9 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
             TerminatorKind::Assert {
                 cond,
                 expected,
-                msg: _,
+                msg,
                 target,
-                cleanup: _,
+                cleanup,
             } => {
                 let cond = trans_operand(fx, cond).load_scalar(fx);
                 // TODO HACK brz/brnz for i8/i16 is not yet implemented
@@ -210,7 +301,12 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
                 } else {
                     fx.bcx.ins().brz(cond, target, &[]);
                 };
-                trap_panic(&mut fx.bcx);
+                if fx.trap_on_assert {
+                    trap_panic(&mut fx.bcx);
+                } else {
+                    let span = bb_data.terminator().source_info.span;
+                    trans_assert_failure(fx, msg, span, *cleanup);
+                }
             }
 
             TerminatorKind::SwitchInt {
@@ -220,24 +316,31 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
                 targets,
             } => {
                 let discr = trans_operand(fx, discr).load_scalar(fx);
-                let mut switch = ::cranelift::frontend::Switch::new();
-                for (i, value) in values.iter().enumerate() {
-                    let ebb = fx.get_ebb(targets[i]);
-                    switch.set_entry(*value as u64, ebb);
+                if fx.bcx.func.dfg.value_type(discr) == types::I128 {
+                    crate::codegen_i128::codegen_switch_int(fx, discr, values, targets);
+                } else {
+                    let mut switch = ::cranelift::frontend::Switch::new();
+                    for (i, value) in values.iter().enumerate() {
+                        let ebb = fx.get_ebb(targets[i]);
+                        switch.set_entry(*value as u64, ebb);
+                    }
+                    let otherwise_ebb = fx.get_ebb(targets[targets.len() - 1]);
+                    switch.emit(&mut fx.bcx, discr, otherwise_ebb);
                 }
-                let otherwise_ebb = fx.get_ebb(targets[targets.len() - 1]);
-                switch.emit(&mut fx.bcx, discr, otherwise_ebb);
             }
             TerminatorKind::Call {
                 func,
                 args,
                 destination,
-                cleanup: _,
+                cleanup,
                 from_hir_call: _,
             } => {
-                crate::abi::codegen_terminator_call(fx, func, args, destination);
+                crate::abi::codegen_terminator_call(fx, func, args, destination, *cleanup);
+            }
+            TerminatorKind::Resume => {
+                crate::unwind::codegen_resume(fx);
             }
-            TerminatorKind::Resume | TerminatorKind::Abort | TerminatorKind::Unreachable => {
+            TerminatorKind::Abort | TerminatorKind::Unreachable => {
                 trap_unreachable(&mut fx.bcx);
             }
             TerminatorKind::Yield { .. }
@@ -250,7 +353,7 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
             TerminatorKind::Drop {
                 location,
                 target,
-                unwind: _,
+                unwind,
             } => {
                 let ty = location.ty(fx.mir, fx.tcx).to_ty(fx.tcx);
                 let ty = fx.monomorphize(&ty);
@@ -263,7 +366,7 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
                     let drop_fn_ty = drop_fn.ty(fx.tcx);
                     match ty.sty {
                         ty::Dynamic(..) => {
-                            crate::abi::codegen_drop(fx, drop_place, drop_fn_ty);
+                            crate::abi::codegen_drop(fx, drop_place, drop_fn_ty, *unwind);
                         }
                         _ => {
                             let arg_place = CPlace::new_stack_slot(
@@ -284,6 +387,7 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
                                 drop_fn_ty,
                                 vec![arg_value],
                                 None,
+                                *unwind,
                             );
                         }
                     }
@@ -299,6 +403,48 @@ fn codegen_fn_content<'a, 'tcx: 'a>(fx: &mut FunctionCx<'a, 'tcx, impl Backend>)
     fx.bcx.finalize();
 }
 
+/// Call the `panic_bounds_check` or `panic` lang item for a failed `Assert` terminator, like the
+/// LLVM backend does.
+fn trans_assert_failure<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    msg: &AssertMessage<'tcx>,
+    span: Span,
+    cleanup: Option<BasicBlock>,
+) {
+    use rustc::middle::lang_items::{PanicBoundsCheckFnLangItem, PanicFnLangItem};
+    use rustc::mir::interpret::EvalErrorKind;
+
+    let loc = fx.tcx.sess.source_map().lookup_char_pos(span.lo());
+    let file = loc.file.name.to_string();
+    let line = loc.line as u32;
+    let col = loc.col.to_usize() as u32 + 1;
+
+    let (lang_item, args) = match msg {
+        EvalErrorKind::BoundsCheck { len, index } => {
+            let location = crate::constant::trans_const_location_ref(fx, &[&file], &[line, col]);
+            let index = trans_operand(fx, index);
+            let len = trans_operand(fx, len);
+            (PanicBoundsCheckFnLangItem, vec![location, index, len])
+        }
+        _ => {
+            let location = crate::constant::trans_const_location_ref(
+                fx,
+                &[msg.description(), &file],
+                &[line, col],
+            );
+            (PanicFnLangItem, vec![location])
+        }
+    };
+
+    let def_id = match fx.tcx.lang_items().require(lang_item) {
+        Ok(id) => id,
+        Err(s) => fx.tcx.sess.span_fatal(span, &s),
+    };
+    let fn_ty = fx.tcx.type_of(def_id);
+    crate::abi::codegen_call_inner(fx, None, fn_ty, args, None, cleanup);
+    trap_unreachable(&mut fx.bcx);
+}
+
 fn trans_stmt<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     cur_ebb: Ebb,
@@ -339,7 +485,7 @@ fn trans_stmt<'a, 'tcx: 'a>(
                         .unwrap()
                         .discriminant_for_variant(fx.tcx, *variant_index)
                         .val;
-                    let discr = CValue::const_val(fx, ptr.layout().ty, to as u64 as i64);
+                    let discr = CValue::const_val(fx, ptr.layout().ty, to as i128);
                     ptr.write_cvalue(fx, discr);
                 }
                 layout::Variants::NicheFilling {
@@ -358,7 +504,7 @@ fn trans_stmt<'a, 'tcx: 'a>(
                         let niche_llval = if niche_value == 0 {
                             CValue::const_val(fx, niche.layout().ty, 0)
                         } else {
-                            CValue::const_val(fx, niche.layout().ty, niche_value as u64 as i64)
+                            CValue::const_val(fx, niche.layout().ty, niche_value as i128)
                         };
                         niche.write_cvalue(fx, niche_llval);
                     }
@@ -433,7 +579,11 @@ fn trans_stmt<'a, 'tcx: 'a>(
                         UnOp::Neg => match layout.ty.sty {
                             ty::Int(_) => {
                                 let clif_ty = fx.clif_type(layout.ty).unwrap();
-                                let zero = fx.bcx.ins().iconst(clif_ty, 0);
+                                let zero = if clif_ty == types::I128 {
+                                    crate::codegen_i128::codegen_const_u128(fx, 0)
+                                } else {
+                                    fx.bcx.ins().iconst(clif_ty, 0)
+                                };
                                 fx.bcx.ins().isub(zero, val)
                             }
                             ty::Float(_) => fx.bcx.ins().fneg(val),
@@ -522,6 +672,16 @@ fn trans_stmt<'a, 'tcx: 'a>(
                             };
                             lval.write_cvalue(fx, CValue::ByVal(res, dest_layout));
                         }
+                        (ty::Float(_), ty::Int(IntTy::I128)) | (ty::Float(_), ty::Uint(UintTy::U128)) => {
+                            let signed = if let ty::Int(_) = to_ty.sty { true } else { false };
+                            let res = crate::codegen_i128::codegen_float_to_int(fx, operand, dest_layout.ty, signed);
+                            lval.write_cvalue(fx, res);
+                        }
+                        (ty::Int(IntTy::I128), ty::Float(_)) | (ty::Uint(UintTy::U128), ty::Float(_)) => {
+                            let signed = if let ty::Int(_) = from_ty.sty { true } else { false };
+                            let res = crate::codegen_i128::codegen_int_to_float(fx, operand, dest_layout.ty, signed);
+                            lval.write_cvalue(fx, res);
+                        }
                         (ty::Float(_), ty::Int(_)) => {
                             let from = operand.load_scalar(fx);
                             let i_type = fx.clif_type(to_ty).unwrap();
@@ -638,7 +798,7 @@ fn trans_stmt<'a, 'tcx: 'a>(
                         .ty
                         .is_sized(fx.tcx.at(DUMMY_SP), ParamEnv::reveal_all()));
                     let ty_size = fx.layout_of(ty).size.bytes();
-                    let val = CValue::const_val(fx, fx.tcx.types.usize, ty_size as i64);
+                    let val = CValue::const_val(fx, fx.tcx.types.usize, ty_size as i128);
                     lval.write_cvalue(fx, val);
                 }
                 Rvalue::Aggregate(kind, operands) => match **kind {
@@ -661,7 +821,9 @@ fn trans_stmt<'a, 'tcx: 'a>(
         | StatementKind::Retag { .. }
         | StatementKind::AscribeUserType(..) => {}
 
-        StatementKind::InlineAsm { .. } => unimpl!("Inline assembly is not supported"),
+        StatementKind::InlineAsm { asm, outputs, inputs } => {
+            crate::inline_asm::codegen_inline_asm(fx, asm, outputs, inputs);
+        }
     }
 }
 
@@ -699,7 +861,7 @@ pub fn trans_get_discriminant<'a, 'tcx: 'a>(
                 .map_or(index.as_u32() as u128, |def| {
                     def.discriminant_for_variant(fx.tcx, index).val
                 });
-            return CValue::const_val(fx, dest_layout.ty, discr_val as u64 as i64);
+            return CValue::const_val(fx, dest_layout.ty, discr_val as i128);
         }
         layout::Variants::Tagged { .. } | layout::Variants::NicheFilling { .. } => {}
     }
@@ -851,6 +1013,22 @@ pub fn trans_int_binop<'a, 'tcx: 'a>(
             "int binop requires lhs and rhs of same type"
         );
     }
+
+    if let Some(res) = crate::codegen_i128::maybe_codegen(fx, bin_op, false, signed, lhs, rhs, out_ty) {
+        return res;
+    }
+
+    // Cranelift can't shift by a 128bit amount, but only the lower bits matter anyway
+    let rhs = if (bin_op == BinOp::Shl || bin_op == BinOp::Shr)
+        && fx.clif_type(rhs.layout().ty) == Some(types::I128)
+    {
+        let amount = rhs.load_scalar(fx);
+        let amount = clif_intcast(fx, amount, types::I64, false);
+        CValue::ByVal(amount, fx.layout_of(fx.tcx.types.u64))
+    } else {
+        rhs
+    };
+
     binop_match! {
         fx, bin_op, signed, lhs, rhs, out_ty, "int/uint";
         Add (_) iadd;
@@ -898,19 +1076,92 @@ pub fn trans_checked_int_binop<'a, 'tcx: 'a>(
         );
     }
 
+    if let Some(res) = crate::codegen_i128::maybe_codegen(fx, bin_op, true, signed, in_lhs, in_rhs, out_ty) {
+        return res;
+    }
+
     let lhs = in_lhs.load_scalar(fx);
     let rhs = in_rhs.load_scalar(fx);
-    let res = match bin_op {
-        BinOp::Add => fx.bcx.ins().iadd(lhs, rhs),
-        BinOp::Sub => fx.bcx.ins().isub(lhs, rhs),
-        BinOp::Mul => fx.bcx.ins().imul(lhs, rhs),
-        BinOp::Shl => fx.bcx.ins().ishl(lhs, rhs),
-        BinOp::Shr => {
-            if !signed {
-                fx.bcx.ins().ushr(lhs, rhs)
+    let ty = fx.bcx.func.dfg.value_type(lhs);
+    let bits = i64::from(ty.bits());
+
+    let (res, has_overflow) = match bin_op {
+        BinOp::Add => {
+            let res = fx.bcx.ins().iadd(lhs, rhs);
+            let has_overflow = if !signed {
+                // The result wrapped around
+                fx.bcx.ins().icmp(IntCC::UnsignedLessThan, res, lhs)
             } else {
-                fx.bcx.ins().sshr(lhs, rhs)
-            }
+                // The result moved in the opposite direction of the sign of `rhs`
+                let rhs_is_negative = fx.bcx.ins().icmp_imm(IntCC::SignedLessThan, rhs, 0);
+                let slt = fx.bcx.ins().icmp(IntCC::SignedLessThan, res, lhs);
+                fx.bcx.ins().bxor(rhs_is_negative, slt)
+            };
+            (res, has_overflow)
+        }
+        BinOp::Sub => {
+            let res = fx.bcx.ins().isub(lhs, rhs);
+            let has_overflow = if !signed {
+                fx.bcx.ins().icmp(IntCC::UnsignedLessThan, lhs, rhs)
+            } else {
+                let rhs_is_negative = fx.bcx.ins().icmp_imm(IntCC::SignedLessThan, rhs, 0);
+                let sgt = fx.bcx.ins().icmp(IntCC::SignedGreaterThan, res, lhs);
+                fx.bcx.ins().bxor(rhs_is_negative, sgt)
+            };
+            (res, has_overflow)
+        }
+        BinOp::Mul if ty == types::I64 => {
+            let res = fx.bcx.ins().imul(lhs, rhs);
+            let has_overflow = if !signed {
+                let val_hi = fx.bcx.ins().umulhi(lhs, rhs);
+                fx.bcx.ins().icmp_imm(IntCC::NotEqual, val_hi, 0)
+            } else {
+                // The high half must be the sign extension of the low half
+                let val_hi = fx.bcx.ins().smulhi(lhs, rhs);
+                let res_sign = fx.bcx.ins().sshr_imm(res, 63);
+                fx.bcx.ins().icmp(IntCC::NotEqual, val_hi, res_sign)
+            };
+            (res, has_overflow)
+        }
+        BinOp::Mul => {
+            // The full product of two integers of at most 32 bits fits in 64 bits
+            let (lhs_wide, rhs_wide) = if !signed {
+                (fx.bcx.ins().uextend(types::I64, lhs), fx.bcx.ins().uextend(types::I64, rhs))
+            } else {
+                (fx.bcx.ins().sextend(types::I64, lhs), fx.bcx.ins().sextend(types::I64, rhs))
+            };
+            let res_wide = fx.bcx.ins().imul(lhs_wide, rhs_wide);
+            let res = fx.bcx.ins().ireduce(ty, res_wide);
+            let has_overflow = if !signed {
+                let val_hi = fx.bcx.ins().ushr_imm(res_wide, bits);
+                fx.bcx.ins().icmp_imm(IntCC::NotEqual, val_hi, 0)
+            } else {
+                let res_extended = fx.bcx.ins().sextend(types::I64, res);
+                fx.bcx.ins().icmp(IntCC::NotEqual, res_wide, res_extended)
+            };
+            (res, has_overflow)
+        }
+        BinOp::Shl | BinOp::Shr => {
+            // Cranelift can't shift by a 128bit amount, but only the lower bits matter anyway
+            let (amount, has_overflow) = if fx.bcx.func.dfg.value_type(rhs) == types::I128 {
+                let (amount_lsb, amount_msb) = fx.bcx.ins().isplit(rhs);
+                let msb_nonzero = fx.bcx.ins().icmp_imm(IntCC::NotEqual, amount_msb, 0);
+                let lsb_too_big =
+                    fx.bcx.ins().icmp_imm(IntCC::UnsignedGreaterThan, amount_lsb, bits - 1);
+                (amount_lsb, fx.bcx.ins().bor(msb_nonzero, lsb_too_big))
+            } else {
+                (rhs, fx.bcx.ins().icmp_imm(IntCC::UnsignedGreaterThan, rhs, bits - 1))
+            };
+
+            // Like LLVM, only use the lower bits of the shift amount
+            let amount = fx.bcx.ins().band_imm(amount, bits - 1);
+            let res = match (bin_op, signed) {
+                (BinOp::Shl, _) => fx.bcx.ins().ishl(lhs, amount),
+                (BinOp::Shr, false) => fx.bcx.ins().ushr(lhs, amount),
+                (BinOp::Shr, true) => fx.bcx.ins().sshr(lhs, amount),
+                _ => unreachable!(),
+            };
+            (res, has_overflow)
         }
         _ => bug!(
             "binop {:?} on checked int/uint lhs: {:?} rhs: {:?}",
@@ -920,8 +1171,7 @@ pub fn trans_checked_int_binop<'a, 'tcx: 'a>(
         ),
     };
 
-    // TODO: check for overflow
-    let has_overflow = fx.bcx.ins().iconst(types::I8, 0);
+    let has_overflow = fx.bcx.ins().bint(types::I8, has_overflow);
 
     let out_place = CPlace::new_stack_slot(fx, out_ty);
     let out_layout = out_place.layout();
@@ -930,7 +1180,7 @@ pub fn trans_checked_int_binop<'a, 'tcx: 'a>(
     out_place.to_cvalue(fx)
 }
 
-fn trans_float_binop<'a, 'tcx: 'a>(
+pub fn trans_float_binop<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     bin_op: BinOp,
     lhs: CValue<'tcx>,
@@ -0,0 +1,262 @@
+//! Replaces 128-bit operators with lang item calls or split 64-bit operations
+
+use crate::prelude::*;
+
+/// When `checked` is true, the result is a `(result, has_overflow)` pair of type `out_ty`.
+pub fn maybe_codegen<'a, 'tcx>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    bin_op: BinOp,
+    checked: bool,
+    is_signed: bool,
+    lhs: CValue<'tcx>,
+    rhs: CValue<'tcx>,
+    out_ty: Ty<'tcx>,
+) -> Option<CValue<'tcx>> {
+    if lhs.layout().ty != fx.tcx.types.u128 && lhs.layout().ty != fx.tcx.types.i128 {
+        return None;
+    }
+
+    match bin_op {
+        // Cranelift legalizes these by splitting them into two 64-bit operations
+        BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor => {
+            assert!(!checked);
+            None
+        }
+        BinOp::Add | BinOp::Sub if !checked => None,
+        BinOp::Add | BinOp::Sub => {
+            let lhs_val = lhs.load_scalar(fx);
+            let rhs_val = rhs.load_scalar(fx);
+            let res = if bin_op == BinOp::Add {
+                fx.bcx.ins().iadd(lhs_val, rhs_val)
+            } else {
+                fx.bcx.ins().isub(lhs_val, rhs_val)
+            };
+            let res = CValue::ByVal(res, lhs.layout());
+
+            let has_overflow = if !is_signed {
+                // The result wrapped around
+                let (a, b) = if bin_op == BinOp::Add { (res, lhs) } else { (lhs, rhs) };
+                maybe_codegen(fx, BinOp::Lt, false, false, a, b, fx.tcx.types.bool)
+                    .unwrap()
+                    .load_scalar(fx)
+            } else {
+                // The result moved in the opposite direction of the sign of `rhs`
+                let (_rhs_lsb, rhs_msb) = fx.bcx.ins().isplit(rhs_val);
+                let rhs_is_negative = fx.bcx.ins().icmp_imm(IntCC::SignedLessThan, rhs_msb, 0);
+                let rhs_is_negative = fx.bcx.ins().bint(types::I8, rhs_is_negative);
+                let cmp_op = if bin_op == BinOp::Add { BinOp::Lt } else { BinOp::Gt };
+                let moved = maybe_codegen(fx, cmp_op, false, true, res, lhs, fx.tcx.types.bool)
+                    .unwrap()
+                    .load_scalar(fx);
+                fx.bcx.ins().bxor(rhs_is_negative, moved)
+            };
+
+            let res = res.load_scalar(fx);
+            Some(CValue::ByValPair(res, has_overflow, fx.layout_of(out_ty)))
+        }
+        BinOp::Offset => bug!("{:?} on 128bit int", bin_op),
+        BinOp::Mul if !checked => Some(fx.easy_call("__multi3", &[lhs, rhs], out_ty)),
+        BinOp::Mul if is_signed => {
+            let oflow_place = CPlace::new_stack_slot(fx, fx.tcx.types.i32);
+            let oflow_addr = oflow_place.to_addr(fx);
+            let oflow_addr = CValue::ByVal(oflow_addr, fx.layout_of(fx.tcx.mk_mut_ptr(fx.tcx.types.i32)));
+            let res = fx
+                .easy_call("__muloti4", &[lhs, rhs, oflow_addr], lhs.layout().ty)
+                .load_scalar(fx);
+            let oflow = oflow_place.to_cvalue(fx).load_scalar(fx);
+            let has_overflow = fx.bcx.ins().icmp_imm(IntCC::NotEqual, oflow, 0);
+            let has_overflow = fx.bcx.ins().bint(types::I8, has_overflow);
+            Some(CValue::ByValPair(res, has_overflow, fx.layout_of(out_ty)))
+        }
+        BinOp::Mul => {
+            // lhs * rhs = lhs_lsb * rhs_lsb
+            //           + ((lhs_msb * rhs_lsb + lhs_lsb * rhs_msb) << 64)
+            //           + ((lhs_msb * rhs_msb) << 128)
+            let lhs_val = lhs.load_scalar(fx);
+            let rhs_val = rhs.load_scalar(fx);
+            let (lhs_lsb, lhs_msb) = fx.bcx.ins().isplit(lhs_val);
+            let (rhs_lsb, rhs_msb) = fx.bcx.ins().isplit(rhs_val);
+
+            let lhs_msb_nonzero = fx.bcx.ins().icmp_imm(IntCC::NotEqual, lhs_msb, 0);
+            let rhs_msb_nonzero = fx.bcx.ins().icmp_imm(IntCC::NotEqual, rhs_msb, 0);
+            let both_msb_nonzero = fx.bcx.ins().band(lhs_msb_nonzero, rhs_msb_nonzero);
+
+            let cross_a_hi = fx.bcx.ins().umulhi(lhs_msb, rhs_lsb);
+            let cross_a_overflow = fx.bcx.ins().icmp_imm(IntCC::NotEqual, cross_a_hi, 0);
+            let cross_b_hi = fx.bcx.ins().umulhi(lhs_lsb, rhs_msb);
+            let cross_b_overflow = fx.bcx.ins().icmp_imm(IntCC::NotEqual, cross_b_hi, 0);
+
+            // Unless `both_msb_nonzero`, at most one of the cross products is non-zero, so
+            // adding them can't overflow.
+            let cross_a = fx.bcx.ins().imul(lhs_msb, rhs_lsb);
+            let cross_b = fx.bcx.ins().imul(lhs_lsb, rhs_msb);
+            let cross = fx.bcx.ins().iadd(cross_a, cross_b);
+
+            let res_lsb = fx.bcx.ins().imul(lhs_lsb, rhs_lsb);
+            let lsb_carry = fx.bcx.ins().umulhi(lhs_lsb, rhs_lsb);
+            let res_msb = fx.bcx.ins().iadd(lsb_carry, cross);
+            let msb_overflow = fx.bcx.ins().icmp(IntCC::UnsignedLessThan, res_msb, lsb_carry);
+
+            let has_overflow = fx.bcx.ins().bor(both_msb_nonzero, cross_a_overflow);
+            let has_overflow = fx.bcx.ins().bor(has_overflow, cross_b_overflow);
+            let has_overflow = fx.bcx.ins().bor(has_overflow, msb_overflow);
+            let has_overflow = fx.bcx.ins().bint(types::I8, has_overflow);
+
+            let res = fx.bcx.ins().iconcat(res_lsb, res_msb);
+            Some(CValue::ByValPair(res, has_overflow, fx.layout_of(out_ty)))
+        }
+        BinOp::Div => {
+            let name = if is_signed { "__divti3" } else { "__udivti3" };
+            Some(fx.easy_call(name, &[lhs, rhs], out_ty))
+        }
+        BinOp::Rem => {
+            let name = if is_signed { "__modti3" } else { "__umodti3" };
+            Some(fx.easy_call(name, &[lhs, rhs], out_ty))
+        }
+        BinOp::Shl | BinOp::Shr => {
+            let name = match (bin_op, is_signed) {
+                (BinOp::Shl, _) => "__ashlti3",
+                (BinOp::Shr, false) => "__lshrti3",
+                (BinOp::Shr, true) => "__ashrti3",
+                _ => unreachable!(),
+            };
+            let amount = rhs.load_scalar(fx);
+            let has_overflow = if checked {
+                // The shift amount must be less than 128
+                let has_overflow = if fx.bcx.func.dfg.value_type(amount) == types::I128 {
+                    let (amount_lsb, amount_msb) = fx.bcx.ins().isplit(amount);
+                    let msb_nonzero = fx.bcx.ins().icmp_imm(IntCC::NotEqual, amount_msb, 0);
+                    let lsb_too_big =
+                        fx.bcx.ins().icmp_imm(IntCC::UnsignedGreaterThan, amount_lsb, 127);
+                    fx.bcx.ins().bor(msb_nonzero, lsb_too_big)
+                } else {
+                    fx.bcx.ins().icmp_imm(IntCC::UnsignedGreaterThan, amount, 127)
+                };
+                Some(fx.bcx.ins().bint(types::I8, has_overflow))
+            } else {
+                None
+            };
+
+            // The shift amount is always passed as `u32`. Like LLVM, only use the lower 7 bits.
+            let amount = clif_intcast(fx, amount, types::I32, false);
+            let amount = fx.bcx.ins().band_imm(amount, 127);
+            let amount = CValue::ByVal(amount, fx.layout_of(fx.tcx.types.u32));
+            let res = fx.easy_call(name, &[lhs, amount], lhs.layout().ty);
+
+            if let Some(has_overflow) = has_overflow {
+                let res = res.load_scalar(fx);
+                Some(CValue::ByValPair(res, has_overflow, fx.layout_of(out_ty)))
+            } else {
+                Some(res)
+            }
+        }
+        _ if checked => bug!("checked {:?} on 128bit int", bin_op),
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            assert_eq!(fx.tcx.types.bool, out_ty);
+            let lhs_val = lhs.load_scalar(fx);
+            let rhs_val = rhs.load_scalar(fx);
+            let (lhs_lsb, lhs_msb) = fx.bcx.ins().isplit(lhs_val);
+            let (rhs_lsb, rhs_msb) = fx.bcx.ins().isplit(rhs_val);
+
+            let res = match bin_op {
+                BinOp::Eq => {
+                    let lsb_eq = fx.bcx.ins().icmp(IntCC::Equal, lhs_lsb, rhs_lsb);
+                    let msb_eq = fx.bcx.ins().icmp(IntCC::Equal, lhs_msb, rhs_msb);
+                    let res = fx.bcx.ins().band(lsb_eq, msb_eq);
+                    fx.bcx.ins().bint(types::I8, res)
+                }
+                BinOp::Ne => {
+                    let lsb_ne = fx.bcx.ins().icmp(IntCC::NotEqual, lhs_lsb, rhs_lsb);
+                    let msb_ne = fx.bcx.ins().icmp(IntCC::NotEqual, lhs_msb, rhs_msb);
+                    let res = fx.bcx.ins().bor(lsb_ne, msb_ne);
+                    fx.bcx.ins().bint(types::I8, res)
+                }
+                _ => {
+                    // The most significant halves decide, unless they are equal. In that case
+                    // the least significant halves are compared as unsigned integers.
+                    let (msb_cc, lsb_cc) = match (bin_op, is_signed) {
+                        (BinOp::Lt, false) => (IntCC::UnsignedLessThan, IntCC::UnsignedLessThan),
+                        (BinOp::Lt, true) => (IntCC::SignedLessThan, IntCC::UnsignedLessThan),
+                        (BinOp::Le, false) => (IntCC::UnsignedLessThan, IntCC::UnsignedLessThanOrEqual),
+                        (BinOp::Le, true) => (IntCC::SignedLessThan, IntCC::UnsignedLessThanOrEqual),
+                        (BinOp::Gt, false) => (IntCC::UnsignedGreaterThan, IntCC::UnsignedGreaterThan),
+                        (BinOp::Gt, true) => (IntCC::SignedGreaterThan, IntCC::UnsignedGreaterThan),
+                        (BinOp::Ge, false) => (IntCC::UnsignedGreaterThan, IntCC::UnsignedGreaterThanOrEqual),
+                        (BinOp::Ge, true) => (IntCC::SignedGreaterThan, IntCC::UnsignedGreaterThanOrEqual),
+                        _ => unreachable!(),
+                    };
+                    let msb_eq = fx.bcx.ins().icmp(IntCC::Equal, lhs_msb, rhs_msb);
+                    let msb_cmp = fx.bcx.ins().icmp(msb_cc, lhs_msb, rhs_msb);
+                    let msb_cmp = fx.bcx.ins().bint(types::I8, msb_cmp);
+                    let lsb_cmp = fx.bcx.ins().icmp(lsb_cc, lhs_lsb, rhs_lsb);
+                    let lsb_cmp = fx.bcx.ins().bint(types::I8, lsb_cmp);
+                    codegen_select(&mut fx.bcx, msb_eq, lsb_cmp, msb_cmp)
+                }
+            };
+
+            Some(CValue::ByVal(res, fx.layout_of(out_ty)))
+        }
+    }
+}
+
+/// Load a 128-bit integer constant
+pub fn codegen_const_u128<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    val: u128,
+) -> Value {
+    let lsb = fx.bcx.ins().iconst(types::I64, val as u64 as i64);
+    let msb = fx.bcx.ins().iconst(types::I64, (val >> 64) as u64 as i64);
+    fx.bcx.ins().iconcat(lsb, msb)
+}
+
+/// Cranelift's `Switch` only supports up to 64-bit values, so compare both halves
+/// of the discriminant against every value instead.
+pub fn codegen_switch_int<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    discr: Value,
+    values: &[u128],
+    targets: &[BasicBlock],
+) {
+    let (discr_lsb, discr_msb) = fx.bcx.ins().isplit(discr);
+    for (value, target) in values.iter().zip(targets.iter()) {
+        let lsb_eq = fx.bcx.ins().icmp_imm(IntCC::Equal, discr_lsb, *value as u64 as i64);
+        let msb_eq = fx.bcx.ins().icmp_imm(IntCC::Equal, discr_msb, (*value >> 64) as u64 as i64);
+        let is_eq = fx.bcx.ins().band(lsb_eq, msb_eq);
+        let ebb = fx.get_ebb(*target);
+        fx.bcx.ins().brnz(is_eq, ebb, &[]);
+    }
+    let otherwise_ebb = fx.get_ebb(targets[targets.len() - 1]);
+    fx.bcx.ins().jump(otherwise_ebb, &[]);
+}
+
+pub fn codegen_int_to_float<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    from: CValue<'tcx>,
+    to_ty: Ty<'tcx>,
+    signed: bool,
+) -> CValue<'tcx> {
+    let name = match (signed, &to_ty.sty) {
+        (true, ty::Float(FloatTy::F32)) => "__floattisf",
+        (true, ty::Float(FloatTy::F64)) => "__floattidf",
+        (false, ty::Float(FloatTy::F32)) => "__floatuntisf",
+        (false, ty::Float(FloatTy::F64)) => "__floatuntidf",
+        _ => bug!("128bit int to {:?} cast", to_ty),
+    };
+    fx.easy_call(name, &[from], to_ty)
+}
+
+pub fn codegen_float_to_int<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    from: CValue<'tcx>,
+    to_ty: Ty<'tcx>,
+    signed: bool,
+) -> CValue<'tcx> {
+    let name = match (&from.layout().ty.sty, signed) {
+        (ty::Float(FloatTy::F32), true) => "__fixsfti",
+        (ty::Float(FloatTy::F64), true) => "__fixdfti",
+        (ty::Float(FloatTy::F32), false) => "__fixunssfti",
+        (ty::Float(FloatTy::F64), false) => "__fixunsdfti",
+        _ => bug!("{:?} to 128bit int cast", from.layout().ty),
+    };
+    fx.easy_call(name, &[from], to_ty)
+}
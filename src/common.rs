@@ -10,6 +10,11 @@ pub fn mir_var(loc: Local) -> Variable {
     Variable::with_u32(loc.index() as u32)
 }
 
+/// The value label used to find the locations of the values of an SSA local for debuginfo
+pub fn mir_value_label(loc: Local) -> ValueLabel {
+    ValueLabel::from_u32(loc.index() as u32)
+}
+
 pub fn pointer_ty(tcx: TyCtxt) -> types::Type {
     match tcx.data_layout.pointer_size.bits() {
         16 => types::I16,
@@ -25,6 +30,7 @@ fn scalar_to_clif_type(tcx: TyCtxt, scalar: &Scalar) -> Type {
         16 => types::I16,
         32 => types::I32,
         64 => types::I64,
+        128 => types::I128,
         size => bug!("Unsupported scalar size {}", size),
     }
 }
@@ -40,7 +46,7 @@ pub fn clif_type_from_ty<'a, 'tcx: 'a>(
             UintTy::U16 => types::I16,
             UintTy::U32 => types::I32,
             UintTy::U64 => types::I64,
-            UintTy::U128 => unimpl!("u128"),
+            UintTy::U128 => types::I128,
             UintTy::Usize => pointer_ty(tcx),
         },
         ty::Int(size) => match size {
@@ -48,7 +54,7 @@ pub fn clif_type_from_ty<'a, 'tcx: 'a>(
             IntTy::I16 => types::I16,
             IntTy::I32 => types::I32,
             IntTy::I64 => types::I64,
-            IntTy::I128 => unimpl!("i128"),
+            IntTy::I128 => types::I128,
             IntTy::Isize => pointer_ty(tcx),
         },
         ty::Char => types::I32,
@@ -65,6 +71,8 @@ pub fn clif_type_from_ty<'a, 'tcx: 'a>(
             }
         }
         ty::Param(_) => bug!("{:?}: {:?}", ty, ty.sty),
+        // FIXME map `#[repr(simd)]` types to vector types once Cranelift can codegen them on x86.
+        // Until then they are always kept in memory, see `intrinsics::simd`.
         _ => return None,
     })
 }
@@ -102,6 +110,14 @@ fn codegen_field<'a, 'tcx: 'a>(
     }
 }
 
+fn scalar_pair_calculate_b_offset(tcx: TyCtxt, a_scalar: &Scalar, b_scalar: &Scalar) -> i32 {
+    let b_offset = a_scalar
+        .value
+        .size(&tcx)
+        .align_to(b_scalar.value.align(&tcx).abi);
+    b_offset.bytes() as i32
+}
+
 /// A read-only value
 #[derive(Debug, Copy, Clone)]
 pub enum CValue<'tcx> {
@@ -135,18 +151,10 @@ impl<'tcx> CValue<'tcx> {
                 fx.bcx.ins().store(MemFlags::new(), value, addr, 0);
                 addr
             }
-            CValue::ByValPair(value, extra, layout) => {
-                let stack_slot = fx.bcx.create_stack_slot(StackSlotData {
-                    kind: StackSlotKind::ExplicitSlot,
-                    size: layout.size.bytes() as u32,
-                    offset: None,
-                });
-                let base = fx.bcx.ins().stack_addr(types::I64, stack_slot, 0);
-                let a_addr = codegen_field(fx, base, layout, mir::Field::new(0)).0;
-                let b_addr = codegen_field(fx, base, layout, mir::Field::new(1)).0;
-                fx.bcx.ins().store(MemFlags::new(), value, a_addr, 0);
-                fx.bcx.ins().store(MemFlags::new(), extra, b_addr, 0);
-                base
+            CValue::ByValPair(_, _, layout) => {
+                let place = CPlace::new_stack_slot(fx, layout.ty);
+                place.write_cvalue(fx, self);
+                place.to_addr(fx)
             }
         }
     }
@@ -176,20 +184,15 @@ impl<'tcx> CValue<'tcx> {
     {
         match self {
             CValue::ByRef(addr, layout) => {
-                assert_eq!(
-                    layout.size.bytes(),
-                    fx.tcx.data_layout.pointer_size.bytes() * 2
-                );
-                let val1_offset = layout.fields.offset(0).bytes() as i32;
-                let val2_offset = layout.fields.offset(1).bytes() as i32;
-                let val1 = fx
-                    .bcx
-                    .ins()
-                    .load(fx.pointer_type, MemFlags::new(), addr, val1_offset);
-                let val2 = fx
-                    .bcx
-                    .ins()
-                    .load(fx.pointer_type, MemFlags::new(), addr, val2_offset);
+                let (a_scalar, b_scalar) = match &layout.abi {
+                    layout::Abi::ScalarPair(a, b) => (a, b),
+                    _ => unreachable!(),
+                };
+                let b_offset = scalar_pair_calculate_b_offset(fx.tcx, a_scalar, b_scalar);
+                let clif_ty1 = crate::abi::scalar_to_clif_type(fx.tcx, a_scalar.clone());
+                let clif_ty2 = crate::abi::scalar_to_clif_type(fx.tcx, b_scalar.clone());
+                let val1 = fx.bcx.ins().load(clif_ty1, MemFlags::new(), addr, 0);
+                let val2 = fx.bcx.ins().load(clif_ty2, MemFlags::new(), addr, b_offset);
                 (val1, val2)
             }
             CValue::ByVal(_, _layout) => bug!("Please use load_value for ByVal"),
@@ -221,14 +224,20 @@ impl<'tcx> CValue<'tcx> {
     pub fn const_val<'a>(
         fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
         ty: Ty<'tcx>,
-        const_val: i64,
+        const_val: i128,
     ) -> CValue<'tcx>
     where
         'tcx: 'a,
     {
         let clif_ty = fx.clif_type(ty).unwrap();
         let layout = fx.layout_of(ty);
-        CValue::ByVal(fx.bcx.ins().iconst(clif_ty, const_val), layout)
+        let val = if clif_ty == types::I128 {
+            // Cranelift can't lower `iconst.i128`
+            crate::codegen_i128::codegen_const_u128(fx, const_val as u128)
+        } else {
+            fx.bcx.ins().iconst(clif_ty, const_val as i64)
+        };
+        CValue::ByVal(val, layout)
     }
 
     pub fn unchecked_cast_to(self, layout: TyLayout<'tcx>) -> Self {
@@ -360,6 +369,7 @@ impl<'a, 'tcx: 'a> CPlace<'tcx> {
         let (addr, dst_layout) = match self {
             CPlace::Var(var, _) => {
                 let data = from.load_scalar(fx);
+                fx.bcx.set_val_label(data, mir_value_label(var));
                 fx.bcx.def_var(mir_var(var), data);
                 return;
             }
@@ -380,10 +390,13 @@ impl<'a, 'tcx: 'a> CPlace<'tcx> {
                 fx.bcx.ins().store(MemFlags::new(), val, addr, 0);
             }
             CValue::ByValPair(val1, val2, _src_layout) => {
-                let val1_offset = dst_layout.fields.offset(0).bytes() as i32;
-                let val2_offset = dst_layout.fields.offset(1).bytes() as i32;
-                fx.bcx.ins().store(MemFlags::new(), val1, addr, val1_offset);
-                fx.bcx.ins().store(MemFlags::new(), val2, addr, val2_offset);
+                let (a_scalar, b_scalar) = match &dst_layout.abi {
+                    layout::Abi::ScalarPair(a, b) => (a, b),
+                    _ => bug!("write ByValPair to non scalar pair layout {:?}", dst_layout.ty),
+                };
+                let b_offset = scalar_pair_calculate_b_offset(fx.tcx, a_scalar, b_scalar);
+                fx.bcx.ins().store(MemFlags::new(), val1, addr, 0);
+                fx.bcx.ins().store(MemFlags::new(), val2, addr, b_offset);
             }
             CValue::ByRef(from, src_layout) => {
                 let size = dst_layout.size.bytes();
@@ -527,6 +540,19 @@ pub fn clif_intcast<'a, 'tcx: 'a>(
     if from == to {
         return val;
     }
+    if to == types::I128 {
+        let lsb = clif_intcast(fx, val, types::I64, signed);
+        let msb = if signed {
+            fx.bcx.ins().sshr_imm(lsb, 63)
+        } else {
+            fx.bcx.ins().iconst(types::I64, 0)
+        };
+        return fx.bcx.ins().iconcat(lsb, msb);
+    }
+    if from == types::I128 {
+        let (lsb, _msb) = fx.bcx.ins().isplit(val);
+        return clif_intcast(fx, lsb, to, signed);
+    }
     if to.wider_or_equal(from) {
         if signed {
             fx.bcx.ins().sextend(to, val)
@@ -550,11 +576,19 @@ pub struct FunctionCx<'a, 'tcx: 'a, B: Backend> {
     pub bcx: FunctionBuilder<'a>,
     pub ebb_map: HashMap<BasicBlock, Ebb>,
     pub local_map: HashMap<Local, CPlace<'tcx>>,
+    /// `None` when unwinding is disabled
+    pub landing_pads: Option<crate::unwind::LandingPads>,
 
     pub clif_comments: crate::pretty_clif::CommentWriter,
     pub constants: &'a mut crate::constant::ConstantCx,
     pub caches: &'a mut Caches<'tcx>,
     pub source_info_set: indexmap::IndexSet<SourceInfo>,
+    pub trap_on_assert: bool,
+
+    pub cgu_name: &'a str,
+    pub global_asm: &'a mut String,
+    /// Used to give the wrapper functions for `asm!` blocks unique names
+    pub inline_asm_index: u32,
 }
 
 impl<'a, 'tcx: 'a, B: Backend + 'a> fmt::Debug for FunctionCx<'a, 'tcx, B> {
@@ -1,7 +1,8 @@
 use std::borrow::Cow;
 
 use rustc::mir::interpret::{
-    read_target_uint, AllocId, AllocKind, Allocation, ConstValue, EvalResult, GlobalId, Scalar,
+    read_target_uint, write_target_uint, AllocId, AllocKind, Allocation, ConstValue, EvalResult,
+    GlobalId, Relocations, Scalar,
 };
 use rustc::ty::{Const, LazyConst};
 use rustc_mir::interpret::{
@@ -22,6 +23,8 @@ pub struct ConstantCx {
 enum TodoItem {
     Alloc(AllocId),
     Static(DefId),
+    /// The pointer to the symbol of a foreign static with a `#[linkage]` attribute
+    ExternWithLinkage(DefId),
 }
 
 impl ConstantCx {
@@ -29,9 +32,10 @@ impl ConstantCx {
         mut self,
         tcx: TyCtxt<'a, 'tcx, 'tcx>,
         module: &mut Module<B>,
+        debug_context: Option<&mut DebugContext<'tcx>>,
     ) {
         //println!("todo {:?}", self.todo);
-        define_all_allocs(tcx, module, &mut self);
+        define_all_allocs(tcx, module, debug_context, &mut self);
         //println!("done {:?}", self.done);
         self.done.clear();
     }
@@ -45,6 +49,16 @@ pub fn codegen_static_ref<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     static_: &Static<'tcx>,
 ) -> CPlace<'tcx> {
+    if crate::tls::is_thread_local(fx.tcx, static_.def_id) {
+        return crate::tls::codegen_tls_ref(fx, static_);
+    }
+
+    if is_extern_with_linkage(fx.tcx, static_.def_id) {
+        fx.constants.todo.insert(TodoItem::ExternWithLinkage(static_.def_id));
+        let data_id = data_id_for_extern_with_linkage(fx.tcx, fx.module, static_.def_id);
+        return cplace_for_dataid(fx, static_.ty, data_id);
+    }
+
     let data_id = data_id_for_static(fx.tcx, fx.module, static_.def_id, Linkage::Import);
     cplace_for_dataid(fx, static_.ty, data_id)
 }
@@ -91,6 +105,26 @@ pub fn force_eval_const<'a, 'tcx: 'a>(
     }
 }
 
+/// The value of `operand` when it is a constant
+pub fn mir_operand_get_const_val<'a, 'tcx: 'a>(
+    fx: &FunctionCx<'a, 'tcx, impl Backend>,
+    operand: &Operand<'tcx>,
+) -> Option<Const<'tcx>> {
+    match operand {
+        Operand::Constant(constant) => Some(force_eval_const(fx, fx.monomorphize(&constant.literal))),
+        // Constant arguments like the `simd_shuffle*` indices are usually promoted
+        Operand::Copy(Place::Promoted(promoted)) | Operand::Move(Place::Promoted(promoted)) => Some(
+            fx.tcx
+                .const_eval(ParamEnv::reveal_all().and(GlobalId {
+                    instance: fx.instance,
+                    promoted: Some(promoted.0),
+                }))
+                .unwrap(),
+        ),
+        Operand::Copy(_) | Operand::Move(_) => None,
+    }
+}
+
 fn trans_const_value<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     const_: Const<'tcx>,
@@ -100,15 +134,20 @@ fn trans_const_value<'a, 'tcx: 'a>(
     match ty.sty {
         ty::Bool => {
             let bits = const_.val.try_to_bits(layout.size).unwrap();
-            CValue::const_val(fx, ty, bits as u64 as i64)
+            CValue::const_val(fx, ty, bits as i128)
+        }
+        ty::Uint(_) | ty::Int(_) if layout.size.bits() == 128 => {
+            let bits = const_.val.try_to_bits(layout.size).unwrap();
+            let val = crate::codegen_i128::codegen_const_u128(fx, bits);
+            CValue::ByVal(val, layout)
         }
         ty::Uint(_) => {
             let bits = const_.val.try_to_bits(layout.size).unwrap();
-            CValue::const_val(fx, ty, bits as u64 as i64)
+            CValue::const_val(fx, ty, bits as i128)
         }
         ty::Int(_) => {
             let bits = const_.val.try_to_bits(layout.size).unwrap();
-            CValue::const_val(fx, ty, bits as i128 as i64)
+            CValue::const_val(fx, ty, bits as i128)
         }
         ty::FnDef(_def_id, _substs) => {
             CValue::ByRef(
@@ -162,6 +201,50 @@ fn trans_const_place<'a, 'tcx: 'a>(
     cplace_for_dataid(fx, const_.ty, data_id)
 }
 
+/// A `&'static` reference to a constant tuple of string slices followed by `u32`s, like the
+/// location argument of the `panic` and `panic_bounds_check` lang items.
+pub fn trans_const_location_ref<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    strs: &[&str],
+    ints: &[u32],
+) -> CValue<'tcx> {
+    let tcx = fx.tcx;
+    let field_tys = strs
+        .iter()
+        .map(|_| tcx.mk_static_str())
+        .chain(ints.iter().map(|_| tcx.types.u32))
+        .collect::<Vec<_>>();
+    let tuple_ty = tcx.intern_tup(&field_tys);
+    let layout = fx.layout_of(tuple_ty);
+
+    let endian = tcx.data_layout.endian;
+    let ptr_size = tcx.data_layout.pointer_size;
+    let mut bytes = vec![0; layout.size.bytes() as usize];
+    let mut relocations = Vec::new();
+    for (i, s) in strs.iter().enumerate() {
+        // The pointer is filled in by the relocation, followed by the length
+        let offset = layout.fields.offset(i);
+        relocations.push((offset, ((), tcx.allocate_bytes(s.as_bytes()))));
+        let len_start = (offset + ptr_size).bytes() as usize;
+        let len_bytes = &mut bytes[len_start..len_start + ptr_size.bytes() as usize];
+        write_target_uint(endian, len_bytes, s.len() as u128).unwrap();
+    }
+    for (i, &int) in ints.iter().enumerate() {
+        let start = layout.fields.offset(strs.len() + i).bytes() as usize;
+        write_target_uint(endian, &mut bytes[start..start + 4], int.into()).unwrap();
+    }
+    relocations.sort_by_key(|&(offset, _)| offset);
+    let mut alloc = Allocation::from_bytes(&bytes, layout.align.abi);
+    alloc.relocations = Relocations::from_presorted(relocations);
+
+    let alloc_id = tcx.alloc_map.lock().allocate(tcx.intern_const_alloc(alloc));
+    fx.constants.todo.insert(TodoItem::Alloc(alloc_id));
+    let data_id = data_id_for_alloc_id(fx.module, alloc_id);
+    let ptr = cplace_for_dataid(fx, tuple_ty, data_id).to_addr(fx);
+    let ref_layout = fx.layout_of(tcx.mk_imm_ref(tcx.types.re_static, tuple_ty));
+    CValue::ByVal(ptr, ref_layout)
+}
+
 fn data_id_for_alloc_id<B: Backend>(module: &mut Module<B>, alloc_id: AllocId) -> DataId {
     module
         .declare_data(&format!("__alloc_{}", alloc_id.0), Linkage::Local, false)
@@ -186,6 +269,23 @@ fn data_id_for_static<'a, 'tcx: 'a, B: Backend>(
         .unwrap()
 }
 
+/// Like for `rustc_codegen_llvm`, the value of a foreign static with a `#[linkage]` attribute is
+/// the address of the symbol, which is null for a missing `extern_weak` symbol.
+fn is_extern_with_linkage(tcx: TyCtxt, def_id: DefId) -> bool {
+    tcx.is_foreign_item(def_id) && tcx.codegen_fn_attrs(def_id).linkage.is_some()
+}
+
+fn data_id_for_extern_with_linkage<'a, 'tcx: 'a, B: Backend>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    module: &mut Module<B>,
+    def_id: DefId,
+) -> DataId {
+    let symbol_name = tcx.symbol_name(Instance::mono(tcx, def_id)).as_str();
+    module
+        .declare_data(&format!("_rust_extern_with_linkage_{}", symbol_name), Linkage::Local, false)
+        .unwrap()
+}
+
 fn cplace_for_dataid<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     ty: Ty<'tcx>,
@@ -201,6 +301,7 @@ fn cplace_for_dataid<'a, 'tcx: 'a>(
 fn define_all_allocs<'a, 'tcx: 'a, B: Backend + 'a>(
     tcx: TyCtxt<'a, 'tcx, 'tcx>,
     module: &mut Module<B>,
+    mut debug_context: Option<&mut DebugContext<'tcx>>,
     cx: &mut ConstantCx,
 ) {
     let memory = Memory::<TransPlaceInterpreter>::new(tcx.at(DUMMY_SP));
@@ -230,6 +331,23 @@ fn define_all_allocs<'a, 'tcx: 'a, B: Backend + 'a>(
                 let data_id = data_id_for_static(tcx, module, def_id, Linkage::Export);
                 (data_id, alloc)
             }
+            TodoItem::ExternWithLinkage(def_id) => {
+                let data_id = data_id_for_extern_with_linkage(tcx, module, def_id);
+                if cx.done.contains(&data_id) {
+                    continue;
+                }
+
+                // FIXME Cranelift doesn't support weak imports, so the symbol must exist
+                let symbol_data_id = data_id_for_static(tcx, module, def_id, Linkage::Import);
+                let mut data_ctx = DataContext::new();
+                data_ctx.define_zeroinit(tcx.data_layout.pointer_size.bytes() as usize);
+                let global_value = module.declare_data_in_data(symbol_data_id, &mut data_ctx);
+                data_ctx.write_data_addr(0, global_value, 0);
+
+                module.define_data(data_id, &data_ctx).unwrap();
+                cx.done.insert(data_id);
+                continue;
+            }
         };
 
         //("data_id {}", data_id);
@@ -263,7 +381,7 @@ fn define_all_allocs<'a, 'tcx: 'a, B: Backend + 'a>(
                     data_id_for_alloc_id(module, reloc)
                 }
                 AllocKind::Static(def_id) => {
-                    cx.todo.insert(TodoItem::Static(def_id));
+                    // Statics are only defined by the cgu containing their mono item
                     data_id_for_static(tcx, module, def_id, Linkage::Import)
                 }
             };
@@ -274,6 +392,11 @@ fn define_all_allocs<'a, 'tcx: 'a, B: Backend + 'a>(
 
         module.define_data(data_id, &data_ctx).unwrap();
         cx.done.insert(data_id);
+
+        if let (TodoItem::Static(def_id), Some(debug_context)) = (todo_item, debug_context.as_mut()) {
+            let symbol_name = tcx.symbol_name(Instance::mono(tcx, def_id)).as_str();
+            debug_context.define_static(tcx, def_id, &symbol_name);
+        }
     }
 
     assert!(cx.todo.is_empty(), "{:?}", cx.todo);
@@ -1,19 +1,25 @@
 use crate::prelude::*;
 
-use std::marker::PhantomData;
+use rustc::mir::interpret::truncate;
+use rustc::ty::layout::LayoutCx;
+use rustc::ty::DefIdTree;
+use rustc_data_structures::indexed_vec::IndexVec;
 
 use syntax::source_map::FileName;
 
+use cranelift::codegen::ir::{InstructionData, Opcode, ValueDef, ValueLoc};
+use cranelift::codegen::ValueLabelsRanges;
+
 use gimli::write::{
-    Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, Range,
-    RangeList, Result, SectionId, UnitEntryId,
+    Address, AttributeValue, DwarfUnit, EndianVec, Expression, LineProgram, Location,
+    LocationList, Range, RangeList, Result, SectionId, StringId, UnitEntryId,
     Writer, FileId, LineStringTable, LineString, Sections,
 };
 use gimli::{Encoding, Format, RunTimeEndian, LineEncoding};
 
 use faerie::*;
 
-fn target_endian(tcx: TyCtxt) -> RunTimeEndian {
+pub fn target_endian(tcx: TyCtxt) -> RunTimeEndian {
     use rustc::ty::layout::Endian;
 
     match tcx.data_layout.endian {
@@ -48,12 +54,45 @@ fn line_program_add_file(line_program: &mut LineProgram, line_strings: &mut Line
     }
 }
 
+/// The DWARF version to emit. It can be chosen using `-Cllvm-args=dwarf-version=<version>` or
+/// LLVM's `-Cllvm-args=-dwarf-version=<version>` spelling. It is only available through
+/// `-Cllvm-args` because rustc has no session option for the DWARF version yet.
+///
+/// gimli takes care of using the sections and forms of the chosen version, like `.debug_rnglists`
+/// and `.debug_line_str` for DWARF 5.
+fn dwarf_version(sess: &Session) -> u16 {
+    if let Some(version) = crate::backend_option(sess, "dwarf-version") {
+        return match version.parse() {
+            Ok(version @ 2..=5) => version,
+            _ => sess.fatal(&format!(
+                "unsupported DWARF version `{}` in -Cllvm-args, expected 2, 3, 4 or 5",
+                version
+            )),
+        };
+    }
+
+    // macOS doesn't seem to support DWARF > 3
+    if sess.target.target.options.is_like_osx {
+        3
+    } else {
+        4
+    }
+}
+
+fn layout_cx<'a, 'tcx: 'a>(tcx: TyCtxt<'a, 'tcx, 'tcx>) -> LayoutCx<'tcx, TyCtxt<'a, 'tcx, 'tcx>> {
+    LayoutCx {
+        tcx,
+        param_env: ParamEnv::reveal_all(),
+    }
+}
+
 #[derive(Clone)]
-struct DebugReloc {
+pub struct DebugReloc {
     offset: u32,
     size: u8,
     name: DebugRelocName,
     addend: i64,
+    pc_relative: bool,
 }
 
 #[derive(Clone)]
@@ -63,31 +102,99 @@ enum DebugRelocName {
 }
 
 impl DebugReloc {
-    fn name<'a>(&self, ctx: &'a DebugContext) -> &'a str {
+    fn name<'a>(&self, symbols: &'a indexmap::IndexSet<String>) -> &'a str {
         match self.name {
             DebugRelocName::Section(id) => id.name(),
-            DebugRelocName::Symbol(index) => ctx.symbols.get_index(index).unwrap(),
+            DebugRelocName::Symbol(index) => symbols.get_index(index).unwrap(),
         }
     }
 }
 
+pub fn declare_section(artifact: &mut Artifact, name: &str, section: &mut WriterRelocate) {
+    if !section.writer.slice().is_empty() {
+        artifact
+            .declare_with(name, Decl::DebugSection, section.writer.take())
+            .unwrap();
+    }
+}
+
+pub fn link_section(
+    artifact: &mut Artifact,
+    symbols: &indexmap::IndexSet<String>,
+    name: &str,
+    section: &WriterRelocate,
+) {
+    for reloc in &section.relocs {
+        let faerie_reloc = if reloc.pc_relative {
+            assert_eq!(reloc.size, 4);
+            faerie::Reloc::Raw {
+                reloc: 2, // R_X86_64_PC32
+                addend: reloc.addend as i32,
+            }
+        } else {
+            faerie::Reloc::Debug {
+                size: reloc.size,
+                addend: reloc.addend as i32,
+            }
+        };
+        artifact
+            .link_with(
+                faerie::Link {
+                    from: name,
+                    to: reloc.name(symbols),
+                    at: u64::from(reloc.offset),
+                },
+                faerie_reloc,
+            )
+            .expect("faerie relocation error");
+    }
+}
+
+/// A file, line and column, with the file added to the line program.
+#[derive(Copy, Clone)]
+struct SourceLocation {
+    file: FileId,
+    line: u64,
+    col: u64,
+}
+
+fn set_decl_location(dwarf: &mut DwarfUnit, entry_id: UnitEntryId, loc: SourceLocation) {
+    let entry = dwarf.unit.get_mut(entry_id);
+
+    entry.set(gimli::DW_AT_decl_file, AttributeValue::FileIndex(Some(loc.file)));
+    entry.set(gimli::DW_AT_decl_line, AttributeValue::Udata(loc.line));
+    // FIXME: probably omit this
+    entry.set(gimli::DW_AT_decl_column, AttributeValue::Udata(loc.col));
+}
+
 pub struct DebugContext<'tcx> {
     endian: RunTimeEndian,
     symbols: indexmap::IndexSet<String>,
 
     dwarf: DwarfUnit,
-    unit_range_list: RangeList,
 
-    _dummy: PhantomData<&'tcx ()>,
+    types: HashMap<Ty<'tcx>, UnitEntryId>,
+    namespaces: HashMap<DefId, UnitEntryId>,
+}
+
+/// The part of `DebugContext` that doesn't need the `TyCtxt`. The debuginfo depending on the
+/// compiled code of the functions and the emission of the sections are done using it on the
+/// worker thread compiling the cgu.
+pub struct DebugUnit {
+    endian: RunTimeEndian,
+    symbols: indexmap::IndexSet<String>,
+
+    dwarf: DwarfUnit,
+    unit_range_list: RangeList,
 }
 
 impl<'a, 'tcx: 'a> DebugContext<'tcx> {
     pub fn new(tcx: TyCtxt, address_size: u8) -> Self {
+        // FIXME support split DWARF (`.dwo` files). This requires referring to addresses in the
+        // `.dwo` file through `.debug_addr` using `DW_FORM_addrx`, which gimli can't write yet.
         let encoding = Encoding {
             format: Format::Dwarf32,
-            // TODO: this should be configurable
-            // macOS doesn't seem to support DWARF > 3
-            version: 3,
+            version: dwarf_version(tcx.sess),
             address_size,
         };
 
@@ -138,35 +245,338 @@ impl<'a, 'tcx: 'a> DebugContext<'tcx> {
             symbols: indexmap::IndexSet::new(),
 
             dwarf,
-            unit_range_list: RangeList(Vec::new()),
 
-            _dummy: PhantomData,
+            types: HashMap::new(),
+            namespaces: HashMap::new(),
         }
     }
 
-    fn emit_location(&mut self, tcx: TyCtxt<'a, 'tcx, 'tcx>, entry_id: UnitEntryId, span: Span) {
+    /// Drop the caches referencing the `TyCtxt` once all mono items of the cgu are translated.
+    pub fn into_unit(self) -> DebugUnit {
+        DebugUnit {
+            endian: self.endian,
+            symbols: self.symbols,
+
+            dwarf: self.dwarf,
+            unit_range_list: RangeList(Vec::new()),
+        }
+    }
+
+    fn source_location(&mut self, tcx: TyCtxt<'a, 'tcx, 'tcx>, span: Span) -> SourceLocation {
         let loc = tcx.sess.source_map().lookup_char_pos(span.lo());
 
-        let file_id = line_program_add_file(
+        let file = line_program_add_file(
             &mut self.dwarf.unit.line_program,
             &mut self.dwarf.line_strings,
             &loc.file.name,
         );
 
-        let entry = self.dwarf.unit.get_mut(entry_id);
+        SourceLocation {
+            file,
+            line: loc.line as u64,
+            col: loc.col.to_usize() as u64,
+        }
+    }
 
-        entry.set(gimli::DW_AT_decl_file, AttributeValue::FileIndex(Some(file_id)));
-        entry.set(
-            gimli::DW_AT_decl_line,
-            AttributeValue::Udata(loc.line as u64),
+    fn emit_location(&mut self, tcx: TyCtxt<'a, 'tcx, 'tcx>, entry_id: UnitEntryId, span: Span) {
+        let loc = self.source_location(tcx, span);
+        set_decl_location(&mut self.dwarf, entry_id, loc);
+    }
+
+    /// Get the `DW_TAG_namespace` of the item `def_id`. The namespace of the crate root is named
+    /// after the crate.
+    fn item_namespace(&mut self, tcx: TyCtxt<'a, 'tcx, 'tcx>, def_id: DefId) -> UnitEntryId {
+        if let Some(&namespace_id) = self.namespaces.get(&def_id) {
+            return namespace_id;
+        }
+
+        let def_key = tcx.def_key(def_id);
+        let (parent_id, name) = match def_key.parent {
+            Some(parent_index) => {
+                let parent = DefId {
+                    krate: def_id.krate,
+                    index: parent_index,
+                };
+                let name = def_key.disambiguated_data.data.as_interned_str().as_str().to_string();
+                (self.item_namespace(tcx, parent), name)
+            }
+            None => (self.dwarf.unit.root(), tcx.crate_name(def_id.krate).as_str().to_string()),
+        };
+
+        let namespace_id = self.dwarf.unit.add(parent_id, gimli::DW_TAG_namespace);
+        let name = self.dwarf.strings.add(name);
+        let namespace_entry = self.dwarf.unit.get_mut(namespace_id);
+        namespace_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+
+        self.namespaces.insert(def_id, namespace_id);
+        namespace_id
+    }
+
+    /// Get the DIE describing `ty`. It is created together with the DIEs of all types it
+    /// references on first use.
+    fn dwarf_ty(&mut self, tcx: TyCtxt<'a, 'tcx, 'tcx>, ty: Ty<'tcx>) -> UnitEntryId {
+        if let Some(&type_id) = self.types.get(&ty) {
+            return type_id;
+        }
+
+        let cx = layout_cx(tcx);
+        let layout = cx.layout_of(ty).unwrap();
+        // Fat pointers are described as a struct of the data pointer and the metadata
+        let is_thin_ptr = match layout.abi {
+            Abi::ScalarPair(..) => false,
+            _ => true,
+        };
+
+        let tag = match ty.sty {
+            ty::Bool | ty::Char | ty::Int(_) | ty::Uint(_) | ty::Float(_) => {
+                gimli::DW_TAG_base_type
+            }
+            ty::Ref(..) | ty::RawPtr(_) | ty::FnPtr(_) if is_thin_ptr => gimli::DW_TAG_pointer_type,
+            ty::Array(..) => gimli::DW_TAG_array_type,
+            ty::Adt(adt_def, _) if adt_def.is_union() => gimli::DW_TAG_union_type,
+            _ => gimli::DW_TAG_structure_type,
+        };
+
+        let root = self.dwarf.unit.root();
+        let type_id = self.dwarf.unit.add(root, tag);
+        // Register the type before recursing, so recursive types terminate
+        self.types.insert(ty, type_id);
+
+        let name = self.dwarf.strings.add(ty.to_string());
+        let type_entry = self.dwarf.unit.get_mut(type_id);
+        type_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        type_entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(layout.size.bytes()));
+
+        let encoding = match ty.sty {
+            ty::Bool => Some(gimli::DW_ATE_boolean),
+            ty::Char => Some(gimli::DW_ATE_UTF),
+            ty::Int(_) => Some(gimli::DW_ATE_signed),
+            ty::Uint(_) => Some(gimli::DW_ATE_unsigned),
+            ty::Float(_) => Some(gimli::DW_ATE_float),
+            _ => None,
+        };
+        if let Some(encoding) = encoding {
+            type_entry.set(gimli::DW_AT_encoding, AttributeValue::Encoding(encoding));
+            return type_id;
+        }
+
+        match ty.sty {
+            ty::Ref(_, pointee_ty, _) | ty::RawPtr(ty::TypeAndMut { ty: pointee_ty, .. }) => {
+                if is_thin_ptr {
+                    let pointee_id = self.dwarf_ty(tcx, pointee_ty);
+                    let type_entry = self.dwarf.unit.get_mut(type_id);
+                    type_entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(pointee_id));
+                } else {
+                    self.add_fat_ptr_members(tcx, type_id, layout, pointee_ty);
+                }
+            }
+            // Function pointers are described as `void *`
+            ty::FnPtr(_) if is_thin_ptr => {}
+            ty::Array(elem_ty, _) => {
+                let elem_id = self.dwarf_ty(tcx, elem_ty);
+                let type_entry = self.dwarf.unit.get_mut(type_id);
+                type_entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(elem_id));
+
+                if let layout::FieldPlacement::Array { count, .. } = layout.fields {
+                    let subrange_id = self.dwarf.unit.add(type_id, gimli::DW_TAG_subrange_type);
+                    let subrange_entry = self.dwarf.unit.get_mut(subrange_id);
+                    subrange_entry.set(gimli::DW_AT_count, AttributeValue::Udata(count));
+                }
+            }
+            ty::Tuple(_) | ty::Closure(..) => {
+                let field_names = (0..layout.fields.count()).map(|i| format!("__{}", i));
+                self.add_members(tcx, type_id, layout, field_names);
+            }
+            ty::Adt(adt_def, _) => match layout.variants {
+                layout::Variants::Single { index } => {
+                    // Enums without variants have a `Single` layout too
+                    if let Some(variant) = adt_def.variants.get(index) {
+                        let field_names = variant.fields.iter().map(|field| field.ident.to_string());
+                        self.add_members(tcx, type_id, layout, field_names);
+                    }
+                }
+                layout::Variants::Tagged { .. } | layout::Variants::NicheFilling { .. } => {
+                    self.add_variant_part(tcx, type_id, layout, adt_def);
+                }
+            },
+            // All other types are opaque
+            _ => {}
+        }
+
+        type_id
+    }
+
+    fn add_member(
+        &mut self,
+        tcx: TyCtxt<'a, 'tcx, 'tcx>,
+        parent: UnitEntryId,
+        name: &str,
+        ty: Ty<'tcx>,
+        offset: Size,
+    ) -> UnitEntryId {
+        let member_type_id = self.dwarf_ty(tcx, ty);
+        let name = self.dwarf.strings.add(name);
+        let member_id = self.dwarf.unit.add(parent, gimli::DW_TAG_member);
+        let member_entry = self.dwarf.unit.get_mut(member_id);
+        member_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        member_entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(member_type_id));
+        member_entry.set(
+            gimli::DW_AT_data_member_location,
+            AttributeValue::Udata(offset.bytes()),
         );
-        // FIXME: probably omit this
-        entry.set(
-            gimli::DW_AT_decl_column,
-            AttributeValue::Udata(loc.col.to_usize() as u64),
+        member_id
+    }
+
+    /// Add a `DW_TAG_member` for every field of `layout`.
+    fn add_members(
+        &mut self,
+        tcx: TyCtxt<'a, 'tcx, 'tcx>,
+        parent: UnitEntryId,
+        layout: TyLayout<'tcx>,
+        field_names: impl Iterator<Item = String>,
+    ) {
+        let cx = layout_cx(tcx);
+        for (i, field_name) in field_names.enumerate() {
+            let field_layout = layout.field(&cx, i).unwrap();
+            self.add_member(tcx, parent, &field_name, field_layout.ty, layout.fields.offset(i));
+        }
+    }
+
+    /// Describe a pointer to an unsized type the same way as the LLVM backend does, as a struct
+    /// of the data pointer and either the length or the vtable pointer.
+    fn add_fat_ptr_members(
+        &mut self,
+        tcx: TyCtxt<'a, 'tcx, 'tcx>,
+        type_id: UnitEntryId,
+        layout: TyLayout<'tcx>,
+        pointee_ty: Ty<'tcx>,
+    ) {
+        let cx = layout_cx(tcx);
+        let (data_ptr_ty, metadata_name) = match tcx.struct_tail(pointee_ty).sty {
+            ty::Slice(elem_ty) => (tcx.mk_imm_ptr(elem_ty), "length"),
+            ty::Str => (tcx.mk_imm_ptr(tcx.types.u8), "length"),
+            _ => (layout.field(&cx, 0).unwrap().ty, "vtable"),
+        };
+        let data_ptr_name = if metadata_name == "length" { "data_ptr" } else { "pointer" };
+
+        self.add_member(tcx, type_id, data_ptr_name, data_ptr_ty, layout.fields.offset(0));
+        let metadata_ty = layout.field(&cx, 1).unwrap().ty;
+        self.add_member(tcx, type_id, metadata_name, metadata_ty, layout.fields.offset(1));
+    }
+
+    /// Describe the variants of an enum with a `DW_TAG_variant_part`. Every variant is a
+    /// `DW_TAG_variant` containing a single member, whose type is a struct of the variant fields.
+    /// The variant without `DW_AT_discr_value` is the dataful variant of a niche filling layout.
+    fn add_variant_part(
+        &mut self,
+        tcx: TyCtxt<'a, 'tcx, 'tcx>,
+        type_id: UnitEntryId,
+        layout: TyLayout<'tcx>,
+        adt_def: &'tcx ty::AdtDef,
+    ) {
+        let cx = layout_cx(tcx);
+
+        let variant_part_id = self.dwarf.unit.add(type_id, gimli::DW_TAG_variant_part);
+
+        // Both tagged and niche filling layouts store the discriminant in field 0
+        let discr_layout = layout.field(&cx, 0).unwrap();
+        let discr_id = self.add_member(
+            tcx,
+            variant_part_id,
+            "__discriminant",
+            discr_layout.ty,
+            layout.fields.offset(0),
         );
+        let discr_entry = self.dwarf.unit.get_mut(discr_id);
+        discr_entry.set(gimli::DW_AT_artificial, AttributeValue::Flag(true));
+        let variant_part_entry = self.dwarf.unit.get_mut(variant_part_id);
+        variant_part_entry.set(gimli::DW_AT_discr, AttributeValue::UnitRef(discr_id));
+
+        for (index, variant) in adt_def.variants.iter_enumerated() {
+            let discr_value = match layout.variants {
+                layout::Variants::Tagged { .. } => {
+                    let discr = adt_def.discriminant_for_variant(tcx, index).val;
+                    Some(truncate(discr, discr_layout.size))
+                }
+                layout::Variants::NicheFilling {
+                    dataful_variant,
+                    ref niche_variants,
+                    niche_start,
+                    ..
+                } => {
+                    if index == dataful_variant {
+                        None
+                    } else if *niche_variants.start() <= index && index <= *niche_variants.end() {
+                        let niche_value = ((index.as_u32() - niche_variants.start().as_u32())
+                            as u128)
+                            .wrapping_add(niche_start);
+                        Some(truncate(niche_value, discr_layout.size))
+                    } else {
+                        // Not representable, so it must be uninhabited
+                        continue;
+                    }
+                }
+                layout::Variants::Single { .. } => unreachable!(),
+            };
+
+            let variant_name = variant.ident.to_string();
+
+            let variant_struct_id = self.dwarf.unit.add(type_id, gimli::DW_TAG_structure_type);
+            let name = self.dwarf.strings.add(&*variant_name);
+            let variant_struct_entry = self.dwarf.unit.get_mut(variant_struct_id);
+            variant_struct_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+            variant_struct_entry.set(
+                gimli::DW_AT_byte_size,
+                AttributeValue::Udata(layout.size.bytes()),
+            );
+            let variant_layout = layout.for_variant(&cx, index);
+            let field_names = variant.fields.iter().map(|field| field.ident.to_string());
+            self.add_members(tcx, variant_struct_id, variant_layout, field_names);
+
+            let variant_id = self.dwarf.unit.add(variant_part_id, gimli::DW_TAG_variant);
+            if let Some(discr_value) = discr_value {
+                let variant_entry = self.dwarf.unit.get_mut(variant_id);
+                variant_entry.set(
+                    gimli::DW_AT_discr_value,
+                    AttributeValue::Udata(discr_value as u64),
+                );
+            }
+
+            let name = self.dwarf.strings.add(&*variant_name);
+            let member_id = self.dwarf.unit.add(variant_id, gimli::DW_TAG_member);
+            let member_entry = self.dwarf.unit.get_mut(member_id);
+            member_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+            member_entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(variant_struct_id));
+            member_entry.set(gimli::DW_AT_data_member_location, AttributeValue::Udata(0));
+        }
+    }
+
+    /// Add a `DW_TAG_variable` for a static defined in the current codegen unit.
+    pub fn define_static(&mut self, tcx: TyCtxt<'a, 'tcx, 'tcx>, def_id: DefId, symbol_name: &str) {
+        let (symbol, _) = self.symbols.insert_full(symbol_name.to_string());
+
+        let type_id = self.dwarf_ty(tcx, tcx.type_of(def_id));
+
+        let root = self.dwarf.unit.root();
+        let var_id = self.dwarf.unit.add(root, gimli::DW_TAG_variable);
+        let name = self.dwarf.strings.add(&*tcx.item_name(def_id).as_str());
+        let linkage_name = self.dwarf.strings.add(symbol_name);
+
+        let mut location = Expression::new();
+        location.op_addr(Address::Relative { symbol, addend: 0 });
+
+        let var_entry = self.dwarf.unit.get_mut(var_id);
+        var_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        var_entry.set(gimli::DW_AT_linkage_name, AttributeValue::StringRef(linkage_name));
+        var_entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(type_id));
+        var_entry.set(gimli::DW_AT_external, AttributeValue::FlagPresent);
+        var_entry.set(gimli::DW_AT_location, AttributeValue::Exprloc(location));
+
+        self.emit_location(tcx, var_id, tcx.def_span(def_id));
     }
+}
 
+impl DebugUnit {
     pub fn emit(&mut self, artifact: &mut Artifact) {
         let unit_range_list_id = self.dwarf.unit.ranges.add(self.unit_range_list.clone());
         let root = self.dwarf.unit.root();
@@ -176,38 +586,16 @@ impl<'a, 'tcx: 'a> DebugContext<'tcx> {
             AttributeValue::RangeListRef(unit_range_list_id),
         );
 
-        let mut sections = Sections::new(WriterRelocate::new(self));
+        let mut sections = Sections::new(WriterRelocate::new(self.endian));
         self.dwarf.write(&mut sections).unwrap();
 
         let _: Result<()> = sections.for_each_mut(|id, section| {
-            if !section.writer.slice().is_empty() {
-                artifact
-                    .declare_with(
-                        id.name(),
-                        Decl::DebugSection,
-                        section.writer.take(),
-                    )
-                    .unwrap();
-            }
+            declare_section(artifact, id.name(), section);
             Ok(())
         });
 
         let _: Result<()> = sections.for_each(|id, section| {
-            for reloc in &section.relocs {
-                artifact
-                    .link_with(
-                        faerie::Link {
-                            from: id.name(),
-                            to: reloc.name(self),
-                            at: u64::from(reloc.offset),
-                        },
-                        faerie::Reloc::Debug {
-                            size: reloc.size,
-                            addend: reloc.addend as i32,
-                        },
-                    )
-                    .expect("faerie relocation error");
-            }
+            link_section(artifact, &self.symbols, id.name(), section);
             Ok(())
         });
     }
@@ -225,17 +613,24 @@ impl<'a, 'b, 'tcx: 'b> FunctionDebugContext<'a, 'tcx> {
         tcx: TyCtxt<'b, 'tcx, 'tcx>,
         debug_context: &'a mut DebugContext<'tcx>,
         mir: &Mir,
+        instance: Instance<'tcx>,
         name: &str,
         _sig: &Signature,
     ) -> Self {
         let (symbol, _) = debug_context.symbols.insert_full(name.to_string());
 
-        // FIXME: add to appropriate scope intead of root
-        let scope = debug_context.dwarf.unit.root();
+        let def_id = instance.def_id();
+        let scope = match tcx.parent(def_id) {
+            Some(parent) => debug_context.item_namespace(tcx, parent),
+            None => debug_context.dwarf.unit.root(),
+        };
+        let item_name = tcx.def_key(def_id).disambiguated_data.data.as_interned_str();
 
         let entry_id = debug_context.dwarf.unit.add(scope, gimli::DW_TAG_subprogram);
-        let entry = debug_context.dwarf.unit.get_mut(entry_id);
+        let item_name_id = debug_context.dwarf.strings.add(&*item_name.as_str());
         let name_id = debug_context.dwarf.strings.add(name);
+        let entry = debug_context.dwarf.unit.get_mut(entry_id);
+        entry.set(gimli::DW_AT_name, AttributeValue::StringRef(item_name_id));
         entry.set(
             gimli::DW_AT_linkage_name,
             AttributeValue::StringRef(name_id),
@@ -246,6 +641,12 @@ impl<'a, 'b, 'tcx: 'b> FunctionDebugContext<'a, 'tcx> {
             AttributeValue::Address(Address::Relative { symbol, addend: 0 }),
         );
 
+        // Stack slot offsets are relative to the CFA, so use it as frame base for the locations
+        // of local variables.
+        let mut frame_base = Expression::new();
+        frame_base.op(gimli::DW_OP_call_frame_cfa);
+        entry.set(gimli::DW_AT_frame_base, AttributeValue::Exprloc(frame_base));
+
         debug_context.emit_location(tcx, entry_id, mir.span);
 
         FunctionDebugContext {
@@ -256,14 +657,112 @@ impl<'a, 'b, 'tcx: 'b> FunctionDebugContext<'a, 'tcx> {
         }
     }
 
+    /// Collect everything needed from the `TyCtxt` to define the debuginfo of the function once
+    /// it is compiled. Must be called before `func` is optimized.
+    pub fn prepare(
+        self,
+        tcx: TyCtxt<'b, 'tcx, 'tcx>,
+        func: &Function,
+        source_info_set: &indexmap::IndexSet<SourceInfo>,
+        mir: &Mir<'tcx>,
+        local_map: &HashMap<Local, CPlace<'tcx>>,
+    ) -> FunctionDebugInfo {
+        let debug_context = self.debug_context;
+
+        let source_infos = source_info_set
+            .iter()
+            .map(|source_info| {
+                (debug_context.source_location(tcx, source_info.span), source_info.scope)
+            })
+            .collect();
+        let fn_location = debug_context.source_location(tcx, self.mir_span);
+
+        let mut locals = Vec::new();
+        for (local, local_decl) in mir.local_decls.iter_enumerated() {
+            let name = match local_decl.name {
+                Some(name) => name,
+                None => continue,
+            };
+            let place = match local_map.get(&local) {
+                Some(&place) => place,
+                None => continue,
+            };
+
+            let tag = if local.index() >= 1 && local.index() <= mir.arg_count {
+                gimli::DW_TAG_formal_parameter
+            } else {
+                gimli::DW_TAG_variable
+            };
+
+            let place_loc = match place {
+                CPlace::Var(_, _) => LocalPlace::Var(mir_value_label(local)),
+                _ => match stack_slot_of_place(func, place) {
+                    Some((stack_slot, offset)) => LocalPlace::Stack(stack_slot, offset),
+                    None => LocalPlace::Unknown,
+                },
+            };
+
+            locals.push(LocalDebugInfo {
+                tag,
+                name: debug_context.dwarf.strings.add(&*name.as_str()),
+                type_id: debug_context.dwarf_ty(tcx, place.layout().ty),
+                scope: local_decl.visibility_scope,
+                decl_location: debug_context.source_location(tcx, local_decl.source_info.span),
+                place: place_loc,
+            });
+        }
+
+        FunctionDebugInfo {
+            entry_id: self.entry_id,
+            symbol: self.symbol,
+            source_infos,
+            fn_location,
+            scope_parents: mir.source_scopes.iter().map(|scope_data| scope_data.parent_scope).collect(),
+            locals,
+        }
+    }
+}
+
+/// Where the value of a local is stored, see `LocalDebugInfo`.
+enum LocalPlace {
+    /// A SSA local, whose location is tracked by Cranelift using the given value label
+    Var(ValueLabel),
+    /// A local in the given stack slot at the given offset
+    Stack(StackSlot, i64),
+    Unknown,
+}
+
+struct LocalDebugInfo {
+    tag: gimli::DwTag,
+    name: StringId,
+    type_id: UnitEntryId,
+    scope: SourceScope,
+    decl_location: SourceLocation,
+    place: LocalPlace,
+}
+
+/// The debuginfo of a function collected by `FunctionDebugContext::prepare`. It doesn't reference
+/// the `TyCtxt`, so the function can be compiled and its debuginfo defined on another thread.
+pub struct FunctionDebugInfo {
+    entry_id: UnitEntryId,
+    symbol: usize,
+    /// The location and scope of every entry of the `source_info_set` of the `FunctionCx`, which
+    /// are referred to by the source locations of the clif instructions
+    source_infos: Vec<(SourceLocation, SourceScope)>,
+    /// Used for instructions without source location
+    fn_location: SourceLocation,
+    scope_parents: IndexVec<SourceScope, Option<SourceScope>>,
+    locals: Vec<LocalDebugInfo>,
+}
+
+impl FunctionDebugInfo {
     pub fn define(
-        &mut self,
-        tcx: TyCtxt,
+        self,
+        debug_unit: &mut DebugUnit,
         context: &Context,
         isa: &cranelift::codegen::isa::TargetIsa,
-        source_info_set: &indexmap::IndexSet<SourceInfo>,
     ) {
-        let line_program = &mut self.debug_context.dwarf.unit.line_program;
+        let line_program = &mut debug_unit.dwarf.unit.line_program;
 
         line_program.begin_sequence(Some(Address::Relative {
             symbol: self.symbol,
@@ -275,35 +774,26 @@ impl<'a, 'b, 'tcx: 'b> FunctionDebugContext<'a, 'tcx> {
         let mut ebbs = func.layout.ebbs().collect::<Vec<_>>();
         ebbs.sort_by_key(|ebb| func.offsets[*ebb]); // Ensure inst offsets always increase
 
-        let line_strings = &mut self.debug_context.dwarf.line_strings;
-        let mut create_row_for_span = |line_program: &mut LineProgram, span: Span| {
-            let loc = tcx.sess.source_map().lookup_char_pos(span.lo());
-            let file_id = line_program_add_file(line_program, line_strings, &loc.file.name);
-
-            /*println!(
-                "srcloc {:>04X} {}:{}:{}",
-                line_program.row().address_offset,
-                file.display(),
-                loc.line,
-                loc.col.to_u32()
-            );*/
-
-            line_program.row().file = file_id;
-            line_program.row().line = loc.line as u64;
-            line_program.row().column = loc.col.to_u32() as u64 + 1;
+        let create_row_for_location = |line_program: &mut LineProgram, loc: SourceLocation| {
+            line_program.row().file = loc.file;
+            line_program.row().line = loc.line;
+            line_program.row().column = loc.col + 1;
             line_program.generate_row();
         };
 
         let mut end = 0;
+        let mut inst_scopes = Vec::new();
         for ebb in ebbs {
             for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
                 let srcloc = func.srclocs[inst];
                 line_program.row().address_offset = offset as u64;
                 if !srcloc.is_default() {
-                    let source_info = *source_info_set.get_index(srcloc.bits() as usize).unwrap();
-                    create_row_for_span(line_program, source_info.span);
+                    let (loc, scope) = self.source_infos[srcloc.bits() as usize];
+                    create_row_for_location(line_program, loc);
+                    inst_scopes.push((offset, offset + size, scope));
                 } else {
-                    create_row_for_span(line_program, self.mir_span);
+                    create_row_for_location(line_program, self.fn_location);
+                    inst_scopes.push((offset, offset + size, OUTERMOST_SOURCE_SCOPE));
                 }
                 end = offset + size;
             }
@@ -311,30 +801,221 @@ impl<'a, 'b, 'tcx: 'b> FunctionDebugContext<'a, 'tcx> {
 
         line_program.end_sequence(end as u64);
 
-        let entry = self.debug_context.dwarf.unit.get_mut(self.entry_id);
-        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(end as u64));
+        // DWARF < 4 doesn't allow `DW_AT_high_pc` to be an offset from `DW_AT_low_pc`
+        let high_pc = if debug_unit.dwarf.unit.encoding().version >= 4 {
+            AttributeValue::Udata(end as u64)
+        } else {
+            AttributeValue::Address(Address::Relative {
+                symbol: self.symbol,
+                addend: end as i64,
+            })
+        };
+        let entry = debug_unit.dwarf.unit.get_mut(self.entry_id);
+        entry.set(gimli::DW_AT_high_pc, high_pc);
 
-        self.debug_context.unit_range_list.0.push(Range::StartLength {
+        debug_unit.unit_range_list.0.push(Range::StartLength {
             begin: Address::Relative {
                 symbol: self.symbol,
                 addend: 0,
             },
             length: end as u64,
         });
+
+        let scope_entries = self.define_scopes(debug_unit, &inst_scopes);
+        let value_labels_ranges = context.build_value_labels_ranges(isa).unwrap();
+        self.define_locals(debug_unit, &context.func, &scope_entries, &value_labels_ranges);
+    }
+
+    /// Add a `DW_TAG_lexical_block` for every MIR source scope containing code. Returns the DIE of
+    /// every scope, which is the DIE of the nearest ancestor for scopes without code.
+    ///
+    /// FIXME emit `DW_TAG_inlined_subroutine` for scopes of functions inlined by the MIR inliner.
+    /// This requires the inliner to record the callee of the scopes it creates.
+    fn define_scopes(
+        &self,
+        debug_unit: &mut DebugUnit,
+        inst_scopes: &[(u32, u32, SourceScope)],
+    ) -> HashMap<SourceScope, UnitEntryId> {
+        // An instruction belongs to its own scope and all ancestors of it
+        let mut scope_ranges: HashMap<SourceScope, Vec<(u32, u32)>> = HashMap::new();
+        for &(start, end, scope) in inst_scopes {
+            let mut scope = Some(scope);
+            while let Some(current) = scope {
+                let ranges = scope_ranges.entry(current).or_default();
+                match ranges.last_mut() {
+                    Some(last) if last.1 == start => last.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+                scope = self.scope_parents[current];
+            }
+        }
+
+        let mut scope_entries = HashMap::new();
+
+        // Parent scopes always come before their children
+        for (scope, &parent) in self.scope_parents.iter_enumerated() {
+            let parent = match parent {
+                Some(parent) => parent,
+                None => {
+                    scope_entries.insert(scope, self.entry_id);
+                    continue;
+                }
+            };
+
+            let parent_entry = scope_entries[&parent];
+            let ranges = match scope_ranges.remove(&scope) {
+                Some(ranges) => ranges,
+                None => {
+                    scope_entries.insert(scope, parent_entry);
+                    continue;
+                }
+            };
+
+            let range_list = RangeList(
+                ranges
+                    .into_iter()
+                    .map(|(start, end)| Range::StartEnd {
+                        begin: Address::Relative {
+                            symbol: self.symbol,
+                            addend: start as i64,
+                        },
+                        end: Address::Relative {
+                            symbol: self.symbol,
+                            addend: end as i64,
+                        },
+                    })
+                    .collect(),
+            );
+            let range_list_id = debug_unit.dwarf.unit.ranges.add(range_list);
+
+            let entry_id = debug_unit
+                .dwarf
+                .unit
+                .add(parent_entry, gimli::DW_TAG_lexical_block);
+            let entry = debug_unit.dwarf.unit.get_mut(entry_id);
+            entry.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(range_list_id));
+
+            scope_entries.insert(scope, entry_id);
+        }
+
+        scope_entries
+    }
+
+    /// Add a `DW_TAG_formal_parameter` or `DW_TAG_variable` for every named argument or user
+    /// variable to the DIE of its scope. Locals in stack slots get a single location. SSA locals
+    /// get a location list built from the value label ranges computed by Cranelift.
+    fn define_locals(
+        &self,
+        debug_unit: &mut DebugUnit,
+        func: &Function,
+        scope_entries: &HashMap<SourceScope, UnitEntryId>,
+        value_labels_ranges: &ValueLabelsRanges,
+    ) {
+        for local in &self.locals {
+            let scope_entry = scope_entries[&local.scope];
+            let var_id = debug_unit.dwarf.unit.add(scope_entry, local.tag);
+            let var_entry = debug_unit.dwarf.unit.get_mut(var_id);
+            var_entry.set(gimli::DW_AT_name, AttributeValue::StringRef(local.name));
+            var_entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(local.type_id));
+
+            match local.place {
+                LocalPlace::Var(value_label) => {
+                    let ranges = value_labels_ranges
+                        .get(&value_label)
+                        .map(|ranges| &ranges[..])
+                        .unwrap_or(&[]);
+                    let location_list = LocationList(
+                        ranges
+                            .iter()
+                            .filter(|range| range.start < range.end)
+                            .filter_map(|range| {
+                                Some(Location::StartEnd {
+                                    begin: Address::Relative {
+                                        symbol: self.symbol,
+                                        addend: i64::from(range.start),
+                                    },
+                                    end: Address::Relative {
+                                        symbol: self.symbol,
+                                        addend: i64::from(range.end),
+                                    },
+                                    data: value_loc_expr(func, range.loc)?,
+                                })
+                            })
+                            .collect(),
+                    );
+                    if !location_list.0.is_empty() {
+                        let location_list_id = debug_unit.dwarf.unit.locations.add(location_list);
+                        let var_entry = debug_unit.dwarf.unit.get_mut(var_id);
+                        var_entry.set(
+                            gimli::DW_AT_location,
+                            AttributeValue::LocationListRef(location_list_id),
+                        );
+                    }
+                }
+                LocalPlace::Stack(stack_slot, offset) => {
+                    if let Some(slot_offset) = func.stack_slots[stack_slot].offset {
+                        let mut location = Expression::new();
+                        location.op_fbreg(i64::from(slot_offset) + offset);
+                        var_entry.set(gimli::DW_AT_location, AttributeValue::Exprloc(location));
+                    }
+                }
+                LocalPlace::Unknown => {}
+            }
+
+            set_decl_location(&mut debug_unit.dwarf, var_id, local.decl_location);
+        }
+    }
+}
+
+/// The location of a value assigned by the register allocator.
+fn value_loc_expr(func: &Function, loc: ValueLoc) -> Option<Expression> {
+    let mut expr = Expression::new();
+    match loc {
+        ValueLoc::Reg(reg) => expr.op_reg(crate::unwind::dwarf_reg(reg)),
+        ValueLoc::Stack(stack_slot) => expr.op_fbreg(i64::from(func.stack_slots[stack_slot].offset?)),
+        ValueLoc::Unassigned => return None,
     }
+    Some(expr)
+}
+
+/// The stack slot containing `place` and the offset of `place` in it, if any.
+///
+/// Stack locals are stored in `FunctionCx::local_map` as the address returned by a `stack_addr`
+/// instruction, so the stack slot is found through the definition of the address. This has to
+/// be done before the function is optimized, as the `stack_addr` may be removed.
+fn stack_slot_of_place(func: &Function, place: CPlace) -> Option<(StackSlot, i64)> {
+    let (stack_slot, offset) = match place {
+        CPlace::Stack(stack_slot, _) => (stack_slot, 0),
+        CPlace::Addr(addr, None, _) => {
+            let inst = match func.dfg.value_def(func.dfg.resolve_aliases(addr)) {
+                ValueDef::Result(inst, 0) => inst,
+                _ => return None,
+            };
+            match func.dfg[inst] {
+                InstructionData::StackLoad {
+                    opcode: Opcode::StackAddr,
+                    stack_slot,
+                    offset,
+                } => (stack_slot, i64::from(offset)),
+                _ => return None,
+            }
+        }
+        CPlace::Addr(_, Some(_), _) | CPlace::Var(_, _) | CPlace::NoPlace(_) => return None,
+    };
+    Some((stack_slot, offset))
 }
 
 #[derive(Clone)]
-struct WriterRelocate {
+pub struct WriterRelocate {
     relocs: Vec<DebugReloc>,
     writer: EndianVec<RunTimeEndian>,
 }
 
 impl WriterRelocate {
-    fn new(ctx: & DebugContext) -> Self {
+    pub fn new(endian: RunTimeEndian) -> Self {
         WriterRelocate {
             relocs: Vec::new(),
-            writer: EndianVec::new(ctx.endian),
+            writer: EndianVec::new(endian),
         }
     }
 }
@@ -368,6 +1049,7 @@ impl Writer for WriterRelocate {
                     size,
                     name: DebugRelocName::Symbol(symbol),
                     addend: addend as i64,
+                    pc_relative: false,
                 });
                 self.write_word(0, size)
             }
@@ -381,6 +1063,7 @@ impl Writer for WriterRelocate {
             size,
             name: DebugRelocName::Section(section),
             addend: val as i64,
+            pc_relative: false,
         });
         self.write_word(0, size)
     }
@@ -397,7 +1080,34 @@ impl Writer for WriterRelocate {
             size,
             name: DebugRelocName::Section(section),
             addend: val as i64,
+            pc_relative: false,
         });
         self.write_word_at(offset, 0, size)
     }
+
+    fn write_eh_pointer(
+        &mut self,
+        address: Address,
+        eh_pe: gimli::DwEhPe,
+        size: u8,
+    ) -> Result<()> {
+        match address {
+            Address::Relative { symbol, addend }
+                if eh_pe.application() == gimli::DW_EH_PE_pcrel
+                    && eh_pe.format() == gimli::DW_EH_PE_sdata4 =>
+            {
+                let offset = self.len() as u64;
+                self.relocs.push(DebugReloc {
+                    offset: offset as u32,
+                    size: 4,
+                    name: DebugRelocName::Symbol(symbol),
+                    addend: addend as i64,
+                    pc_relative: true,
+                });
+                self.write_udata(0, 4)
+            }
+            _ if eh_pe == gimli::DW_EH_PE_absptr => self.write_address(address, size),
+            _ => Err(gimli::write::Error::UnsupportedPointerEncoding(eh_pe)),
+        }
+    }
 }
@@ -0,0 +1,383 @@
+//! Inline and global assembly.
+//!
+//! Cranelift can't compile assembly, so every `asm!` block is outlined into a wrapper function
+//! written in assembly, which is called like a normal function. All `global_asm!` items and
+//! wrapper functions of a cgu are assembled by the C compiler of the target into an extra object
+//! file.
+//!
+//! A wrapper function gets a pointer to a buffer in `rdi`, which contains an eightbyte for every
+//! input followed by an eightbyte for every output. It loads the inputs into the registers chosen
+//! for them, runs the assembly and stores the outputs back into the buffer. Only register
+//! operands are supported.
+
+use std::fmt::Write;
+
+use rustc::hir;
+use rustc::session::config::OutputType;
+use rustc_codegen_ssa::back::link::{get_linker, linker_and_flavor};
+use syntax::ast::{AsmDialect, NodeId};
+
+use crate::prelude::*;
+
+/// 64, 32, 16 and 8 bit names of the registers usable for operands. `rsp` is excluded and `rbp`
+/// holds the buffer pointer.
+const REGS: [[&str; 4]; 14] = [
+    ["rax", "eax", "ax", "al"],
+    ["rcx", "ecx", "cx", "cl"],
+    ["rdx", "edx", "dx", "dl"],
+    ["rsi", "esi", "si", "sil"],
+    ["rdi", "edi", "di", "dil"],
+    ["r8", "r8d", "r8w", "r8b"],
+    ["r9", "r9d", "r9w", "r9b"],
+    ["r10", "r10d", "r10w", "r10b"],
+    ["r11", "r11d", "r11w", "r11b"],
+    ["rbx", "ebx", "bx", "bl"],
+    ["r12", "r12d", "r12w", "r12b"],
+    ["r13", "r13d", "r13w", "r13b"],
+    ["r14", "r14d", "r14w", "r14b"],
+    ["r15", "r15d", "r15w", "r15b"],
+];
+
+/// Callee saved registers, which the wrapper saves in case the assembly clobbers them
+const CALLEE_SAVED: [&str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
+
+/// Offset of the cfa from `%rsp` in the body of the wrapper functions: the return address, `%rbp`,
+/// the callee saved registers and 8 bytes of padding
+const FRAME_SIZE: usize = 8 + 8 + 5 * 8 + 8;
+
+fn reg_index(name: &str) -> Option<usize> {
+    REGS.iter().position(|names| names.contains(&name))
+}
+
+fn reg_name(reg: usize, size: Size) -> &'static str {
+    match size.bytes() {
+        8 => REGS[reg][0],
+        4 => REGS[reg][1],
+        2 => REGS[reg][2],
+        1 => REGS[reg][3],
+        _ => unimpl!("Inline asm operand of size {}", size.bytes()),
+    }
+}
+
+/// Quote `name` for use as a symbol in assembly. Mangled symbol names may contain characters like
+/// `$`, which are only allowed in quoted symbols.
+pub fn quoted_symbol(name: &str) -> String {
+    format!("\"{}\"", name)
+}
+
+pub fn check_target(tcx: TyCtxt) {
+    let target = &tcx.sess.target.target;
+    if target.arch != "x86_64" || target.options.is_like_osx || target.options.is_like_windows {
+        unimpl!("Assembly is only supported on x86_64 ELF targets");
+    }
+}
+
+pub fn codegen_global_asm(tcx: TyCtxt, global_asm: &mut String, node_id: NodeId) {
+    check_target(tcx);
+    let item = tcx.hir().expect_item(node_id);
+    if let hir::ItemKind::GlobalAsm(ref ga) = item.node {
+        global_asm.push_str(&ga.asm.as_str());
+        global_asm.push('\n');
+    } else {
+        bug!("Expected GlobalAsm found {:?}", item);
+    }
+}
+
+enum Constraint {
+    /// Any register
+    Reg,
+    /// A specific register
+    ExplicitReg(usize),
+    /// The same register as the output with the given index
+    Tied(usize),
+}
+
+fn parse_constraint(constraint: &str) -> Constraint {
+    let constraint = constraint.trim_start_matches(|c| c == '=' || c == '&');
+    if constraint == "r" {
+        Constraint::Reg
+    } else if constraint.starts_with('{') && constraint.ends_with('}') {
+        let name = &constraint[1..constraint.len() - 1];
+        match reg_index(name) {
+            Some(reg) => Constraint::ExplicitReg(reg),
+            None => unimpl!("Unsupported register {} in inline asm", name),
+        }
+    } else if let Ok(idx) = constraint.parse::<usize>() {
+        Constraint::Tied(idx)
+    } else {
+        unimpl!("Unsupported inline asm constraint {:?}", constraint);
+    }
+}
+
+pub fn codegen_inline_asm<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    asm: &hir::InlineAsm,
+    outputs: &[Place<'tcx>],
+    inputs: &[(Span, Operand<'tcx>)],
+) {
+    check_target(fx.tcx);
+
+    let mut used_regs = FxHashSet::default();
+    for clobber in &asm.clobbers {
+        match &*clobber.as_str() {
+            "memory" | "cc" | "flags" | "dirflag" | "fpsr" => {}
+            name => match reg_index(name) {
+                Some(reg) => {
+                    used_regs.insert(reg);
+                }
+                None => unimpl!("Unsupported clobber {} in inline asm", name),
+            },
+        }
+    }
+
+    let output_places = outputs.iter().map(|place| trans_place(fx, place)).collect::<Vec<_>>();
+    let output_constraints = asm
+        .outputs
+        .iter()
+        .map(|output| {
+            if output.is_indirect {
+                unimpl!("Indirect inline asm outputs are not supported");
+            }
+            parse_constraint(&output.constraint.as_str())
+        })
+        .collect::<Vec<_>>();
+
+    // Read-write outputs are also inputs
+    let mut input_values = inputs
+        .iter()
+        .map(|(_span, operand)| trans_operand(fx, operand))
+        .collect::<Vec<_>>();
+    let mut input_constraints = asm
+        .inputs
+        .iter()
+        .map(|constraint| parse_constraint(&constraint.as_str()))
+        .collect::<Vec<_>>();
+    for (i, output) in asm.outputs.iter().enumerate() {
+        if output.is_rw {
+            input_values.push(output_places[i].to_cvalue(fx));
+            input_constraints.push(Constraint::Tied(i));
+        }
+    }
+
+    // Assign registers. Explicit registers are reserved first.
+    for constraint in output_constraints.iter().chain(input_constraints.iter()) {
+        if let Constraint::ExplicitReg(reg) = *constraint {
+            used_regs.insert(reg);
+        }
+    }
+    let mut alloc_reg = || {
+        let reg = (0..REGS.len())
+            .find(|reg| !used_regs.contains(reg))
+            .unwrap_or_else(|| unimpl!("Ran out of registers for inline asm"));
+        used_regs.insert(reg);
+        reg
+    };
+    let output_regs = output_constraints
+        .into_iter()
+        .map(|constraint| match constraint {
+            Constraint::Reg => alloc_reg(),
+            Constraint::ExplicitReg(reg) => reg,
+            Constraint::Tied(_) => unimpl!("Tied inline asm output"),
+        })
+        .collect::<Vec<_>>();
+    let input_regs = input_constraints
+        .iter()
+        .map(|constraint| match *constraint {
+            Constraint::Reg => alloc_reg(),
+            Constraint::ExplicitReg(reg) => reg,
+            Constraint::Tied(idx) => output_regs[idx],
+        })
+        .collect::<Vec<_>>();
+
+    // Substitute `$N` in the template. Operands are numbered outputs first.
+    let operand_names = output_regs
+        .iter()
+        .zip(output_places.iter())
+        .map(|(&reg, place)| reg_name(reg, place.layout().size))
+        .chain(
+            input_regs
+                .iter()
+                .zip(input_values.iter())
+                .map(|(&reg, value)| reg_name(reg, value.layout().size)),
+        )
+        .collect::<Vec<_>>();
+    let body = substitute_operands(&asm.asm.as_str(), &operand_names, asm.dialect);
+
+    // Generate the wrapper function. `#[inline]` and generic functions are codegened in every cgu
+    // and crate using them, so the name contains the crate disambiguator and cgu name to prevent
+    // duplicate definitions.
+    let name = format!(
+        "__inline_asm_{}_{}_{}_n{}",
+        fx.tcx.crate_disambiguator(LOCAL_CRATE).to_fingerprint().to_hex(),
+        fx.cgu_name,
+        fx.tcx.symbol_name(fx.instance).as_str(),
+        fx.inline_asm_index,
+    );
+    fx.inline_asm_index += 1;
+
+    let symbol = quoted_symbol(&name);
+    let global_asm = &mut *fx.global_asm;
+    writeln!(global_asm, "    .text").unwrap();
+    writeln!(global_asm, "    .globl {}", symbol).unwrap();
+    writeln!(global_asm, "    .hidden {}", symbol).unwrap();
+    writeln!(global_asm, "    .type {},@function", symbol).unwrap();
+    writeln!(global_asm, "{}:", symbol).unwrap();
+    writeln!(global_asm, "    .cfi_startproc").unwrap();
+    // The return address and the saved registers are described using cfi directives, so unwinders
+    // and debuggers can walk through the wrapper. `%rbp` is used as base pointer for the operands,
+    // not as frame pointer, so the cfa stays relative to `%rsp`.
+    writeln!(global_asm, "    push %rbp").unwrap();
+    writeln!(global_asm, "    .cfi_def_cfa_offset 16").unwrap();
+    writeln!(global_asm, "    .cfi_offset %rbp, -16").unwrap();
+    for (i, reg) in CALLEE_SAVED.iter().enumerate() {
+        writeln!(global_asm, "    push %{}", reg).unwrap();
+        writeln!(global_asm, "    .cfi_def_cfa_offset {}", 24 + i * 8).unwrap();
+        writeln!(global_asm, "    .cfi_offset %{}, -{}", reg, 24 + i * 8).unwrap();
+    }
+    // Keep the stack 16 byte aligned for calls made by the asm body. The return address and the
+    // six saved registers leave `%rsp` at 8 mod 16.
+    writeln!(global_asm, "    sub $8, %rsp").unwrap();
+    writeln!(global_asm, "    .cfi_def_cfa_offset {}", FRAME_SIZE).unwrap();
+    writeln!(global_asm, "    mov %rdi, %rbp").unwrap();
+    for (i, &reg) in input_regs.iter().enumerate() {
+        writeln!(global_asm, "    mov {}(%rbp), %{}", i * 8, REGS[reg][0]).unwrap();
+    }
+    if asm.dialect == AsmDialect::Intel {
+        writeln!(global_asm, "    .intel_syntax noprefix").unwrap();
+    }
+    writeln!(global_asm, "    {}", body).unwrap();
+    if asm.dialect == AsmDialect::Intel {
+        writeln!(global_asm, "    .att_syntax").unwrap();
+    }
+    for (i, &reg) in output_regs.iter().enumerate() {
+        writeln!(global_asm, "    mov %{}, {}(%rbp)", REGS[reg][0], (input_regs.len() + i) * 8).unwrap();
+    }
+    writeln!(global_asm, "    add $8, %rsp").unwrap();
+    writeln!(global_asm, "    .cfi_def_cfa_offset {}", FRAME_SIZE - 8).unwrap();
+    for (i, reg) in CALLEE_SAVED.iter().enumerate().rev() {
+        writeln!(global_asm, "    pop %{}", reg).unwrap();
+        writeln!(global_asm, "    .cfi_def_cfa_offset {}", 16 + i * 8).unwrap();
+    }
+    writeln!(global_asm, "    pop %rbp").unwrap();
+    writeln!(global_asm, "    .cfi_def_cfa_offset 8").unwrap();
+    writeln!(global_asm, "    ret").unwrap();
+    writeln!(global_asm, "    .cfi_endproc").unwrap();
+    writeln!(global_asm, "    .size {name}, .-{name}", name = symbol).unwrap();
+    writeln!(global_asm).unwrap();
+
+    // Call the wrapper function
+    let buffer = fx.bcx.create_stack_slot(StackSlotData {
+        kind: StackSlotKind::ExplicitSlot,
+        size: std::cmp::max((input_regs.len() + output_regs.len()) as u32 * 8, 8),
+        offset: None,
+    });
+    let buffer_addr = fx.bcx.ins().stack_addr(fx.pointer_type, buffer, 0);
+
+    for (i, value) in input_values.into_iter().enumerate() {
+        let value = value.load_scalar(fx);
+        let value = match fx.bcx.func.dfg.value_type(value) {
+            types::I64 => value,
+            ty if ty.is_int() => fx.bcx.ins().uextend(types::I64, value),
+            ty => unimpl!("Inline asm input of type {}", ty),
+        };
+        fx.bcx.ins().store(MemFlags::new(), value, buffer_addr, i as i32 * 8);
+    }
+
+    fx.lib_call(&name, vec![fx.pointer_type], None, &[buffer_addr]);
+
+    for (i, place) in output_places.into_iter().enumerate() {
+        let clif_ty = fx.clif_type(place.layout().ty).unwrap_or_else(|| {
+            unimpl!("Inline asm output of type {:?}", place.layout().ty)
+        });
+        let offset = (input_regs.len() + i) as i32 * 8;
+        let value = fx.bcx.ins().load(clif_ty, MemFlags::new(), buffer_addr, offset);
+        place.write_cvalue(fx, CValue::ByVal(value, place.layout()));
+    }
+}
+
+fn substitute_operands(template: &str, operand_names: &[&str], dialect: AsmDialect) -> String {
+    let prefix = match dialect {
+        AsmDialect::Att => "%",
+        AsmDialect::Intel => "",
+    };
+
+    let mut res = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            res.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('$') => {
+                chars.next();
+                res.push('$');
+            }
+            Some('{') => {
+                chars.next();
+                let operand = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                match operand.parse::<usize>() {
+                    Ok(idx) if idx < operand_names.len() => {
+                        res.push_str(prefix);
+                        res.push_str(operand_names[idx]);
+                    }
+                    _ => unimpl!("Unsupported inline asm operand ${{{}}}", operand),
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut idx = 0;
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    chars.next();
+                    idx = idx * 10 + digit as usize;
+                }
+                if idx >= operand_names.len() {
+                    unimpl!("Inline asm operand ${} doesn't exist", idx);
+                }
+                res.push_str(prefix);
+                res.push_str(operand_names[idx]);
+            }
+            _ => res.push('$'),
+        }
+    }
+    res
+}
+
+/// Assemble `global_asm` using the C compiler of the target
+pub fn assemble<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    name: &str,
+    global_asm: &str,
+) -> CompiledModule {
+    let output_filenames = tcx.output_filenames(LOCAL_CRATE);
+    let asm_file = output_filenames.temp_path_ext("s", Some(name));
+    let obj_file = output_filenames.temp_path(OutputType::Object, Some(name));
+    std::fs::write(&asm_file, global_asm).unwrap();
+
+    // Assemble using the C compiler used as linker, which gets the target specific flags like
+    // `-m64` from `pre_link_args` and knows where to find the assembler.
+    let (linker, flavor) = linker_and_flavor(tcx.sess);
+    let (_, mut cmd) = get_linker(tcx.sess, &linker, flavor);
+    if let Some(args) = tcx.sess.target.target.options.pre_link_args.get(&flavor) {
+        cmd.args(args);
+    }
+    cmd.arg("-c").arg("-o").arg(&obj_file).arg(&asm_file);
+    match cmd.output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => tcx.sess.fatal(&format!(
+            "Failed to assemble `{}`: {} exited with {}\n{}",
+            asm_file.display(),
+            linker.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr),
+        )),
+        Err(err) => tcx.sess.fatal(&format!("Failed to run {}: {}", linker.display(), err)),
+    }
+
+    CompiledModule {
+        name: name.to_string(),
+        kind: ModuleKind::Regular,
+        object: Some(obj_file),
+        bytecode: None,
+        bytecode_compressed: None,
+    }
+}
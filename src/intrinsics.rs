@@ -57,38 +57,16 @@ macro_rules! intrinsic_match {
     };
 }
 
-macro_rules! atomic_binop_return_old {
-    ($fx:expr, $op:ident<$T:ident>($ptr:ident, $src:ident) -> $ret:ident) => {
-        let clif_ty = $fx.clif_type($T).unwrap();
-        let old = $fx.bcx.ins().load(clif_ty, MemFlags::new(), $ptr, 0);
-        let new = $fx.bcx.ins().band(old, $src);
-        $fx.bcx.ins().store(MemFlags::new(), new, $ptr, 0);
-        $ret.write_cvalue($fx, CValue::ByVal(old, $fx.layout_of($T)));
-    };
-}
-
-macro_rules! atomic_minmax {
-    ($fx:expr, $cc:expr, <$T:ident> ($ptr:ident, $src:ident) -> $ret:ident) => {
-        // Read old
-        let clif_ty = $fx.clif_type($T).unwrap();
-        let old = $fx.bcx.ins().load(clif_ty, MemFlags::new(), $ptr, 0);
-
-        // Compare
-        let is_eq = $fx.bcx.ins().icmp(IntCC::SignedGreaterThan, old, $src);
-        let new = crate::common::codegen_select(&mut $fx.bcx, is_eq, old, $src);
-
-        // Write new
-        $fx.bcx.ins().store(MemFlags::new(), new, $ptr, 0);
+mod atomic;
+mod simd;
 
-        let ret_val = CValue::ByVal(old, $ret.layout());
-        $ret.write_cvalue($fx, ret_val);
-    };
-}
+pub use self::atomic::needs_libatomic;
 
 pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
     fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
     def_id: DefId,
     substs: &'tcx Substs,
+    mir_args: &[Operand<'tcx>],
     args: Vec<CValue<'tcx>>,
     destination: Option<(CPlace<'tcx>, BasicBlock)>,
 ) {
@@ -112,6 +90,20 @@ pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
         }
     };
 
+    if intrinsic.starts_with("atomic_") {
+        self::atomic::codegen_atomic_intrinsic_call(fx, intrinsic, substs, args, ret);
+        let ret_ebb = fx.get_ebb(destination.expect("atomic intrinsics don't diverge").1);
+        fx.bcx.ins().jump(ret_ebb, &[]);
+        return;
+    }
+
+    if intrinsic.starts_with("simd_") {
+        self::simd::codegen_simd_intrinsic_call(fx, intrinsic, substs, mir_args, args, ret);
+        let ret_ebb = fx.get_ebb(destination.expect("simd intrinsics don't diverge").1);
+        fx.bcx.ins().jump(ret_ebb, &[]);
+        return;
+    }
+
     let u64_layout = fx.layout_of(fx.tcx.types.u64);
     let usize_layout = fx.layout_of(fx.tcx.types.usize);
 
@@ -148,7 +140,7 @@ pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
         };
         size_of, <T> () {
             let size_of = fx.layout_of(T).size.bytes();
-            let size_of = CValue::const_val(fx, usize_layout.ty, size_of as i64);
+            let size_of = CValue::const_val(fx, usize_layout.ty, size_of as i128);
             ret.write_cvalue(fx, size_of);
         };
         size_of_val, <T> (c ptr) {
@@ -167,7 +159,7 @@ pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
         };
         min_align_of, <T> () {
             let min_align = fx.layout_of(T).align.abi.bytes();
-            let min_align = CValue::const_val(fx, usize_layout.ty, min_align as i64);
+            let min_align = CValue::const_val(fx, usize_layout.ty, min_align as i128);
             ret.write_cvalue(fx, min_align);
         };
         min_align_of_val, <T> (c ptr) {
@@ -186,7 +178,7 @@ pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
         };
         type_id, <T> () {
             let type_id = fx.tcx.type_id_hash(T);
-            let type_id = CValue::const_val(fx, u64_layout.ty, type_id as i64);
+            let type_id = CValue::const_val(fx, u64_layout.ty, type_id as i128);
             ret.write_cvalue(fx, type_id);
         };
         _ if intrinsic.starts_with("unchecked_") || intrinsic == "exact_div", (c x, c y) {
@@ -328,19 +320,61 @@ pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
             ret.write_cvalue(fx, uninit_val);
         };
         ctlz | ctlz_nonzero, <T> (v arg) {
-            let res = CValue::ByVal(fx.bcx.ins().clz(arg), fx.layout_of(T));
+            let res = if T == fx.tcx.types.u128 || T == fx.tcx.types.i128 {
+                // leading zeros of msb, or 64 + leading zeros of lsb when msb is zero
+                let (lsb, msb) = fx.bcx.ins().isplit(arg);
+                let lsb_lz = fx.bcx.ins().clz(lsb);
+                let msb_lz = fx.bcx.ins().clz(msb);
+                let msb_is_zero = fx.bcx.ins().icmp_imm(IntCC::Equal, msb, 0);
+                let lsb_lz_plus_64 = fx.bcx.ins().iadd_imm(lsb_lz, 64);
+                let res = fx.bcx.ins().select(msb_is_zero, lsb_lz_plus_64, msb_lz);
+                clif_intcast(fx, res, types::I128, false)
+            } else {
+                fx.bcx.ins().clz(arg)
+            };
+            let res = CValue::ByVal(res, fx.layout_of(T));
             ret.write_cvalue(fx, res);
         };
         cttz | cttz_nonzero, <T> (v arg) {
-            let res = CValue::ByVal(fx.bcx.ins().ctz(arg), fx.layout_of(T));
+            let res = if T == fx.tcx.types.u128 || T == fx.tcx.types.i128 {
+                // trailing zeros of lsb, or 64 + trailing zeros of msb when lsb is zero
+                let (lsb, msb) = fx.bcx.ins().isplit(arg);
+                let lsb_tz = fx.bcx.ins().ctz(lsb);
+                let msb_tz = fx.bcx.ins().ctz(msb);
+                let lsb_is_zero = fx.bcx.ins().icmp_imm(IntCC::Equal, lsb, 0);
+                let msb_tz_plus_64 = fx.bcx.ins().iadd_imm(msb_tz, 64);
+                let res = fx.bcx.ins().select(lsb_is_zero, msb_tz_plus_64, lsb_tz);
+                clif_intcast(fx, res, types::I128, false)
+            } else {
+                fx.bcx.ins().ctz(arg)
+            };
+            let res = CValue::ByVal(res, fx.layout_of(T));
             ret.write_cvalue(fx, res);
         };
         ctpop, <T> (v arg) {
-            let res = CValue::ByVal(fx.bcx.ins().popcnt(arg), fx.layout_of(T));
+            let res = if T == fx.tcx.types.u128 || T == fx.tcx.types.i128 {
+                let (lsb, msb) = fx.bcx.ins().isplit(arg);
+                let lsb_popcnt = fx.bcx.ins().popcnt(lsb);
+                let msb_popcnt = fx.bcx.ins().popcnt(msb);
+                let res = fx.bcx.ins().iadd(lsb_popcnt, msb_popcnt);
+                clif_intcast(fx, res, types::I128, false)
+            } else {
+                fx.bcx.ins().popcnt(arg)
+            };
+            let res = CValue::ByVal(res, fx.layout_of(T));
             ret.write_cvalue(fx, res);
         };
         bitreverse, <T> (v arg) {
-            let res = CValue::ByVal(fx.bcx.ins().bitrev(arg), fx.layout_of(T));
+            let res = if T == fx.tcx.types.u128 || T == fx.tcx.types.i128 {
+                // reverse both halves and swap them
+                let (lsb, msb) = fx.bcx.ins().isplit(arg);
+                let lsb_rev = fx.bcx.ins().bitrev(lsb);
+                let msb_rev = fx.bcx.ins().bitrev(msb);
+                fx.bcx.ins().iconcat(msb_rev, lsb_rev)
+            } else {
+                fx.bcx.ins().bitrev(arg)
+            };
+            let res = CValue::ByVal(res, fx.layout_of(T));
             ret.write_cvalue(fx, res);
         };
         needs_drop, <T> () {
@@ -358,75 +392,38 @@ pub fn codegen_intrinsic_call<'a, 'tcx: 'a>(
                 return;
             }
         };
+        _ if intrinsic == "try", (v f, v data, v local_ptr) {
+            // Call `f(data)`. When it unwinds, store the exception pointer in `*local_ptr` and
+            // return 1, otherwise return 0.
+            let sig = fx.bcx.import_signature(Signature {
+                params: vec![AbiParam::new(fx.pointer_type)],
+                returns: vec![],
+                call_conv: CallConv::SystemV,
+            });
+            let call_inst = fx.bcx.ins().call_indirect(sig, f, &[data]);
 
-        _ if intrinsic.starts_with("atomic_fence"), () {};
-        _ if intrinsic.starts_with("atomic_singlethreadfence"), () {};
-        _ if intrinsic.starts_with("atomic_load"), (c ptr) {
-            let inner_layout =
-                fx.layout_of(ptr.layout().ty.builtin_deref(true).unwrap().ty);
-            let val = CValue::ByRef(ptr.load_scalar(fx), inner_layout);
-            ret.write_cvalue(fx, val);
-        };
-        _ if intrinsic.starts_with("atomic_store"), (v ptr, c val) {
-            let dest = CPlace::Addr(ptr, None, val.layout());
-            dest.write_cvalue(fx, val);
-        };
-        _ if intrinsic.starts_with("atomic_xchg"), <T> (v ptr, c src) {
-            // Read old
-            let clif_ty = fx.clif_type(T).unwrap();
-            let old = fx.bcx.ins().load(clif_ty, MemFlags::new(), ptr, 0);
-            ret.write_cvalue(fx, CValue::ByVal(old, fx.layout_of(T)));
-
-            // Write new
-            let dest = CPlace::Addr(ptr, None, src.layout());
-            dest.write_cvalue(fx, src);
-        };
-        _ if intrinsic.starts_with("atomic_cxchg"), <T> (v ptr, v test_old, v new) { // both atomic_cxchg_* and atomic_cxchgweak_*
-            // Read old
-            let clif_ty = fx.clif_type(T).unwrap();
-            let old = fx.bcx.ins().load(clif_ty, MemFlags::new(), ptr, 0);
-
-            // Compare
-            let is_eq = fx.bcx.ins().icmp(IntCC::Equal, old, test_old);
-            let new = crate::common::codegen_select(&mut fx.bcx, is_eq, new, old); // Keep old if not equal to test_old
+            let res = if fx.landing_pads.is_some() {
+                let (landing_pad, continue_ebb) = crate::unwind::add_unwind_edge(fx, call_inst, true);
+                let ret_ebb = fx.bcx.create_ebb();
+                let res = fx.bcx.append_ebb_param(ret_ebb, types::I32);
 
-            // Write new
-            fx.bcx.ins().store(MemFlags::new(), new, ptr, 0);
+                fx.bcx.switch_to_block(landing_pad);
+                let exception = crate::unwind::codegen_get_exception_ptr(fx);
+                fx.bcx.ins().store(MemFlags::new(), exception, local_ptr, 0);
+                let one = fx.bcx.ins().iconst(types::I32, 1);
+                fx.bcx.ins().jump(ret_ebb, &[one]);
 
-            let ret_val = CValue::ByValPair(old, fx.bcx.ins().bint(types::I8, is_eq), ret.layout());
-            ret.write_cvalue(fx, ret_val);
-        };
+                fx.bcx.switch_to_block(continue_ebb);
+                let zero = fx.bcx.ins().iconst(types::I32, 0);
+                fx.bcx.ins().jump(ret_ebb, &[zero]);
 
-        _ if intrinsic.starts_with("atomic_xadd"), <T> (v ptr, v amount) {
-            atomic_binop_return_old! (fx, iadd<T>(ptr, amount) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_xsub"), <T> (v ptr, v amount) {
-            atomic_binop_return_old! (fx, isub<T>(ptr, amount) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_and"), <T> (v ptr, v src) {
-            atomic_binop_return_old! (fx, band<T>(ptr, src) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_nand"), <T> (v ptr, v src) {
-            atomic_binop_return_old! (fx, bnand<T>(ptr, src) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_or"), <T> (v ptr, v src) {
-            atomic_binop_return_old! (fx, bor<T>(ptr, src) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_xor"), <T> (v ptr, v src) {
-            atomic_binop_return_old! (fx, bxor<T>(ptr, src) -> ret);
-        };
-
-        _ if intrinsic.starts_with("atomic_max"), <T> (v ptr, v src) {
-            atomic_minmax!(fx, IntCC::SignedGreaterThan, <T> (ptr, src) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_umax"), <T> (v ptr, v src) {
-            atomic_minmax!(fx, IntCC::UnsignedGreaterThan, <T> (ptr, src) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_min"), <T> (v ptr, v src) {
-            atomic_minmax!(fx, IntCC::SignedLessThan, <T> (ptr, src) -> ret);
-        };
-        _ if intrinsic.starts_with("atomic_umin"), <T> (v ptr, v src) {
-            atomic_minmax!(fx, IntCC::UnsignedLessThan, <T> (ptr, src) -> ret);
+                fx.bcx.switch_to_block(ret_ebb);
+                res
+            } else {
+                // Unwinding is disabled, so `f` can't unwind
+                fx.bcx.ins().iconst(types::I32, 0)
+            };
+            ret.write_cvalue(fx, CValue::ByVal(res, fx.layout_of(fx.tcx.types.i32)));
         };
     }
 
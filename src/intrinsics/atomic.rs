@@ -0,0 +1,197 @@
+//! Codegen of the `atomic_*` intrinsics.
+//!
+//! Cranelift doesn't have atomic instructions yet, so every atomic operation is lowered to a call
+//! to the `__atomic_*` functions of libatomic, which use lock prefixed instructions on x86.
+
+use super::*;
+
+/// Whether `-latomic` needs to be passed when linking, as the atomic intrinsics are lowered to
+/// calls to libatomic. On macOS and Windows the `__atomic_*` functions are provided by the C
+/// runtime.
+pub fn needs_libatomic(sess: &Session) -> bool {
+    !sess.target.target.options.is_like_osx && !sess.target.target.options.is_like_windows
+}
+
+/// The `__ATOMIC_*` memory orders accepted by the libatomic functions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Ordering {
+    Relaxed = 0,
+    Acquire = 2,
+    Release = 3,
+    AcqRel = 4,
+    SeqCst = 5,
+}
+
+/// Parse the success and failure orderings from the suffix of an intrinsic name of the form
+/// `atomic_<op>[_<ordering>][_fail<ordering>]`.
+fn parse_orderings(tcx: TyCtxt, intrinsic: &str) -> (Ordering, Ordering) {
+    let split: Vec<&str> = intrinsic.split('_').collect();
+    let is_cxchg = split[1] == "cxchg" || split[1] == "cxchgweak";
+
+    match split.len() {
+        2 => (Ordering::SeqCst, Ordering::SeqCst),
+        3 => match split[2] {
+            "unordered" | "relaxed" => (Ordering::Relaxed, Ordering::Relaxed),
+            "acq" => (Ordering::Acquire, Ordering::Acquire),
+            "rel" => (Ordering::Release, Ordering::Relaxed),
+            "acqrel" => (Ordering::AcqRel, Ordering::Acquire),
+            "failrelaxed" if is_cxchg => (Ordering::SeqCst, Ordering::Relaxed),
+            "failacq" if is_cxchg => (Ordering::SeqCst, Ordering::Acquire),
+            _ => tcx.sess.fatal(&format!("unknown ordering in atomic intrinsic `{}`", intrinsic)),
+        },
+        4 => match (split[2], split[3]) {
+            ("acq", "failrelaxed") if is_cxchg => (Ordering::Acquire, Ordering::Relaxed),
+            ("acqrel", "failrelaxed") if is_cxchg => (Ordering::AcqRel, Ordering::Relaxed),
+            _ => tcx.sess.fatal(&format!("unknown ordering in atomic intrinsic `{}`", intrinsic)),
+        },
+        _ => tcx.sess.fatal(&format!("atomic intrinsic `{}` not in correct format", intrinsic)),
+    }
+}
+
+/// Call `__atomic_<op>_<size of ty>`. The memory orders are passed after `args`.
+fn atomic_libcall<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    op: &str,
+    ty: Ty<'tcx>,
+    args: &[Value],
+    orderings: &[Ordering],
+    output_ty: Option<types::Type>,
+) -> Option<Value> {
+    let size = fx.layout_of(ty).size.bytes();
+    if ![1, 2, 4, 8].contains(&size) {
+        unimpl!("atomic operation on {} byte value", size);
+    }
+
+    let mut args = args.to_vec();
+    for &ordering in orderings {
+        args.push(fx.bcx.ins().iconst(types::I32, ordering as i64));
+    }
+    let input_tys = args.iter().map(|&arg| fx.bcx.func.dfg.value_type(arg)).collect();
+
+    fx.lib_call(&format!("__atomic_{}_{}", op, size), input_tys, output_ty, &args)
+}
+
+/// `__atomic_compare_exchange_N(ptr, expected_ptr, desired, weak, success, failure)` writes the
+/// current value to `*expected_ptr` on failure, so the old value can always be read from there.
+fn atomic_cxchg<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    ty: Ty<'tcx>,
+    ptr: Value,
+    expected_place: CPlace<'tcx>,
+    new: Value,
+    weak: bool,
+    (success, failure): (Ordering, Ordering),
+) -> Value {
+    let expected_ptr = expected_place.to_addr(fx);
+    // The `weak` argument is a `bool`, which clang expects to be zero extended to 32 bits
+    let weak = fx.bcx.ins().iconst(types::I32, weak as i64);
+    atomic_libcall(
+        fx,
+        "compare_exchange",
+        ty,
+        &[ptr, expected_ptr, new, weak],
+        &[success, failure],
+        Some(types::I8),
+    )
+    .unwrap()
+}
+
+pub fn codegen_atomic_intrinsic_call<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    intrinsic: &str,
+    substs: &'tcx Substs,
+    args: Vec<CValue<'tcx>>,
+    ret: CPlace<'tcx>,
+) {
+    let (ordering, failure_ordering) = parse_orderings(fx.tcx, intrinsic);
+
+    intrinsic_match! {
+        fx, intrinsic, substs, args,
+
+        _ if intrinsic.starts_with("atomic_fence"), () {
+            let ordering = fx.bcx.ins().iconst(types::I32, ordering as i64);
+            fx.lib_call("atomic_thread_fence", vec![types::I32], None, &[ordering]);
+        };
+        _ if intrinsic.starts_with("atomic_singlethreadfence"), () {
+            let ordering = fx.bcx.ins().iconst(types::I32, ordering as i64);
+            fx.lib_call("atomic_signal_fence", vec![types::I32], None, &[ordering]);
+        };
+        _ if intrinsic.starts_with("atomic_load"), <T> (v ptr) {
+            let clif_ty = fx.clif_type(T).unwrap();
+            let val = atomic_libcall(fx, "load", T, &[ptr], &[ordering], Some(clif_ty)).unwrap();
+            ret.write_cvalue(fx, CValue::ByVal(val, fx.layout_of(T)));
+        };
+        _ if intrinsic.starts_with("atomic_store"), <T> (v ptr, v val) {
+            atomic_libcall(fx, "store", T, &[ptr, val], &[ordering], None);
+        };
+        _ if intrinsic.starts_with("atomic_xchg"), <T> (v ptr, v src) {
+            let clif_ty = fx.clif_type(T).unwrap();
+            let old = atomic_libcall(fx, "exchange", T, &[ptr, src], &[ordering], Some(clif_ty)).unwrap();
+            ret.write_cvalue(fx, CValue::ByVal(old, fx.layout_of(T)));
+        };
+        _ if intrinsic.starts_with("atomic_cxchg"), <T> (v ptr, c test_old, v new) { // both atomic_cxchg_* and atomic_cxchgweak_*
+            let weak = intrinsic.starts_with("atomic_cxchgweak");
+            let expected_place = CPlace::new_stack_slot(fx, T);
+            expected_place.write_cvalue(fx, test_old);
+
+            let is_eq = atomic_cxchg(fx, T, ptr, expected_place, new, weak, (ordering, failure_ordering));
+            let old = expected_place.to_cvalue(fx).load_scalar(fx);
+
+            let ret_val = CValue::ByValPair(old, is_eq, ret.layout());
+            ret.write_cvalue(fx, ret_val);
+        };
+
+        _ if intrinsic.starts_with("atomic_xadd")
+            || intrinsic.starts_with("atomic_xsub")
+            || intrinsic.starts_with("atomic_and")
+            || intrinsic.starts_with("atomic_nand")
+            || intrinsic.starts_with("atomic_or")
+            || intrinsic.starts_with("atomic_xor"), <T> (v ptr, v src) {
+            let op = match intrinsic.split('_').nth(1).unwrap() {
+                "xadd" => "fetch_add",
+                "xsub" => "fetch_sub",
+                "and" => "fetch_and",
+                "nand" => "fetch_nand",
+                "or" => "fetch_or",
+                "xor" => "fetch_xor",
+                _ => unreachable!(),
+            };
+            let clif_ty = fx.clif_type(T).unwrap();
+            let old = atomic_libcall(fx, op, T, &[ptr, src], &[ordering], Some(clif_ty)).unwrap();
+            ret.write_cvalue(fx, CValue::ByVal(old, fx.layout_of(T)));
+        };
+
+        // libatomic has no min and max functions, so use a compare exchange loop instead
+        _ if intrinsic.starts_with("atomic_max")
+            || intrinsic.starts_with("atomic_umax")
+            || intrinsic.starts_with("atomic_min")
+            || intrinsic.starts_with("atomic_umin"), <T> (v ptr, v src) {
+            let cc = match intrinsic.split('_').nth(1).unwrap() {
+                "max" => IntCC::SignedGreaterThan,
+                "umax" => IntCC::UnsignedGreaterThan,
+                "min" => IntCC::SignedLessThan,
+                "umin" => IntCC::UnsignedLessThan,
+                _ => unreachable!(),
+            };
+            let clif_ty = fx.clif_type(T).unwrap();
+
+            let expected_place = CPlace::new_stack_slot(fx, T);
+            let old = atomic_libcall(fx, "load", T, &[ptr], &[Ordering::Relaxed], Some(clif_ty)).unwrap();
+            expected_place.write_cvalue(fx, CValue::ByVal(old, fx.layout_of(T)));
+
+            let loop_ebb = fx.bcx.create_ebb();
+            fx.bcx.ins().jump(loop_ebb, &[]);
+            fx.bcx.switch_to_block(loop_ebb);
+
+            // Keep old if it already is the maximum or minimum
+            let old = expected_place.to_cvalue(fx).load_scalar(fx);
+            let keep_old = fx.bcx.ins().icmp(cc, old, src);
+            let new = crate::common::codegen_select(&mut fx.bcx, keep_old, old, src);
+
+            let success = atomic_cxchg(fx, T, ptr, expected_place, new, true, (ordering, Ordering::Relaxed));
+            fx.bcx.ins().brz(success, loop_ebb, &[]);
+
+            ret.write_cvalue(fx, CValue::ByVal(old, fx.layout_of(T)));
+        };
+    }
+}
@@ -0,0 +1,328 @@
+//! Codegen of the `simd_*` platform intrinsics.
+//!
+//! Cranelift doesn't support vector instructions on x86 yet, so vectors are stored in memory and
+//! every operation is performed lane by lane using scalar instructions.
+//!
+//! This is only enough for code using the `simd_*` intrinsics directly. `core::arch` and crates
+//! like `packed_simd` need the `llvm.*` intrinsics and real vector types, so `core::arch` is still
+//! disabled by `patches/0001-Disable-stdsimd.patch`.
+
+use rustc::mir::interpret::ConstValue;
+
+use super::*;
+
+fn validate_simd_type<'a, 'tcx: 'a>(
+    fx: &FunctionCx<'a, 'tcx, impl Backend>,
+    intrinsic: &str,
+    ty: Ty<'tcx>,
+) {
+    if !ty.is_simd() {
+        fx.tcx.sess.fatal(&format!(
+            "invalid monomorphization of `{}` intrinsic: expected SIMD input type, found non-SIMD `{}`",
+            intrinsic, ty,
+        ));
+    }
+}
+
+fn lane_count_and_layout<'a, 'tcx: 'a>(
+    fx: &FunctionCx<'a, 'tcx, impl Backend>,
+    layout: TyLayout<'tcx>,
+) -> (usize, TyLayout<'tcx>) {
+    assert!(layout.ty.is_simd());
+    let lane_count = layout.ty.simd_size(fx.tcx);
+    let lane_layout = fx.layout_of(layout.ty.simd_type(fx.tcx));
+    (lane_count, lane_layout)
+}
+
+fn simd_for_each_lane<'a, 'tcx: 'a, B: Backend>(
+    fx: &mut FunctionCx<'a, 'tcx, B>,
+    val: CValue<'tcx>,
+    ret: CPlace<'tcx>,
+    f: impl Fn(&mut FunctionCx<'a, 'tcx, B>, TyLayout<'tcx>, CValue<'tcx>) -> CValue<'tcx>,
+) {
+    let (lane_count, _lane_layout) = lane_count_and_layout(fx, val.layout());
+    let (ret_lane_count, ret_lane_layout) = lane_count_and_layout(fx, ret.layout());
+    assert_eq!(lane_count, ret_lane_count);
+
+    for lane in 0..lane_count {
+        let lane = mir::Field::new(lane);
+        let val_lane = val.value_field(fx, lane);
+        let res_lane = f(fx, ret_lane_layout, val_lane);
+        ret.place_field(fx, lane).write_cvalue(fx, res_lane);
+    }
+}
+
+fn simd_pair_for_each_lane<'a, 'tcx: 'a, B: Backend>(
+    fx: &mut FunctionCx<'a, 'tcx, B>,
+    x: CValue<'tcx>,
+    y: CValue<'tcx>,
+    ret: CPlace<'tcx>,
+    f: impl Fn(&mut FunctionCx<'a, 'tcx, B>, TyLayout<'tcx>, CValue<'tcx>, CValue<'tcx>) -> CValue<'tcx>,
+) {
+    assert_eq!(x.layout(), y.layout());
+    let (lane_count, _lane_layout) = lane_count_and_layout(fx, x.layout());
+    let (ret_lane_count, ret_lane_layout) = lane_count_and_layout(fx, ret.layout());
+    assert_eq!(lane_count, ret_lane_count);
+
+    for lane in 0..lane_count {
+        let lane = mir::Field::new(lane);
+        let x_lane = x.value_field(fx, lane);
+        let y_lane = y.value_field(fx, lane);
+        let res_lane = f(fx, ret_lane_layout, x_lane, y_lane);
+        ret.place_field(fx, lane).write_cvalue(fx, res_lane);
+    }
+}
+
+fn simd_lane_binop<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    bin_op: BinOp,
+    x: CValue<'tcx>,
+    y: CValue<'tcx>,
+    out_ty: Ty<'tcx>,
+) -> CValue<'tcx> {
+    match x.layout().ty.sty {
+        ty::Uint(_) => crate::base::trans_int_binop(fx, bin_op, x, y, out_ty, false),
+        ty::Int(_) => crate::base::trans_int_binop(fx, bin_op, x, y, out_ty, true),
+        ty::Float(_) => crate::base::trans_float_binop(fx, bin_op, x, y, out_ty),
+        _ => unreachable!("{:?}", x.layout().ty),
+    }
+}
+
+/// Comparisons return all ones for true and zero for false, like LLVM's `sext` of an `i1`
+fn simd_lane_cmp<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    bin_op: BinOp,
+    x: CValue<'tcx>,
+    y: CValue<'tcx>,
+    ret_lane_layout: TyLayout<'tcx>,
+) -> CValue<'tcx> {
+    let bool_ty = fx.tcx.types.bool;
+    let res = simd_lane_binop(fx, bin_op, x, y, bool_ty).load_scalar(fx);
+    let ret_lane_ty = fx.clif_type(ret_lane_layout.ty).unwrap();
+    let res = clif_intcast(fx, res, ret_lane_ty, false);
+    let res = fx.bcx.ins().ineg(res);
+    CValue::ByVal(res, ret_lane_layout)
+}
+
+fn simd_lane_cast<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    from: CValue<'tcx>,
+    to_layout: TyLayout<'tcx>,
+) -> CValue<'tcx> {
+    let from_ty = from.layout().ty;
+    let from_clif_ty = fx.clif_type(from_ty).unwrap();
+    let to_clif_ty = fx.clif_type(to_layout.ty).unwrap();
+    let from = from.load_scalar(fx);
+
+    let res = match (&from_ty.sty, &to_layout.ty.sty) {
+        (ty::Int(_), ty::Int(_)) | (ty::Int(_), ty::Uint(_)) => {
+            clif_intcast(fx, from, to_clif_ty, true)
+        }
+        (ty::Uint(_), ty::Int(_)) | (ty::Uint(_), ty::Uint(_)) => {
+            clif_intcast(fx, from, to_clif_ty, false)
+        }
+        (ty::Int(_), ty::Float(_)) => {
+            // FIXME missing encoding for fcvt_from_sint.f32.i8
+            let from = if from_clif_ty == types::I8 || from_clif_ty == types::I16 {
+                fx.bcx.ins().sextend(types::I32, from)
+            } else {
+                from
+            };
+            fx.bcx.ins().fcvt_from_sint(to_clif_ty, from)
+        }
+        (ty::Uint(_), ty::Float(_)) => {
+            // FIXME missing encoding for fcvt_from_uint.f32.i8
+            let from = if from_clif_ty == types::I8 || from_clif_ty == types::I16 {
+                fx.bcx.ins().uextend(types::I32, from)
+            } else {
+                from
+            };
+            fx.bcx.ins().fcvt_from_uint(to_clif_ty, from)
+        }
+        (ty::Float(_), ty::Int(_)) => fx.bcx.ins().fcvt_to_sint_sat(to_clif_ty, from),
+        (ty::Float(_), ty::Uint(_)) => fx.bcx.ins().fcvt_to_uint_sat(to_clif_ty, from),
+        (ty::Float(FloatTy::F32), ty::Float(FloatTy::F64)) => {
+            fx.bcx.ins().fpromote(types::F64, from)
+        }
+        (ty::Float(FloatTy::F64), ty::Float(FloatTy::F32)) => {
+            fx.bcx.ins().fdemote(types::F32, from)
+        }
+        (ty::Float(_), ty::Float(_)) => from,
+        _ => unimpl!("simd_cast from {:?} to {:?}", from_ty, to_layout.ty),
+    };
+    CValue::ByVal(res, to_layout)
+}
+
+/// The lane index of `simd_insert` and `simd_extract`. Like the `simd_shuffle*` indices it has to
+/// be a constant, so it can be checked against the lane count at compile time.
+fn simd_lane_idx<'a, 'tcx: 'a>(
+    fx: &FunctionCx<'a, 'tcx, impl Backend>,
+    intrinsic: &str,
+    idx: &Operand<'tcx>,
+    lane_count: usize,
+) -> usize {
+    let idx_const = crate::constant::mir_operand_get_const_val(fx, idx).unwrap_or_else(|| {
+        fx.tcx.sess.fatal(&format!(
+            "invalid monomorphization of `{}` intrinsic: index is not a constant",
+            intrinsic,
+        ))
+    });
+    let idx = idx_const
+        .val
+        .try_to_bits(Size::from_bytes(4))
+        .unwrap_or_else(|| panic!("{:?}", idx_const));
+    if idx >= lane_count as u128 {
+        fx.tcx.sess.fatal(&format!(
+            "invalid monomorphization of `{}` intrinsic: index {} is out of bounds (limit {})",
+            intrinsic, idx, lane_count,
+        ));
+    }
+    idx as usize
+}
+
+pub fn codegen_simd_intrinsic_call<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    intrinsic: &str,
+    substs: &'tcx Substs,
+    mir_args: &[Operand<'tcx>],
+    args: Vec<CValue<'tcx>>,
+    ret: CPlace<'tcx>,
+) {
+    intrinsic_match! {
+        fx, intrinsic, substs, args,
+
+        simd_cast, (c a) {
+            validate_simd_type(fx, intrinsic, a.layout().ty);
+            simd_for_each_lane(fx, a, ret, |fx, ret_lane_layout, lane| {
+                simd_lane_cast(fx, lane, ret_lane_layout)
+            });
+        };
+
+        simd_eq | simd_ne | simd_lt | simd_le | simd_gt | simd_ge, (c x, c y) {
+            validate_simd_type(fx, intrinsic, x.layout().ty);
+            let bin_op = match intrinsic {
+                "simd_eq" => BinOp::Eq,
+                "simd_ne" => BinOp::Ne,
+                "simd_lt" => BinOp::Lt,
+                "simd_le" => BinOp::Le,
+                "simd_gt" => BinOp::Gt,
+                "simd_ge" => BinOp::Ge,
+                _ => unreachable!(),
+            };
+            simd_pair_for_each_lane(fx, x, y, ret, |fx, ret_lane_layout, x_lane, y_lane| {
+                simd_lane_cmp(fx, bin_op, x_lane, y_lane, ret_lane_layout)
+            });
+        };
+
+        simd_add | simd_sub | simd_mul | simd_div | simd_rem
+        | simd_shl | simd_shr | simd_and | simd_or | simd_xor, (c x, c y) {
+            validate_simd_type(fx, intrinsic, x.layout().ty);
+            let bin_op = match intrinsic {
+                "simd_add" => BinOp::Add,
+                "simd_sub" => BinOp::Sub,
+                "simd_mul" => BinOp::Mul,
+                "simd_div" => BinOp::Div,
+                "simd_rem" => BinOp::Rem,
+                "simd_shl" => BinOp::Shl,
+                "simd_shr" => BinOp::Shr,
+                "simd_and" => BinOp::BitAnd,
+                "simd_or" => BinOp::BitOr,
+                "simd_xor" => BinOp::BitXor,
+                _ => unreachable!(),
+            };
+            simd_pair_for_each_lane(fx, x, y, ret, |fx, ret_lane_layout, x_lane, y_lane| {
+                simd_lane_binop(fx, bin_op, x_lane, y_lane, ret_lane_layout.ty)
+            });
+        };
+
+        // simd_shuffle32<T, U>(x: T, y: T, idx: [u32; 32]) -> U
+        _ if intrinsic.starts_with("simd_shuffle"), (c x, c y, c _idx) {
+            validate_simd_type(fx, intrinsic, x.layout().ty);
+
+            let n: usize = intrinsic["simd_shuffle".len()..].parse().unwrap();
+            let (lane_count, _lane_layout) = lane_count_and_layout(fx, x.layout());
+            let (ret_lane_count, _ret_lane_layout) = lane_count_and_layout(fx, ret.layout());
+            assert_eq!(n, ret_lane_count);
+
+            // The indices are always a constant, which is required to be evaluated at compile time
+            let idx_const = crate::constant::mir_operand_get_const_val(fx, &mir_args[2])
+                .expect("simd_shuffle* idx not const");
+            let idx_bytes = match idx_const.val {
+                ConstValue::ByRef(_alloc_id, alloc, offset) => {
+                    let offset = offset.bytes() as usize;
+                    &alloc.bytes[offset..offset + 4 * n]
+                }
+                _ => unreachable!("{:?}", idx_const),
+            };
+            let indices = idx_bytes
+                .chunks(4)
+                .map(|idx| {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(idx);
+                    match fx.tcx.data_layout.endian {
+                        layout::Endian::Little => u32::from_le_bytes(bytes),
+                        layout::Endian::Big => u32::from_be_bytes(bytes),
+                    }
+                })
+                .collect::<Vec<u32>>();
+
+            for (out_idx, &in_idx) in indices.iter().enumerate() {
+                let in_idx = in_idx as usize;
+                let in_lane = if in_idx < lane_count {
+                    x.value_field(fx, mir::Field::new(in_idx))
+                } else if in_idx < 2 * lane_count {
+                    y.value_field(fx, mir::Field::new(in_idx - lane_count))
+                } else {
+                    fx.tcx.sess.fatal(&format!(
+                        "invalid monomorphization of `{}` intrinsic: shuffle index #{} is out of bounds (limit {})",
+                        intrinsic, out_idx, 2 * lane_count,
+                    ));
+                };
+                ret.place_field(fx, mir::Field::new(out_idx)).write_cvalue(fx, in_lane);
+            }
+        };
+
+        // simd_insert<T, E>(x: T, idx: u32, val: E) -> T
+        simd_insert, (c base, c _idx, c val) {
+            validate_simd_type(fx, intrinsic, base.layout().ty);
+            let (lane_count, lane_layout) = lane_count_and_layout(fx, base.layout());
+            assert_eq!(lane_layout, val.layout());
+            let idx = simd_lane_idx(fx, intrinsic, &mir_args[1], lane_count);
+
+            ret.write_cvalue(fx, base);
+            ret.place_field(fx, mir::Field::new(idx)).write_cvalue(fx, val);
+        };
+
+        // simd_extract<T, E>(x: T, idx: u32) -> E
+        simd_extract, (c v, c _idx) {
+            validate_simd_type(fx, intrinsic, v.layout().ty);
+            let (lane_count, _lane_layout) = lane_count_and_layout(fx, v.layout());
+            let idx = simd_lane_idx(fx, intrinsic, &mir_args[1], lane_count);
+
+            let lane = v.value_field(fx, mir::Field::new(idx));
+            ret.write_cvalue(fx, lane);
+        };
+
+        simd_select, (c m, c a, c b) {
+            validate_simd_type(fx, intrinsic, m.layout().ty);
+            validate_simd_type(fx, intrinsic, a.layout().ty);
+            let (lane_count, _lane_layout) = lane_count_and_layout(fx, a.layout());
+            let (mask_lane_count, _mask_lane_layout) = lane_count_and_layout(fx, m.layout());
+            assert_eq!(lane_count, mask_lane_count);
+
+            for lane in 0..lane_count {
+                let lane = mir::Field::new(lane);
+                let m_lane = m.value_field(fx, lane).load_scalar(fx);
+                let a_lane = a.value_field(fx, lane).load_scalar(fx);
+                let b_lane = b.value_field(fx, lane).load_scalar(fx);
+
+                let m_lane = fx.bcx.ins().icmp_imm(IntCC::Equal, m_lane, 0);
+                let res_lane = codegen_select(&mut fx.bcx, m_lane, b_lane, a_lane);
+
+                let ret_lane = ret.place_field(fx, lane);
+                let res_lane = CValue::ByVal(res_lane, ret_lane.layout());
+                ret_lane.write_cvalue(fx, res_lane);
+            }
+        };
+    }
+}
@@ -0,0 +1,616 @@
+//! Running the crate in-process, which happens when the `SHOULD_RUN` env var is set.
+//!
+//! The upstream crates and native libraries are loaded into the rustc process first, see
+//! `load_dependencies`. Assembly and thread local statics of the jitted code are loaded as a shared
+//! object too, see `load_global_asm`.
+//!
+//! By default every function is compiled before `main` is called. When the `JIT_LAZY` env var is
+//! set too, every function is replaced by a stub instead. The stub calls the function whose
+//! address is stored in a pointer slot belonging to the stub. As long as this slot is null, the
+//! stub first calls `__clif_jit_fn`, which compiles the function in a new module and returns its
+//! address, after which the stub patches the slot. This way only functions which are actually
+//! called get compiled.
+//!
+//! The `TyCtxt` can only be used on the thread running rustc, but the jitted code may call a
+//! function for the first time on any thread it spawns. In lazy mode `main` therefore runs on a
+//! thread of its own, while the rustc thread compiles the functions requested by `__clif_jit_fn`
+//! through a channel.
+
+use std::ffi::{CStr, CString};
+use std::fmt::Write;
+use std::fs::File;
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{mpsc, Mutex};
+
+use tempfile::Builder as TempFileBuilder;
+
+use rustc::middle::cstore::NativeLibraryKind;
+use rustc::middle::dependency_format::Linkage as DependencyLinkage;
+use rustc::session::search_paths::PathKind;
+use rustc_allocator::ALLOCATOR_METHODS;
+use rustc_codegen_ssa::CrateInfo;
+
+use crate::prelude::*;
+
+use crate::archive::{object_symbols, read_archive, ArchiveBuilder};
+use crate::inline_asm::quoted_symbol;
+use crate::link_copied::{archive_config, relevant_lib, RLIB_BYTECODE_EXTENSION};
+use crate::metadata::METADATA_FILENAME;
+
+pub fn run_jit<'a, 'tcx: 'a>(tcx: TyCtxt<'a, 'tcx, 'tcx>, log: &Option<File>) -> ! {
+    let lazy = std::env::var("JIT_LAZY").is_ok();
+
+    let (local_functions, local_statics) = local_symbol_names(tcx);
+    let (dependency_symbols, trampolines) = load_dependencies(tcx, &local_functions);
+
+    let mut jit_builder = SimpleJITBuilder::new();
+    jit_builder.symbol("__clif_jit_fn", clif_jit_fn as *const u8);
+    jit_builder.symbols(dependency_symbols.iter().cloned());
+    let mut jit_module: Module<SimpleJITBackend> = Module::new(jit_builder);
+    assert_eq!(pointer_ty(tcx), jit_module.target_config().pointer_type());
+
+    let sig = Signature {
+        params: vec![
+            AbiParam::new(jit_module.target_config().pointer_type()),
+            AbiParam::new(jit_module.target_config().pointer_type()),
+        ],
+        returns: vec![AbiParam::new(
+            jit_module.target_config().pointer_type(), /*isize*/
+        )],
+        call_conv: CallConv::SystemV,
+    };
+    let main_func_id = jit_module
+        .declare_function("main", Linkage::Import, &sig)
+        .unwrap();
+
+    let (instances, global_asm_symbols) = if lazy {
+        codegen_stubs(tcx, &mut jit_module, log)
+    } else {
+        (Vec::new(), crate::codegen_cgus(tcx, &mut jit_module, log))
+    };
+    crate::save_incremental(tcx);
+    crate::allocator::codegen(tcx.sess, &mut jit_module);
+
+    // Functions whose codegen failed are declared, but not defined, so report the errors before
+    // finalizing tries to resolve references to them
+    tcx.sess.abort_if_errors();
+
+    jit_module.finalize_definitions();
+
+    for (name, slot) in trampolines {
+        let ptr = finalized_symbol(&mut jit_module, &name).unwrap_or_else(|| {
+            tcx.sess.fatal(&format!("`{}` is used by an upstream crate, but wasn't defined", name))
+        });
+        unsafe {
+            *slot = ptr;
+        }
+    }
+
+    let finalized_main: *const u8 = jit_module.get_finalized_function(main_func_id);
+
+    println!("Rustc codegen cranelift will JIT run the executable, because the SHOULD_RUN env var is set");
+
+    let args = ::std::env::var("JIT_ARGS").unwrap_or_else(|_|String::new());
+    let args = args
+        .split(" ")
+        .chain(Some(&*tcx.crate_name(LOCAL_CRATE).as_str().to_string()))
+        .map(|arg| CString::new(arg).unwrap()).collect::<Vec<_>>();
+
+    let ret = if lazy {
+        let mut symbols = local_functions
+            .into_iter()
+            .chain(local_statics)
+            .filter_map(|name| {
+                let ptr = finalized_symbol(&mut jit_module, &name)?;
+                Some((name, ptr))
+            })
+            .collect::<Vec<_>>();
+        symbols.extend(dependency_symbols);
+        symbols.extend(global_asm_symbols);
+
+        let state = LazyJitState {
+            tcx,
+            instances,
+            symbols,
+            log,
+        };
+        run_main_lazily(&state, finalized_main, args)
+    } else {
+        call_main(finalized_main, args)
+    };
+
+    jit_module.finish();
+    std::process::exit(ret);
+}
+
+fn call_main(main: *const u8, args: Vec<CString>) -> c_int {
+    let f: extern "C" fn(c_int, *const *const c_char) -> c_int =
+        unsafe { ::std::mem::transmute(main) };
+    let argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+    // TODO: Rust doesn't care, but POSIX argv has a NULL sentinel at the end
+    f(args.len() as c_int, argv.as_ptr())
+}
+
+/// Run `main` on a new thread and compile the functions requested by `__clif_jit_fn` until it
+/// returns.
+fn run_main_lazily<'a, 'tcx: 'a>(
+    state: &LazyJitState<'a, 'tcx>,
+    main: *const u8,
+    args: Vec<CString>,
+) -> c_int {
+    let (tx, rx) = mpsc::channel();
+    let requests = Box::new(Mutex::new(tx.clone()));
+    // Leaked, as threads spawned by the jitted code may still call `__clif_jit_fn` while the
+    // process exits
+    LAZY_JIT_REQUESTS.store(Box::into_raw(requests), Ordering::SeqCst);
+
+    // Raw pointers aren't `Send`
+    let main = main as usize;
+    std::thread::Builder::new()
+        .name("jit main".to_string())
+        // The default stack size of spawned threads is much smaller than that of the main thread
+        .stack_size(8 * 1024 * 1024)
+        .spawn(move || {
+            let ret = call_main(main as *const u8, args);
+            tx.send(LazyJitMessage::Exit(ret)).unwrap();
+        })
+        .unwrap();
+
+    loop {
+        match rx.recv().unwrap() {
+            LazyJitMessage::Compile(index, reply) => {
+                let func_ptr = codegen_lazily(state, index);
+                reply.send(func_ptr as usize).unwrap();
+            }
+            LazyJitMessage::Exit(ret) => return ret,
+        }
+    }
+}
+
+/// The symbol names of all functions and statics defined by the jitted code
+fn local_symbol_names<'a, 'tcx: 'a>(tcx: TyCtxt<'a, 'tcx, 'tcx>) -> (Vec<String>, Vec<String>) {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+    let mut functions = Vec::new();
+    let mut statics = Vec::new();
+    for mono_item in cgus.iter().flat_map(|cgu| cgu.items().keys()) {
+        match *mono_item {
+            MonoItem::Fn(instance) => {
+                functions.push(tcx.symbol_name(instance).as_str().to_string());
+            }
+            MonoItem::Static(def_id) => {
+                let instance = Instance::mono(tcx, def_id);
+                statics.push(tcx.symbol_name(instance).as_str().to_string());
+            }
+            MonoItem::GlobalAsm(_) => {}
+        }
+    }
+    if crate::allocator::shim_kind(tcx.sess).is_some() {
+        functions.extend(ALLOCATOR_METHODS.iter().map(|method| format!("__rust_{}", method.name)));
+    }
+    (functions, statics)
+}
+
+/// The address of the function or static `name`, or `None` when it wasn't declared, for example
+/// because its codegen hit something unimplemented before declaring it. Declared, but undefined
+/// symbols can't occur, as compilation is aborted on errors before the symbols are looked up.
+fn finalized_symbol(module: &mut Module<SimpleJITBackend>, name: &str) -> Option<*const u8> {
+    match module.get_name(name) {
+        Some(FuncOrDataId::Func(func_id)) => Some(module.get_finalized_function(func_id)),
+        Some(FuncOrDataId::Data(data_id)) => Some(module.get_finalized_data(data_id).0 as *const u8),
+        None => None,
+    }
+}
+
+/// Make the upstream crates and the native libraries used by them and the local crate available
+/// to the jitted code. Returns the symbols defined by upstream rlibs and the slots for the
+/// addresses of the trampolines, which have to be written once the jitted code is finalized.
+///
+/// Upstream dylibs are loaded as is. Rlibs can't be loaded, so they are linked into a temporary
+/// shared object, which also links to the native libraries. Some functions used by upstream
+/// crates, like the allocator shim or the `#[panic_handler]`, are only defined by the jitted
+/// code. Calls to them go through trampolines in the shared object, which jump to the address
+/// stored in `<symbol>.jit_addr`.
+fn load_dependencies<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    local_functions: &[String],
+) -> (Vec<(String, *const u8)>, Vec<(String, *mut *const u8)>) {
+    let sess = tcx.sess;
+    let crate_info = CrateInfo::new(tcx);
+    let formats = sess.dependency_formats.borrow();
+    let data = formats.get(&CrateType::Executable).unwrap();
+
+    let tmpdir = match TempFileBuilder::new().prefix("cg_clif_jit").tempdir() {
+        Ok(tmpdir) => tmpdir,
+        Err(err) => sess.fatal(&format!("couldn't create a temp dir: {}", err)),
+    };
+    let so_path = tmpdir.path().join("libjit_dependencies.so");
+
+    let mut cmd = Command::new("cc");
+    cmd.arg("-shared").arg("-o").arg(&so_path);
+    // Calls to the trampolines must not be resolved to a function of the same name in rustc
+    cmd.arg("-Wl,-Bsymbolic-functions");
+    for search_path in sess.target_filesearch(PathKind::All).search_paths() {
+        cmd.arg("-L").arg(&search_path.dir);
+    }
+
+    let mut defined = FxHashSet::default();
+    let mut undefined = FxHashSet::default();
+    let mut native_libs = Vec::new();
+
+    // Everything in the rlibs and static native libraries may be used by the jitted code
+    cmd.arg("-Wl,--whole-archive");
+    for lib in crate_info.used_libraries.iter() {
+        match lib.name {
+            Some(name) if relevant_lib(sess, lib) => match lib.kind {
+                NativeLibraryKind::NativeStatic => {
+                    cmd.arg(format!("-l{}", name));
+                }
+                NativeLibraryKind::NativeFramework => {}
+                _ => native_libs.push(name),
+            },
+            _ => {}
+        }
+    }
+    for &(cnum, _) in crate_info.used_crates_dynamic.iter() {
+        let src = &crate_info.used_crate_source[&cnum];
+        match data[cnum.as_usize() - 1] {
+            DependencyLinkage::NotLinked | DependencyLinkage::IncludedFromDylib => {}
+            DependencyLinkage::Dynamic => {
+                load_dylib(sess, &src.dylib.as_ref().unwrap().0);
+            }
+            DependencyLinkage::Static => {
+                let rlib = &src.rlib.as_ref().unwrap().0;
+                let dst = tmpdir.path().join(rlib.file_name().unwrap());
+                let entries = read_archive(rlib).unwrap_or_else(|err| {
+                    sess.fatal(&format!("failed to read archive {}: {}", rlib.display(), err))
+                });
+                let mut builder = ArchiveBuilder::new(archive_config(sess, &dst, None));
+                for (name, contents) in entries {
+                    if name == METADATA_FILENAME || name.ends_with(RLIB_BYTECODE_EXTENSION) {
+                        continue;
+                    }
+                    defined.extend(object_symbols(&contents, true));
+                    undefined.extend(object_symbols(&contents, false));
+                    builder.add_data(&name, contents);
+                }
+                builder.update_symbols();
+                builder.build();
+                cmd.arg(&dst);
+
+                // Statically linked native libraries are bundled in the rlib
+                for lib in crate_info.native_libraries[&cnum].iter() {
+                    match lib.name {
+                        Some(name) if relevant_lib(sess, lib) => match lib.kind {
+                            NativeLibraryKind::NativeStatic
+                            | NativeLibraryKind::NativeFramework => {}
+                            _ => native_libs.push(name),
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    cmd.arg("-Wl,--no-whole-archive");
+
+    let mut seen_native_libs = FxHashSet::default();
+    for name in native_libs {
+        if seen_native_libs.insert(name) {
+            cmd.arg(format!("-l{}", name));
+        }
+    }
+
+    if crate::intrinsics::needs_libatomic(sess) {
+        cmd.arg("-latomic");
+    }
+
+    let trampoline_names = local_functions
+        .iter()
+        .filter(|name| undefined.contains(*name) && !defined.contains(*name))
+        .collect::<Vec<_>>();
+    let mut trampolines_asm = String::new();
+    for name in &trampoline_names {
+        let symbol = quoted_symbol(name);
+        let addr_symbol = quoted_symbol(&format!("{}.jit_addr", name));
+        writeln!(trampolines_asm, "    .text").unwrap();
+        writeln!(trampolines_asm, "    .globl {}", symbol).unwrap();
+        writeln!(trampolines_asm, "    .type {},@function", symbol).unwrap();
+        writeln!(trampolines_asm, "{}:", symbol).unwrap();
+        writeln!(trampolines_asm, "    movq {}@GOTPCREL(%rip), %r11", addr_symbol).unwrap();
+        writeln!(trampolines_asm, "    jmpq *(%r11)").unwrap();
+        writeln!(trampolines_asm, "    .data").unwrap();
+        writeln!(trampolines_asm, "    .globl {}", addr_symbol).unwrap();
+        writeln!(trampolines_asm, "    .balign 8").unwrap();
+        writeln!(trampolines_asm, "{}:", addr_symbol).unwrap();
+        writeln!(trampolines_asm, "    .quad 0").unwrap();
+    }
+    // Without this the shared object would require an executable stack
+    writeln!(trampolines_asm, "    .section .note.GNU-stack,\"\",@progbits").unwrap();
+    let trampolines_path = tmpdir.path().join("trampolines.s");
+    std::fs::write(&trampolines_path, trampolines_asm).unwrap();
+    cmd.arg(&trampolines_path);
+
+    match cmd.output() {
+        Ok(ref output) if output.status.success() => {}
+        Ok(output) => sess.fatal(&format!(
+            "linking the dependencies for jitting failed: {:?}\n{}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr),
+        )),
+        Err(err) => sess.fatal(&format!("couldn't run cc: {}", err)),
+    }
+    let handle = load_dylib(sess, &so_path);
+
+    let lookup = |name: &str| {
+        let c_name = CString::new(name).unwrap();
+        unsafe { libc::dlsym(handle, c_name.as_ptr()) }
+    };
+    let symbols = defined
+        .iter()
+        .map(|name| (name.clone(), lookup(name) as *const u8))
+        .filter(|&(_, ptr)| !ptr.is_null())
+        .collect();
+    let trampolines = trampoline_names
+        .into_iter()
+        .map(|name| {
+            let slot = lookup(&format!("{}.jit_addr", name));
+            assert!(!slot.is_null(), "missing trampoline for {}", name);
+            (name.clone(), slot as *mut *const u8)
+        })
+        .collect();
+
+    (symbols, trampolines)
+}
+
+/// Assemble the `global_asm!` items, `asm!` wrappers and thread local statics of the jitted code
+/// into a shared object and load it, like the rlibs in `load_dependencies`. Returns the symbols
+/// defined by it.
+///
+/// The assembly is only known after the module using it has been created, so this has to be
+/// called before that module is finalized, which resolves its imports of these symbols using
+/// `dlsym`. The returned symbols are registered with the `SimpleJITBuilder` of the modules created
+/// afterwards.
+pub fn load_global_asm<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    global_asm: &str,
+) -> Vec<(String, *const u8)> {
+    if global_asm.is_empty() {
+        return Vec::new();
+    }
+
+    let sess = tcx.sess;
+    let tmpdir = match TempFileBuilder::new().prefix("cg_clif_jit_asm").tempdir() {
+        Ok(tmpdir) => tmpdir,
+        Err(err) => sess.fatal(&format!("couldn't create a temp dir: {}", err)),
+    };
+    let asm_path = tmpdir.path().join("global_asm.s");
+    let so_path = tmpdir.path().join("libjit_global_asm.so");
+
+    // `dlsym` can't find hidden symbols, like the `asm!` wrappers and thread local accessors
+    let mut asm = global_asm
+        .lines()
+        .filter(|line| !line.trim_start().starts_with(".hidden "))
+        .collect::<Vec<_>>()
+        .join("\n");
+    // Without this the shared object would require an executable stack
+    asm.push_str("\n    .section .note.GNU-stack,\"\",@progbits\n");
+    std::fs::write(&asm_path, asm).unwrap();
+
+    let mut cmd = Command::new("cc");
+    cmd.arg("-shared").arg("-o").arg(&so_path).arg(&asm_path);
+    match cmd.output() {
+        Ok(ref output) if output.status.success() => {}
+        Ok(output) => sess.fatal(&format!(
+            "assembling the global asm for jitting failed: {:?}\n{}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr),
+        )),
+        Err(err) => sess.fatal(&format!("couldn't run cc: {}", err)),
+    }
+    let handle = load_dylib(sess, &so_path);
+
+    let so = std::fs::read(&so_path).unwrap();
+    object_symbols(&so, true)
+        .into_iter()
+        .filter_map(|name| {
+            let c_name = CString::new(&*name).unwrap();
+            let ptr = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+            if ptr.is_null() {
+                None
+            } else {
+                Some((name, ptr as *const u8))
+            }
+        })
+        .collect()
+}
+
+fn load_dylib(sess: &Session, path: &Path) -> *mut libc::c_void {
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) };
+    if handle.is_null() {
+        let err = unsafe { CStr::from_ptr(libc::dlerror()) };
+        sess.fatal(&format!("couldn't load {}: {}", path.display(), err.to_string_lossy()));
+    }
+    handle
+}
+
+struct LazyJitState<'a, 'tcx: 'a> {
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    /// The instance to compile for every stub index
+    instances: Vec<Instance<'tcx>>,
+    /// The addresses of the stubs, statics and allocator shim of the main module and of the
+    /// symbols defined by upstream rlibs
+    symbols: Vec<(String, *const u8)>,
+    log: &'a Option<File>,
+}
+
+enum LazyJitMessage {
+    /// Compile the function of the stub with the given index and send back its address
+    Compile(usize, mpsc::Sender<usize>),
+    /// `main` returned the given exit code
+    Exit(c_int),
+}
+
+/// Used by `__clif_jit_fn` to send requests to the rustc thread. Null when not jitting lazily.
+static LAZY_JIT_REQUESTS: AtomicPtr<Mutex<mpsc::Sender<LazyJitMessage>>> =
+    AtomicPtr::new(std::ptr::null_mut());
+
+/// Define a stub for every function and codegen all other mono items. Returns the instance of
+/// every stub index and the symbols defined by the global asm, see `load_global_asm`.
+fn codegen_stubs<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    module: &mut Module<SimpleJITBackend>,
+    log: &Option<File>,
+) -> (Vec<Instance<'tcx>>, Vec<(String, *const u8)>) {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+
+    let mut instances = Vec::new();
+    let mut other_mono_items = FxHashMap::default();
+    for cgu in cgus.iter() {
+        for (&mono_item, &(linkage, vis)) in cgu.items() {
+            match mono_item {
+                MonoItem::Fn(instance) => {
+                    crate::unimpl::try_unimpl(tcx, log, || {
+                        codegen_stub(tcx, module, instance, instances.len());
+                        instances.push(instance);
+                    });
+                }
+                MonoItem::Static(_) | MonoItem::GlobalAsm(_) => {
+                    other_mono_items.insert(mono_item, (linkage, vis));
+                }
+            }
+        }
+    }
+
+    let (mut functions, global_asm) =
+        crate::codegen_mono_items(tcx, module, "jit", None, None, log, other_mono_items);
+    functions.extend(crate::main_shim::maybe_create_entry_wrapper(tcx, module));
+    crate::compile_functions_for_jit(tcx, module, functions);
+
+    (instances, load_global_asm(tcx, &global_asm))
+}
+
+/// Define the stub for `instance`, which calls `__clif_jit_fn(index)` on the first call.
+fn codegen_stub<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    module: &mut Module<SimpleJITBackend>,
+    instance: Instance<'tcx>,
+    index: usize,
+) {
+    let pointer_type = module.target_config().pointer_type();
+
+    let (name, sig) = get_function_name_and_sig(tcx, instance);
+    let func_id = module
+        .declare_function(&name, Linkage::Export, &sig)
+        .unwrap();
+
+    let slot_id = module
+        .declare_data(&format!("{}.jit_slot", name), Linkage::Local, true)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define_zeroinit(pointer_type.bytes() as usize);
+    module.define_data(slot_id, &data_ctx).unwrap();
+
+    let jit_fn_sig = Signature {
+        params: vec![AbiParam::new(pointer_type)],
+        returns: vec![AbiParam::new(pointer_type)],
+        call_conv: CallConv::SystemV,
+    };
+    let jit_fn_id = module
+        .declare_function("__clif_jit_fn", Linkage::Import, &jit_fn_sig)
+        .unwrap();
+
+    let mut ctx = Context::new();
+    ctx.func = Function::with_name_signature(ExternalName::user(0, 0), sig.clone());
+    {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut bcx = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+        let entry_ebb = bcx.create_ebb();
+        let compile_ebb = bcx.create_ebb();
+        let call_ebb = bcx.create_ebb();
+
+        bcx.switch_to_block(entry_ebb);
+        let args = sig
+            .params
+            .iter()
+            .map(|param| bcx.append_ebb_param(entry_ebb, param.value_type))
+            .collect::<Vec<Value>>();
+        let slot_gv = module.declare_data_in_func(slot_id, &mut bcx.func);
+        let slot = bcx.ins().global_value(pointer_type, slot_gv);
+        let func_ptr = bcx.ins().load(pointer_type, MemFlags::new(), slot, 0);
+        bcx.ins().brnz(func_ptr, call_ebb, &[func_ptr]);
+        bcx.ins().jump(compile_ebb, &[]);
+
+        bcx.switch_to_block(compile_ebb);
+        let jit_fn = module.declare_func_in_func(jit_fn_id, &mut bcx.func);
+        let index = bcx.ins().iconst(pointer_type, index as i64);
+        let call_inst = bcx.ins().call(jit_fn, &[index]);
+        let func_ptr = bcx.inst_results(call_inst)[0];
+        bcx.ins().store(MemFlags::new(), func_ptr, slot, 0);
+        bcx.ins().jump(call_ebb, &[func_ptr]);
+
+        bcx.switch_to_block(call_ebb);
+        let func_ptr = bcx.append_ebb_param(call_ebb, pointer_type);
+        let sig_ref = bcx.import_signature(sig);
+        let call_inst = bcx.ins().call_indirect(sig_ref, func_ptr, &args);
+        let results = bcx.inst_results(call_inst).to_vec(); // Clone to prevent borrow error
+        bcx.ins().return_(&results);
+
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(func_id, &mut ctx).unwrap();
+}
+
+/// Called by the stub with index `index` on any thread. Asks the rustc thread to compile the
+/// function and waits for its address.
+extern "C" fn clif_jit_fn(index: usize) -> *const u8 {
+    let requests = LAZY_JIT_REQUESTS.load(Ordering::SeqCst);
+    assert!(!requests.is_null(), "__clif_jit_fn called while not jitting lazily");
+    let (reply_tx, reply_rx) = mpsc::channel();
+    unsafe { &*requests }
+        .lock()
+        .unwrap()
+        .send(LazyJitMessage::Compile(index, reply_tx))
+        .unwrap();
+    reply_rx.recv().unwrap() as *const u8
+}
+
+/// Compile the function of the stub with index `index`. Calls into other functions go through
+/// their stubs, so every function is compiled in a module of its own.
+fn codegen_lazily<'a, 'tcx: 'a>(state: &LazyJitState<'a, 'tcx>, index: usize) -> *const u8 {
+    let tcx = state.tcx;
+    let instance = state.instances[index];
+
+    let mut jit_builder = SimpleJITBuilder::new();
+    jit_builder.symbol("__clif_jit_fn", clif_jit_fn as *const u8);
+    jit_builder.symbols(state.symbols.iter().cloned());
+    let mut module: Module<SimpleJITBackend> = Module::new(jit_builder);
+
+    let mut cx = crate::CodegenCx::new(tcx, &mut module, "jit", None, None);
+    crate::unimpl::try_unimpl(tcx, state.log, || {
+        crate::base::trans_mono_item(&mut cx, MonoItem::Fn(instance), Linkage::Export);
+    });
+    let (functions, global_asm) = cx.finalize();
+
+    // The jitted code can't handle compilation errors, so exit instead
+    if tcx.sess.err_count() > 0 {
+        std::process::exit(1);
+    }
+    crate::compile_functions_for_jit(tcx, &mut module, functions);
+    load_global_asm(tcx, &global_asm);
+
+    module.finalize_definitions();
+    let (name, sig) = get_function_name_and_sig(tcx, instance);
+    let func_id = module.declare_function(&name, Linkage::Export, &sig).unwrap();
+    let func_ptr = module.get_finalized_function(func_id);
+
+    // Freeing the module would free the compiled function
+    std::mem::forget(module);
+
+    func_ptr
+}
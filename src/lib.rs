@@ -1,7 +1,9 @@
 #![feature(rustc_private, never_type, decl_macro)]
 #![allow(intra_doc_link_resolution_failure)]
 
+extern crate flate2;
 extern crate log;
+extern crate num_cpus;
 extern crate rustc;
 extern crate rustc_allocator;
 extern crate rustc_codegen_ssa;
@@ -15,27 +17,31 @@ extern crate syntax;
 
 use std::any::Any;
 use std::fs::File;
-use std::sync::mpsc;
-use std::os::raw::{c_char, c_int};
-use std::ffi::CString;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
-use rustc::dep_graph::DepGraph;
+use rustc::dep_graph::{DepGraph, WorkProduct, WorkProductFileKind, WorkProductId};
 use rustc::middle::cstore::MetadataLoader;
 use rustc::session::{
-    config::{DebugInfo, OutputFilenames, OutputType},
+    config::{DebugInfo, OptLevel, OutputFilenames, OutputType},
     CompileIncomplete,
 };
 use rustc::ty::query::Providers;
-use rustc::mir::mono::{Linkage as RLinkage, Visibility};
+use rustc::mir::mono::{CodegenUnit, Linkage as RLinkage, Visibility};
+use rustc_data_structures::stable_hasher::{HashStable, StableHasher, StableHasherResult};
 use rustc_codegen_ssa::back::linker::LinkerInfo;
 use rustc_codegen_ssa::CrateInfo;
 use rustc_codegen_utils::codegen_backend::CodegenBackend;
 use rustc_codegen_utils::link::out_filename;
+use syntax::symbol::InternedString;
 
 use cranelift::codegen::settings;
 use cranelift_faerie::*;
 
+use crate::base::UncompiledFunction;
 use crate::constant::ConstantCx;
+use crate::debuginfo::DebugUnit;
 use crate::prelude::*;
 
 mod abi;
@@ -43,18 +49,24 @@ mod allocator;
 mod analyze;
 mod archive;
 mod base;
+mod codegen_i128;
 mod common;
 mod constant;
 mod debuginfo;
+mod inline_asm;
 mod intrinsics;
+mod jit;
 mod link;
 mod link_copied;
 mod main_shim;
 mod metadata;
+mod optimize;
 mod pretty_clif;
+mod tls;
 mod trap;
 mod unimpl;
 mod unsize;
+mod unwind;
 mod vtable;
 
 mod prelude {
@@ -89,6 +101,7 @@ mod prelude {
 
     pub use cranelift::codegen::ir::{
         condcodes::IntCC, function::Function, ExternalName, FuncRef, Inst, StackSlot, SourceLoc,
+        ValueLabel,
     };
     pub use cranelift::codegen::isa::CallConv;
     pub use cranelift::codegen::Context;
@@ -105,18 +118,17 @@ mod prelude {
     pub use crate::debuginfo::{DebugContext, FunctionDebugContext};
     pub use crate::trap::*;
     pub use crate::unimpl::{unimpl, with_unimpl_span};
+    pub use crate::unwind::{UnwindContext, UnwindInfoKind};
     pub use crate::{Caches, CodegenCx};
 }
 
 pub struct Caches<'tcx> {
-    pub context: Context,
     pub vtables: HashMap<(Ty<'tcx>, Option<ty::PolyExistentialTraitRef<'tcx>>), DataId>,
 }
 
 impl<'tcx> Default for Caches<'tcx> {
     fn default() -> Self {
         Caches {
-            context: Context::new(),
             vtables: HashMap::new(),
         }
     }
@@ -128,13 +140,25 @@ pub struct CodegenCx<'a, 'clif, 'tcx, B: Backend + 'static> {
     ccx: ConstantCx,
     caches: Caches<'tcx>,
     debug_context: Option<&'clif mut DebugContext<'tcx>>,
+    unwind_context: Option<&'clif mut UnwindContext>,
+    /// The translated functions, which are compiled after all mono items are translated
+    functions: Vec<UncompiledFunction>,
+    /// Trap instead of calling the panic lang items when an `Assert` terminator fails. Enabled
+    /// using `-Cllvm-args=trap-on-assert`.
+    trap_on_assert: bool,
+    /// Name of the cgu, used to give the wrapper functions for `asm!` blocks unique names
+    cgu_name: String,
+    /// `global_asm!` items and wrapper functions for `asm!` blocks, see `inline_asm`
+    global_asm: String,
 }
 
 impl<'a, 'clif, 'tcx, B: Backend + 'static> CodegenCx<'a, 'clif, 'tcx, B> {
     fn new(
         tcx: TyCtxt<'a, 'tcx, 'tcx>,
         module: &'clif mut Module<B>,
+        cgu_name: &str,
         debug_context: Option<&'clif mut DebugContext<'tcx>>,
+        unwind_context: Option<&'clif mut UnwindContext>,
     ) -> Self {
         CodegenCx {
             tcx,
@@ -142,11 +166,19 @@ impl<'a, 'clif, 'tcx, B: Backend + 'static> CodegenCx<'a, 'clif, 'tcx, B> {
             ccx: ConstantCx::default(),
             caches: Caches::default(),
             debug_context,
+            unwind_context,
+            functions: Vec::new(),
+            trap_on_assert: backend_option(tcx.sess, "trap-on-assert").is_some(),
+            cgu_name: cgu_name.to_string(),
+            global_asm: String::new(),
         }
     }
 
-    fn finalize(self) {
-        self.ccx.finalize(self.tcx, self.module);
+    /// Returns the functions to compile and the assembly to be assembled into an extra object
+    /// file
+    fn finalize(self) -> (Vec<UncompiledFunction>, String) {
+        self.ccx.finalize(self.tcx, self.module, self.debug_context);
+        (self.functions, self.global_asm)
     }
 }
 
@@ -156,7 +188,12 @@ impl CodegenBackend for CraneliftCodegenBackend {
     fn init(&self, sess: &Session) {
         for cty in sess.opts.crate_types.iter() {
             match *cty {
-                CrateType::Rlib | CrateType::Dylib | CrateType::Executable => {}
+                CrateType::Rlib
+                | CrateType::Dylib
+                | CrateType::Executable
+                | CrateType::Staticlib
+                | CrateType::Cdylib
+                | CrateType::ProcMacro => {}
                 _ => {
                     sess.err(&format!(
                         "Rustc codegen cranelift doesn't support output type {}",
@@ -177,6 +214,12 @@ impl CodegenBackend for CraneliftCodegenBackend {
         if sess.opts.debugging_opts.pgo_gen.is_some() {
             sess.err("pgo is not supported");
         }
+        match sess.opts.optimize {
+            OptLevel::Size | OptLevel::SizeMin => {
+                sess.warn("Optimizing for size is not supported. Optimizing for speed instead");
+            }
+            OptLevel::No | OptLevel::Less | OptLevel::Default | OptLevel::Aggressive => {}
+        }
     }
 
     fn metadata_loader(&self) -> Box<dyn MetadataLoader + Sync> {
@@ -210,139 +253,102 @@ impl CodegenBackend for CraneliftCodegenBackend {
 
         let metadata = tcx.encode_metadata();
 
-        // TODO: move to the end of this function when compiling libcore doesn't have unimplemented stuff anymore
-        save_incremental(tcx);
-        tcx.sess.warn("Saved incremental data");
-
-        let mut log = if cfg!(debug_assertions) {
+        let log = if cfg!(debug_assertions) {
             Some(File::create(concat!(env!("CARGO_MANIFEST_DIR"), "/target/out/log.txt")).unwrap())
         } else {
             None
         };
 
         if std::env::var("SHOULD_RUN").is_ok() {
-            let mut jit_module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
-            assert_eq!(pointer_ty(tcx), jit_module.target_config().pointer_type());
-
-            let sig = Signature {
-                params: vec![
-                    AbiParam::new(jit_module.target_config().pointer_type()),
-                    AbiParam::new(jit_module.target_config().pointer_type()),
-                ],
-                returns: vec![AbiParam::new(
-                    jit_module.target_config().pointer_type(), /*isize*/
-                )],
-                call_conv: CallConv::SystemV,
-            };
-            let main_func_id = jit_module
-                .declare_function("main", Linkage::Import, &sig)
-                .unwrap();
-
-            codegen_cgus(tcx, &mut jit_module, &mut None, &mut log);
-            crate::allocator::codegen(tcx.sess, &mut jit_module);
-            jit_module.finalize_definitions();
-
-            tcx.sess.abort_if_errors();
-
-            let finalized_main: *const u8 = jit_module.get_finalized_function(main_func_id);
-
-            println!("Rustc codegen cranelift will JIT run the executable, because the SHOULD_RUN env var is set");
-
-            let f: extern "C" fn(c_int, *const *const c_char) -> c_int =
-                unsafe { ::std::mem::transmute(finalized_main) };
-
-            let args = ::std::env::var("JIT_ARGS").unwrap_or_else(|_|String::new());
-            let args = args
-                .split(" ")
-                .chain(Some(&*tcx.crate_name(LOCAL_CRATE).as_str().to_string()))
-                .map(|arg| CString::new(arg).unwrap()).collect::<Vec<_>>();
-            let argv = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
-            // TODO: Rust doesn't care, but POSIX argv has a NULL sentinel at the end
-
-            let ret = f(args.len() as c_int, argv.as_ptr());
-
-            jit_module.finish();
-            std::process::exit(ret);
+            crate::jit::run_jit(tcx, &log);
         } else {
-            let new_module = |name: String| {
-                let module: Module<FaerieBackend> = Module::new(
-                    FaerieBuilder::new(
-                        build_isa(tcx.sess),
-                        name + ".o",
-                        FaerieTrapCollection::Disabled,
-                        FaerieBuilder::default_libcall_names(),
-                    )
-                    .unwrap(),
-                );
-                assert_eq!(
-                    pointer_ty(tcx),
-                    module.target_config().pointer_type()
-                );
-                module
-            };
-
-            let emit_module = |name: &str, kind: ModuleKind, mut module: Module<FaerieBackend>, debug: Option<DebugContext>| {
-                module.finalize_definitions();
-                let mut artifact = module.finish().artifact;
-
-                if let Some(mut debug) = debug {
-                    debug.emit(&mut artifact);
+            let unwind_info_kind = crate::unwind::unwind_info_kind(tcx.sess);
+
+            let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
+
+            // The cgus are translated to clif ir on this thread, as that needs the `TyCtxt`.
+            // Compiling and emitting them happens on the worker threads in the meantime.
+            let mut workers = Workers::spawn();
+            let mut results = Vec::with_capacity(cgus.len());
+            time("codegen cgus", || {
+                for cgu in cgus.iter() {
+                    if can_reuse_cgu(tcx, cgu) {
+                        results.push(reuse_workproduct_for_cgu(tcx, cgu));
+                        continue;
+                    }
+
+                    let dep_node = cgu.codegen_dep_node(tcx);
+                    let (module, _) = tcx.dep_graph.with_task(
+                        dep_node,
+                        tcx,
+                        (cgu.name().clone(), unwind_info_kind, &log),
+                        module_codegen,
+                        rustc::dep_graph::hash_result,
+                    );
+                    workers.compile(module);
                 }
+            });
+            if tcx.sess.opts.debugging_opts.incremental_info {
+                println!("[incremental] Re-using {} out of {} modules", results.len(), cgus.len());
+            }
+            let compiled = time("compile cgus", || workers.join());
+            for result in compiled {
+                let (module, global_asm_module) = result.unwrap_or_else(|err| tcx.sess.fatal(&err));
+                let work_product = save_work_product(tcx, &module, &global_asm_module);
+                results.push(ModuleCodegenResult(module, global_asm_module, work_product));
+            }
 
-                let tmp_file = tcx
-                    .output_filenames(LOCAL_CRATE)
-                    .temp_path(OutputType::Object, Some(name));
-                let obj = artifact.emit().unwrap();
-                std::fs::write(&tmp_file, obj).unwrap();
-                CompiledModule {
-                    name: name.to_string(),
-                    kind,
-                    object: Some(tmp_file),
-                    bytecode: None,
-                    bytecode_compressed: None,
+            let mut modules = Vec::with_capacity(results.len());
+            let mut work_products = FxHashMap::default();
+            for ModuleCodegenResult(module, global_asm_module, work_product) in results {
+                modules.push(module);
+                modules.extend(global_asm_module);
+                if let Some((id, product)) = work_product {
+                    work_products.insert(id, product);
                 }
-            };
-
-            let mut faerie_module = new_module("some_file".to_string());
-
-            let mut debug = if tcx.sess.opts.debuginfo != DebugInfo::None
-                && !tcx.sess.target.target.options.is_like_osx // macOS debuginfo doesn't work yet (see #303)
-            {
-                let debug = DebugContext::new(tcx, faerie_module.target_config().pointer_type().bytes() as u8);
-                Some(debug)
-            } else {
-                None
-            };
+            }
 
-            codegen_cgus(tcx, &mut faerie_module, &mut debug, &mut log);
+            // Errors are checked afterwards, as the incremental session directory is discarded
+            // anyway when there were errors.
+            save_incremental(tcx);
 
             tcx.sess.abort_if_errors();
 
-            let mut allocator_module = new_module("allocator_shim.o".to_string());
+            let mut allocator_module = new_module(tcx, "allocator_shim.o".to_string());
             let created_alloc_shim =
                 crate::allocator::codegen(tcx.sess, &mut allocator_module);
 
-            return Box::new(CodegenResults {
-                crate_name: tcx.crate_name(LOCAL_CRATE),
-                modules: vec![emit_module("dummy_name", ModuleKind::Regular, faerie_module, debug)],
-                allocator_module: if created_alloc_shim {
-                    Some(emit_module("allocator_shim", ModuleKind::Allocator, allocator_module, None))
-                } else {
-                    None
-                },
-                metadata_module: CompiledModule {
+            // Rustc reads the metadata of dylibs and proc-macros from the linked shared object
+            let metadata_module = if tcx.sess.crate_types.get().iter().any(|&crate_type| {
+                crate_type == CrateType::Dylib || crate_type == CrateType::ProcMacro
+            }) {
+                crate::metadata::codegen_metadata_module(tcx, &metadata)
+            } else {
+                CompiledModule {
                     name: "dummy_metadata".to_string(),
                     kind: ModuleKind::Metadata,
                     object: None,
                     bytecode: None,
                     bytecode_compressed: None,
+                }
+            };
+
+            return Box::new((CodegenResults {
+                crate_name: tcx.crate_name(LOCAL_CRATE),
+                modules,
+                allocator_module: if created_alloc_shim {
+                    let object = object_path(tcx, "allocator_shim");
+                    Some(emit_module("allocator_shim", ModuleKind::Allocator, object, allocator_module, None, None))
+                } else {
+                    None
                 },
+                metadata_module,
                 crate_hash: tcx.crate_hash(LOCAL_CRATE),
                 metadata,
                 windows_subsystem: None, // Windows is not yet supported
                 linker_info: LinkerInfo::new(tcx),
                 crate_info: CrateInfo::new(tcx),
-            });
+            }, work_products));
         }
     }
 
@@ -350,30 +356,55 @@ impl CodegenBackend for CraneliftCodegenBackend {
         &self,
         res: Box<dyn Any>,
         sess: &Session,
-        _dep_graph: &DepGraph,
+        dep_graph: &DepGraph,
         outputs: &OutputFilenames,
     ) -> Result<(), CompileIncomplete> {
-        let res = *res
-            .downcast::<CodegenResults>()
+        let (res, work_products) = *res
+            .downcast::<(CodegenResults, FxHashMap<WorkProductId, WorkProduct>)>()
             .expect("Expected CraneliftCodegenBackend's CodegenResult, found Box<Any>");
 
+        rustc_incremental::save_work_product_index(sess, dep_graph, work_products);
+
         for &crate_type in sess.opts.crate_types.iter() {
             let output_name = out_filename(sess, crate_type, &outputs, &res.crate_name.as_str());
             match crate_type {
                 CrateType::Rlib => link::link_rlib(sess, &res, output_name),
-                CrateType::Dylib | CrateType::Executable => {
+                CrateType::Staticlib => link::link_staticlib(sess, &res, &output_name),
+                // The exported symbols of a cdylib or proc-macro are limited using a version
+                // script by `Linker::export_symbols`. For proc-macros this is only the
+                // `__rustc_proc_macro_decls_*` static rustc looks for after loading it.
+                CrateType::Dylib
+                | CrateType::Cdylib
+                | CrateType::ProcMacro
+                | CrateType::Executable => {
                     link::link_natively(sess, crate_type, &res, &output_name);
                 }
-                _ => sess.fatal(&format!("Unsupported crate type: {:?}", crate_type)),
             }
         }
         Ok(())
     }
 }
 
-fn build_isa(sess: &Session) -> Box<isa::TargetIsa + 'static> {
-    use rustc::session::config::OptLevel;
+/// Returns the value of the backend option `name` passed as `-Cllvm-args=name=value`, or an empty
+/// string for `-Cllvm-args=name`. A leading `-` is accepted like LLVM does. When an option is
+/// passed multiple times, the last one wins.
+///
+/// `-Cllvm-args` is the only way to pass options to a codegen backend. Unlike env vars it is
+/// tracked by the dep graph, so changing an option invalidates the reused cgus.
+pub fn backend_option<'a>(sess: &'a Session, name: &str) -> Option<&'a str> {
+    sess.opts.cg.llvm_args.iter().rev().find_map(|arg| {
+        let arg = if arg.starts_with('-') { &arg[1..] } else { &arg[..] };
+        if arg == name {
+            Some("")
+        } else if arg.starts_with(name) && arg[name.len()..].starts_with('=') {
+            Some(&arg[name.len() + 1..])
+        } else {
+            None
+        }
+    })
+}
 
+fn build_isa(sess: &Session) -> Box<isa::TargetIsa + 'static> {
     let mut flags_builder = settings::builder();
     flags_builder.enable("is_pic").unwrap();
     flags_builder.set("probestack_enabled", "false").unwrap(); // ___cranelift_probestack is not provided
@@ -383,19 +414,9 @@ fn build_isa(sess: &Session) -> Box<isa::TargetIsa + 'static> {
         "false"
     }).unwrap();
 
-    // FIXME enable again when https://github.com/CraneStation/cranelift/issues/664 is fixed
-    /*match sess.opts.optimize {
-        OptLevel::No => {
-            flags_builder.set("opt_level", "fastest").unwrap();
-        }
-        OptLevel::Less | OptLevel::Default => {}
-        OptLevel::Aggressive => {
-            flags_builder.set("opt_level", "best").unwrap();
-        }
-        OptLevel::Size | OptLevel::SizeMin => {
-            sess.warn("Optimizing for size is not supported. Just ignoring the request");
-        }
-    }*/
+    flags_builder
+        .set("opt_level", crate::optimize::cranelift_opt_level(sess))
+        .unwrap();
 
     let flags = settings::Flags::new(flags_builder);
     cranelift::codegen::isa::lookup(sess.target.target.llvm_target.parse().unwrap())
@@ -403,12 +424,13 @@ fn build_isa(sess: &Session) -> Box<isa::TargetIsa + 'static> {
         .finish(flags)
 }
 
+/// Codegen all cgus into a single module. Used for jitting. Returns the symbols defined by the
+/// global asm, see `jit::load_global_asm`.
 fn codegen_cgus<'a, 'tcx: 'a>(
     tcx: TyCtxt<'a, 'tcx, 'tcx>,
     module: &mut Module<impl Backend + 'static>,
-    debug: &mut Option<DebugContext<'tcx>>,
-    log: &mut Option<File>,
-) {
+    log: &Option<File>,
+) -> Vec<(String, *const u8)> {
     let (_, cgus) = tcx.collect_and_partition_mono_items(LOCAL_CRATE);
     let mono_items = cgus
         .iter()
@@ -417,19 +439,362 @@ fn codegen_cgus<'a, 'tcx: 'a>(
         .map(|(&mono_item, &(linkage, vis))| (mono_item, (linkage, vis)))
         .collect::<FxHashMap<_, (_, _)>>();
 
-    codegen_mono_items(tcx, module, debug.as_mut(), log, mono_items);
+    let (mut functions, global_asm) =
+        codegen_mono_items(tcx, module, "jit", None, None, log, mono_items);
+    functions.extend(crate::main_shim::maybe_create_entry_wrapper(tcx, module));
+    compile_functions_for_jit(tcx, module, functions);
 
-    crate::main_shim::maybe_create_entry_wrapper(tcx, module);
+    crate::jit::load_global_asm(tcx, &global_asm)
+}
+
+/// When jitting there is no cgu to compile on a worker thread, so the functions are compiled on
+/// the current thread right away.
+fn compile_functions_for_jit(
+    tcx: TyCtxt,
+    module: &mut Module<impl Backend + 'static>,
+    functions: Vec<UncompiledFunction>,
+) {
+    let passes = crate::optimize::passes(tcx.sess);
+    if let Err(err) = crate::base::compile_functions(module, None, None, &passes, functions) {
+        tcx.sess.fatal(&err);
+    }
+}
+
+fn new_module(tcx: TyCtxt, name: String) -> Module<FaerieBackend> {
+    let module: Module<FaerieBackend> = Module::new(
+        FaerieBuilder::new(
+            build_isa(tcx.sess),
+            name + ".o",
+            FaerieTrapCollection::Disabled,
+            FaerieBuilder::default_libcall_names(),
+        )
+        .unwrap(),
+    );
+    assert_eq!(
+        pointer_ty(tcx),
+        module.target_config().pointer_type()
+    );
+    module
+}
+
+fn object_path<'a, 'tcx: 'a>(tcx: TyCtxt<'a, 'tcx, 'tcx>, name: &str) -> PathBuf {
+    tcx.output_filenames(LOCAL_CRATE).temp_path(OutputType::Object, Some(name))
+}
+
+/// Doesn't use the `TyCtxt`, as it runs on the worker threads for cgus.
+fn emit_module(
+    name: &str,
+    kind: ModuleKind,
+    object: PathBuf,
+    mut module: Module<FaerieBackend>,
+    debug: Option<DebugUnit>,
+    unwind: Option<UnwindContext>,
+) -> CompiledModule {
+    module.finalize_definitions();
+    let mut artifact = module.finish().artifact;
+
+    if let Some(mut debug) = debug {
+        debug.emit(&mut artifact);
+    }
+
+    if let Some(unwind) = unwind {
+        unwind.emit(&mut artifact);
+    }
+
+    let mut obj = artifact.emit().unwrap();
+    crate::unwind::mark_eh_frame_allocated(&mut obj);
+    std::fs::write(&object, obj).unwrap();
+    CompiledModule {
+        name: name.to_string(),
+        kind,
+        object: Some(object),
+        bytecode: None,
+        bytecode_compressed: None,
+    }
+}
+
+/// A cgu translated to clif ir, which still has to be compiled and emitted. This doesn't need the
+/// `TyCtxt`, so it happens on a worker thread, like LLVM runs on worker threads for cg_llvm.
+struct UncompiledModule {
+    name: String,
+    object: PathBuf,
+    module: Module<FaerieBackend>,
+    debug: Option<DebugUnit>,
+    unwind: Option<UnwindContext>,
+    passes: Vec<crate::optimize::Pass>,
+    functions: Vec<UncompiledFunction>,
+    /// The object for the assembly of the cgu if any, see `inline_asm`
+    global_asm_module: Option<CompiledModule>,
+}
+
+// `FaerieBackend` isn't `Send`, as it stores the `TargetIsa` and the function giving the names of
+// libcalls as trait objects without `Send` bound. The ones created by `new_module` don't have
+// any thread local state, so the module can be moved to a worker thread. cg_llvm does the same
+// for its `ModuleLlvm`.
+unsafe impl Send for UncompiledModule {}
+
+impl<HCX> HashStable<HCX> for UncompiledModule {
+    fn hash_stable<W: StableHasherResult>(&self, _: &mut HCX, _: &mut StableHasher<W>) {
+        // do nothing
+    }
+}
+
+/// Runs on a worker thread. Returns the compiled module and the object for its assembly.
+fn compile_module(
+    module: UncompiledModule,
+) -> Result<(CompiledModule, Option<CompiledModule>), String> {
+    let UncompiledModule {
+        name,
+        object,
+        mut module,
+        mut debug,
+        mut unwind,
+        passes,
+        functions,
+        global_asm_module,
+    } = module;
+
+    crate::base::compile_functions(&mut module, debug.as_mut(), unwind.as_mut(), &passes, functions)?;
+    let compiled_module = emit_module(&name, ModuleKind::Regular, object, module, debug, unwind);
+    Ok((compiled_module, global_asm_module))
+}
+
+/// A thread per cpu compiling the modules passed to `compile`.
+struct Workers {
+    jobs: mpsc::Sender<(usize, UncompiledModule)>,
+    results: mpsc::Receiver<(usize, Result<(CompiledModule, Option<CompiledModule>), String>)>,
+    job_count: usize,
+}
+
+impl Workers {
+    fn spawn() -> Self {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<(usize, UncompiledModule)>();
+        let (results_tx, results_rx) = mpsc::channel();
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+
+        for i in 0..num_cpus::get() {
+            let jobs_rx = jobs_rx.clone();
+            let results_tx = results_tx.clone();
+            thread::Builder::new()
+                .name(format!("cg_clif worker {}", i))
+                .spawn(move || loop {
+                    // The lock is released again before the module is compiled
+                    let job = jobs_rx.lock().unwrap().recv();
+                    match job {
+                        Ok((index, module)) => {
+                            results_tx.send((index, compile_module(module))).unwrap();
+                        }
+                        // All modules have been compiled
+                        Err(mpsc::RecvError) => break,
+                    }
+                })
+                .unwrap();
+        }
+
+        Workers {
+            jobs: jobs_tx,
+            results: results_rx,
+            job_count: 0,
+        }
+    }
+
+    fn compile(&mut self, module: UncompiledModule) {
+        self.jobs.send((self.job_count, module)).unwrap();
+        self.job_count += 1;
+    }
+
+    /// Wait for all modules to be compiled. The results are in the order the modules were passed
+    /// to `compile`, so the order of the objects doesn't depend on the scheduling.
+    fn join(self) -> Vec<Result<(CompiledModule, Option<CompiledModule>), String>> {
+        let Workers { jobs, results, job_count } = self;
+        // Makes the workers exit once all jobs are done
+        drop(jobs);
+
+        let mut compiled = (0..job_count).map(|_| None).collect::<Vec<_>>();
+        // Ends when all workers have exited
+        for (index, result) in results {
+            compiled[index] = Some(result);
+        }
+        compiled
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| Err("a cranelift worker thread panicked".to_string()))
+            })
+            .collect()
+    }
+}
+
+/// The compiled module of a cgu, the object for its assembly if any and the work product to save
+/// for it in the incremental cache
+struct ModuleCodegenResult(
+    CompiledModule,
+    Option<CompiledModule>,
+    Option<(WorkProductId, WorkProduct)>,
+);
+
+// Adapted from `determine_cgu_reuse` in rustc_codegen_ssa::base
+fn can_reuse_cgu<'a, 'tcx: 'a>(tcx: TyCtxt<'a, 'tcx, 'tcx>, cgu: &CodegenUnit<'tcx>) -> bool {
+    if !tcx.dep_graph.is_fully_enabled() {
+        return false;
+    }
+
+    let work_product_id = &cgu.work_product_id();
+    if tcx.dep_graph.previous_work_product(work_product_id).is_none() {
+        // We don't have anything cached for this CGU. This can happen
+        // if the CGU did not exist in the previous session.
+        return false;
+    }
+
+    // Try to mark the CGU as green. If it we can do so, it means that nothing
+    // affecting the object file has changed and we can re-use the cached version.
+    let dep_node = cgu.codegen_dep_node(tcx);
+    assert!(
+        !tcx.dep_graph.dep_node_exists(&dep_node),
+        "CompileCodegenUnit dep-node for CGU `{}` already exists before marking.",
+        cgu.name()
+    );
+
+    tcx.dep_graph.try_mark_green(tcx, &dep_node).is_some()
+}
+
+fn reuse_workproduct_for_cgu<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    cgu: &CodegenUnit<'tcx>,
+) -> ModuleCodegenResult {
+    let work_product = cgu.work_product(tcx);
+    let mut object = None;
+    for (kind, saved_file) in &work_product.saved_files {
+        match kind {
+            WorkProductFileKind::Object => {}
+            _ => bug!("Unexpected work product file kind {:?} for cgu {}", kind, cgu.name()),
+        }
+        let obj_out = tcx
+            .output_filenames(LOCAL_CRATE)
+            .temp_path(OutputType::Object, Some(&cgu.name().as_str()));
+        let source_file = rustc_incremental::in_incr_comp_dir_sess(tcx.sess, saved_file);
+        if let Err(err) = rustc_fs_util::link_or_copy(&source_file, &obj_out) {
+            tcx.sess.err(&format!(
+                "unable to copy {} to {}: {}",
+                source_file.display(),
+                obj_out.display(),
+                err
+            ));
+        }
+        object = Some(obj_out);
+    }
+
+    ModuleCodegenResult(
+        CompiledModule {
+            name: cgu.name().to_string(),
+            kind: ModuleKind::Regular,
+            object,
+            bytecode: None,
+            bytecode_compressed: None,
+        },
+        None,
+        Some((cgu.work_product_id(), work_product)),
+    )
+}
+
+/// Executed as dep graph task, so every query used while compiling the cgu becomes a dependency
+/// of its `CompileCodegenUnit` dep node.
+fn module_codegen<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    (cgu_name, unwind_info_kind, log): (InternedString, Option<UnwindInfoKind>, &Option<File>),
+) -> UncompiledModule {
+    let cgu = tcx.codegen_unit(cgu_name);
+    let cgu_name = cgu.name().as_str().to_string();
+
+    let mut module = new_module(tcx, cgu_name.clone());
+
+    let mut debug = if tcx.sess.opts.debuginfo != DebugInfo::None
+        && !tcx.sess.target.target.options.is_like_osx // macOS debuginfo doesn't work yet (see #303)
+    {
+        let debug = DebugContext::new(tcx, module.target_config().pointer_type().bytes() as u8);
+        Some(debug)
+    } else {
+        None
+    };
+
+    let mut unwind = unwind_info_kind.map(|kind| UnwindContext::new(tcx, &mut module, kind));
+
+    let (functions, global_asm) =
+        codegen_cgu(tcx, &mut module, debug.as_mut(), unwind.as_mut(), log, &cgu);
+
+    let global_asm_module = if global_asm.is_empty() {
+        None
+    } else {
+        Some(crate::inline_asm::assemble(tcx, &format!("{}.asm", cgu_name), &global_asm))
+    };
+
+    UncompiledModule {
+        object: object_path(tcx, &cgu_name),
+        name: cgu_name,
+        module,
+        debug: debug.map(DebugContext::into_unit),
+        unwind,
+        passes: crate::optimize::passes(tcx.sess),
+        functions,
+        global_asm_module,
+    }
+}
+
+fn save_work_product<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    module: &CompiledModule,
+    global_asm_module: &Option<CompiledModule>,
+) -> Option<(WorkProductId, WorkProduct)> {
+    // Work products only support a single object file per cgu, so cgus with assembly are always
+    // recompiled.
+    if tcx.sess.opts.incremental.is_some() && global_asm_module.is_none() {
+        rustc_incremental::copy_cgu_workproducts_to_incr_comp_cache_dir(
+            tcx.sess,
+            &module.name,
+            &[(WorkProductFileKind::Object, module.object.clone().unwrap())],
+        )
+    } else {
+        None
+    }
+}
+
+fn codegen_cgu<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    module: &mut Module<impl Backend + 'static>,
+    debug: Option<&mut DebugContext<'tcx>>,
+    unwind: Option<&mut UnwindContext>,
+    log: &Option<File>,
+    cgu: &CodegenUnit<'tcx>,
+) -> (Vec<UncompiledFunction>, String) {
+    let mono_items = cgu
+        .items()
+        .iter()
+        .map(|(&mono_item, &(linkage, vis))| (mono_item, (linkage, vis)))
+        .collect::<FxHashMap<_, (_, _)>>();
+
+    let cgu_name = cgu.name().as_str();
+    let (mut functions, global_asm) =
+        codegen_mono_items(tcx, module, &cgu_name, debug, unwind, log, mono_items);
+
+    // Only the cgu containing the user main function gets the entry wrapper
+    if let Some((main_def_id, _)) = tcx.entry_fn(LOCAL_CRATE) {
+        if cgu.items().contains_key(&MonoItem::Fn(Instance::mono(tcx, main_def_id))) {
+            functions.extend(crate::main_shim::maybe_create_entry_wrapper(tcx, module));
+        }
+    }
+
+    (functions, global_asm)
 }
 
 fn codegen_mono_items<'a, 'tcx: 'a>(
     tcx: TyCtxt<'a, 'tcx, 'tcx>,
     module: &mut Module<impl Backend + 'static>,
+    cgu_name: &str,
     debug_context: Option<&mut DebugContext<'tcx>>,
-    log: &mut Option<File>,
+    unwind_context: Option<&mut UnwindContext>,
+    log: &Option<File>,
     mono_items: FxHashMap<MonoItem<'tcx>, (RLinkage, Visibility)>,
-) {
-    let mut cx = CodegenCx::new(tcx, module, debug_context);
+) -> (Vec<UncompiledFunction>, String) {
+    let mut cx = CodegenCx::new(tcx, module, cgu_name, debug_context, unwind_context);
     time("codegen mono items", move || {
         for (mono_item, (linkage, vis)) in mono_items {
             unimpl::try_unimpl(tcx, log, || {
@@ -445,8 +810,8 @@ fn codegen_mono_items<'a, 'tcx: 'a>(
             });
         }
 
-        cx.finalize();
-    });
+        cx.finalize()
+    })
 }
 
 fn time<R>(name: &str, f: impl FnOnce() -> R) -> R {
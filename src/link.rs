@@ -1,14 +1,14 @@
 use std::ascii;
 use std::char;
 use std::env;
-use std::fs::File;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::str;
 
 use tempfile::Builder as TempFileBuilder;
 
-use rustc::session::config::{self, CrateType, DebugInfo, RUST_CGU_EXT};
+use rustc::middle::cstore::NativeLibraryKind;
+use rustc::session::config::{self, CrateType, DebugInfo, PrintRequest, RUST_CGU_EXT};
 use rustc::session::search_paths::PathKind;
 use rustc::session::Session;
 use rustc_codegen_ssa::back::command::Command;
@@ -19,52 +19,75 @@ use rustc_target::spec::{LinkerFlavor, PanicStrategy, RelroLevel};
 
 use crate::prelude::*;
 
+use crate::archive::ArchiveBuilder;
 use crate::link_copied::*;
 
 pub(crate) fn link_rlib(sess: &Session, res: &CodegenResults, output_name: PathBuf) {
-    let file = File::create(&output_name).unwrap();
-    let mut builder = ar::Builder::new(file);
+    let mut builder = ArchiveBuilder::new(archive_config(sess, &output_name, None));
 
     // Add main object file
     for module in &res.modules {
         if let Some(ref object_path) = module.object {
-            let object = File::open(object_path).expect("Someone deleted our object file");
-            let object_len = object.metadata().unwrap().len();
-            builder
-                .append(
-                    &ar::Header::new(
-                        (module.name.to_string() + RUST_CGU_EXT).into_bytes(),
-                        object_len,
-                    ),
-                    object,
-                )
-                .unwrap();
+            builder.add_file(object_path);
         }
     }
 
-    // Non object files need to be added after object files, because ranlib will
-    // try to read the native architecture from the first file, even if it isn't
-    // an object file
-    builder
-        .append(
-            &ar::Header::new(
-                crate::metadata::METADATA_FILENAME.as_bytes().to_vec(),
-                res.metadata.raw_data.len() as u64,
-            ),
-            ::std::io::Cursor::new(res.metadata.raw_data.clone()),
-        )
-        .unwrap();
-
-    // Finalize archive
-    std::mem::drop(builder);
-
-    // Run ranlib to be able to link the archive
-    let status = std::process::Command::new("ranlib")
-        .arg(output_name)
-        .status()
-        .expect("Couldn't run ranlib");
-    if !status.success() {
-        sess.fatal(&format!("Ranlib exited with code {:?}", status.code()));
+    // Non object files need to be added after object files, because some tools
+    // read the native architecture from the first file, even if it isn't an
+    // object file
+    builder.add_data(crate::metadata::METADATA_FILENAME, res.metadata.raw_data.clone());
+
+    builder.update_symbols();
+    builder.build();
+}
+
+/// Bundle the objects of this crate together with the objects of all upstream rlibs into a single
+/// archive, which can be linked by a non-rust linker.
+pub(crate) fn link_staticlib(sess: &Session, res: &CodegenResults, output_name: &Path) {
+    let mut builder = ArchiveBuilder::new(archive_config(sess, output_name, None));
+
+    for module in res.modules.iter().chain(&res.allocator_module) {
+        if let Some(ref object_path) = module.object {
+            builder.add_file(object_path);
+        }
+    }
+
+    // Statically linked native libraries of this crate are bundled too
+    for lib in res.crate_info.used_libraries.iter() {
+        match lib.name {
+            Some(name) if lib.kind == NativeLibraryKind::NativeStatic && relevant_lib(sess, lib) => {
+                builder.add_native_library(&name.as_str());
+            }
+            _ => {}
+        }
+    }
+
+    let mut all_native_libs = vec![];
+    let result = each_linked_rlib(sess, &res.crate_info, &mut |cnum, path| {
+        // If a native static library of the crate is not relevant for the current target, all
+        // native objects bundled in the rlib are skipped, like when linking natively
+        let native_libs = &res.crate_info.native_libraries[&cnum];
+        let skip_native = native_libs.iter().any(|lib| {
+            lib.kind == NativeLibraryKind::NativeStatic && !relevant_lib(sess, lib)
+        });
+
+        builder.add_archive(path, |name| {
+            name == crate::metadata::METADATA_FILENAME
+                || name.ends_with(RLIB_BYTECODE_EXTENSION)
+                || (skip_native && !name.ends_with(&format!(".{}.o", RUST_CGU_EXT)))
+        });
+
+        all_native_libs.extend(native_libs.iter().cloned());
+    });
+    if let Err(err) = result {
+        sess.fatal(&err);
+    }
+
+    builder.update_symbols();
+    builder.build();
+
+    if sess.opts.prints.contains(&PrintRequest::NativeStaticLibs) {
+        print_native_static_libs(sess, &all_native_libs);
     }
 }
 
@@ -129,6 +152,9 @@ pub(crate) fn link_natively(
                   out_filename, codegen_results);
         cmd = linker.finalize();
     }
+    if crate::intrinsics::needs_libatomic(sess) {
+        cmd.arg("-latomic");
+    }
     if let Some(args) = sess.target.target.options.late_link_args.get(&flavor) {
         cmd.args(args);
     }
@@ -29,15 +29,15 @@ use crate::metadata::METADATA_FILENAME;
 
 
 // cg_clif doesn't have bytecode, so this is just a dummy
-const RLIB_BYTECODE_EXTENSION: &str = ".cg_clif_bytecode_dummy";
+pub const RLIB_BYTECODE_EXTENSION: &str = ".cg_clif_bytecode_dummy";
 
 fn archive_search_paths(sess: &Session) -> Vec<PathBuf> {
     sess.target_filesearch(PathKind::Native).search_path_dirs()
 }
 
-fn archive_config<'a>(sess: &'a Session,
-                      output: &Path,
-                      input: Option<&Path>) -> ArchiveConfig<'a> {
+pub fn archive_config<'a>(sess: &'a Session,
+                          output: &Path,
+                          input: Option<&Path>) -> ArchiveConfig<'a> {
     ArchiveConfig {
         sess,
         dst: output.to_path_buf(),
@@ -613,7 +613,43 @@ pub fn add_upstream_native_libraries(cmd: &mut dyn Linker,
     }
 }
 
-fn relevant_lib(sess: &Session, lib: &NativeLibrary) -> bool {
+pub fn print_native_static_libs(sess: &Session, all_native_libs: &[NativeLibrary]) {
+    let mut lib_args: Vec<_> = all_native_libs.iter()
+        .filter(|l| relevant_lib(sess, l))
+        .filter_map(|lib| {
+            let name = lib.name?;
+            match lib.kind {
+                NativeLibraryKind::NativeStaticNobundle |
+                NativeLibraryKind::NativeUnknown => {
+                    if sess.target.target.options.is_like_msvc {
+                        Some(format!("{}.lib", name))
+                    } else {
+                        Some(format!("-l{}", name))
+                    }
+                },
+                NativeLibraryKind::NativeFramework => {
+                    // ld-only syntax, since there are no frameworks in MSVC
+                    Some(format!("-framework {}", name))
+                },
+                // These are included, no need to print them
+                NativeLibraryKind::NativeStatic => None,
+            }
+        })
+        .collect();
+    // Unlike for cg_llvm, libatomic is needed
+    if crate::intrinsics::needs_libatomic(sess) {
+        lib_args.push("-latomic".to_string());
+    }
+    if !lib_args.is_empty() {
+        sess.note_without_error("Link against the following native artifacts when linking \
+                                 against this static library. The order and any duplication \
+                                 can be significant on some platforms.");
+        // Prefix for greppability
+        sess.note_without_error(&format!("native-static-libs: {}", &lib_args.join(" ")));
+    }
+}
+
+pub fn relevant_lib(sess: &Session, lib: &NativeLibrary) -> bool {
     match lib.cfg {
         Some(ref cfg) => attr::cfg_matches(cfg, &sess.parse_sess, None),
         None => true,
@@ -1,11 +1,14 @@
 use crate::prelude::*;
 
+use crate::base::UncompiledFunction;
+
 /// Create the `main` function which will initialize the rust runtime and call
-/// users main function.
+/// users main function. It is compiled together with the other functions of the cgu, so it gets
+/// unwind info too.
 pub fn maybe_create_entry_wrapper<'a, 'tcx: 'a>(
     tcx: TyCtxt<'a, 'tcx, 'tcx>,
     module: &mut Module<impl Backend + 'static>,
-) {
+) -> Option<UncompiledFunction> {
     use rustc::middle::lang_items::StartFnLangItem;
     use rustc::session::config::EntryFnType;
 
@@ -17,17 +20,17 @@ pub fn maybe_create_entry_wrapper<'a, 'tcx: 'a>(
                 EntryFnType::Start => false,
             },
         ),
-        None => return,
+        None => return None,
     };
 
-    create_entry_fn(tcx, module, main_def_id, use_start_lang_item);;
+    return Some(create_entry_fn(tcx, module, main_def_id, use_start_lang_item));
 
     fn create_entry_fn<'a, 'tcx: 'a>(
         tcx: TyCtxt<'a, 'tcx, 'tcx>,
         m: &mut Module<impl Backend + 'static>,
         rust_main_def_id: DefId,
         use_start_lang_item: bool,
-    ) {
+    ) -> UncompiledFunction {
         let main_ret_ty = tcx.fn_sig(rust_main_def_id).output();
         // Given that `main()` has no arguments,
         // then its return type cannot have
@@ -53,7 +56,7 @@ pub fn maybe_create_entry_wrapper<'a, 'tcx: 'a>(
 
         let instance = Instance::mono(tcx, rust_main_def_id);
 
-        let (main_name, main_sig) = get_function_name_and_sig(tcx, instance, false);
+        let (main_name, main_sig) = get_function_name_and_sig(tcx, instance);
         let main_func_id = m
             .declare_function(&main_name, Linkage::Import, &main_sig)
             .unwrap();
@@ -98,6 +101,6 @@ pub fn maybe_create_entry_wrapper<'a, 'tcx: 'a>(
             bcx.seal_all_blocks();
             bcx.finalize();
         }
-        m.define_function(cmain_func_id, &mut ctx).unwrap();
+        UncompiledFunction::new(cmain_func_id, "main".to_string(), ctx.func)
     }
 }
@@ -1,11 +1,21 @@
-use rustc::middle::cstore::MetadataLoader;
+use rustc::middle::cstore::{EncodedMetadata, MetadataLoader};
+use rustc::middle::exported_symbols::metadata_symbol_name;
 use rustc_data_structures::owning_ref::{self, OwningRef};
 use rustc_data_structures::rustc_erase_owner;
 use std::fs::File;
+use std::io::Write;
 use std::path::Path;
 
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::prelude::*;
+
 pub const METADATA_FILENAME: &str = "rust.metadata.bin";
 
+/// The section containing the metadata of dylibs
+const METADATA_SECTION: &str = ".rustc";
+
 pub struct CraneliftMetadataLoader;
 
 impl MetadataLoader for CraneliftMetadataLoader {
@@ -33,8 +43,66 @@ impl MetadataLoader for CraneliftMetadataLoader {
     fn get_dylib_metadata(
         &self,
         _target: &crate::rustc_target::spec::Target,
-        _path: &Path,
+        path: &Path,
     ) -> Result<owning_ref::ErasedBoxRef<[u8]>, String> {
-        Err("dylib metadata loading is not yet supported".to_string())
+        let buf = std::fs::read(path).map_err(|e| format!("{:?}", e))?;
+        let elf = goblin::elf::Elf::parse(&buf).map_err(|e| format!("{:?}", e))?;
+        let section = elf
+            .section_headers
+            .iter()
+            .find(|section| {
+                match elf.shdr_strtab.get(section.sh_name) {
+                    Some(Ok(name)) => name == METADATA_SECTION,
+                    _ => false,
+                }
+            })
+            .ok_or_else(|| format!("couldn't find {} section", METADATA_SECTION))?;
+        let start = section.sh_offset as usize;
+        let end = start + section.sh_size as usize;
+        if end > buf.len() {
+            return Err(format!("{} section is out of bounds", METADATA_SECTION));
+        }
+
+        let buf: OwningRef<Vec<u8>, [u8]> = OwningRef::new(buf).map(|buf| &buf[start..end]);
+        Ok(rustc_erase_owner!(buf.map_owner_box()))
     }
 }
+
+/// The object file containing the metadata of a dylib or proc-macro. Like for the LLVM backend, the
+/// metadata is compressed, prefixed with the uncompressed metadata header and stored in an
+/// allocated `.rustc` section starting at the exported metadata symbol, so `strip` keeps it.
+///
+/// faerie only emits custom sections as non-allocated debug sections, so the object is assembled
+/// by the C compiler of the target instead.
+pub fn codegen_metadata_module<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    metadata: &EncodedMetadata,
+) -> CompiledModule {
+    let mut compressed = tcx.metadata_encoding_version();
+    DeflateEncoder::new(&mut compressed, Compression::fast())
+        .write_all(&metadata.raw_data)
+        .unwrap();
+
+    let metadata_file = tcx
+        .output_filenames(LOCAL_CRATE)
+        .temp_path_ext("rmeta.deflate", Some("metadata"));
+    std::fs::write(&metadata_file, compressed).unwrap();
+
+    let asm = format!(
+        concat!(
+            "    .section {section},\"a\",@progbits\n",
+            "    .globl {name}\n",
+            "    .type {name},@object\n",
+            "{name}:\n",
+            "    .incbin \"{file}\"\n",
+            "    .size {name}, .-{name}\n",
+        ),
+        section = METADATA_SECTION,
+        name = crate::inline_asm::quoted_symbol(&metadata_symbol_name(tcx)),
+        file = metadata_file.display(),
+    );
+
+    let mut module = crate::inline_asm::assemble(tcx, "metadata", &asm);
+    module.kind = ModuleKind::Metadata;
+    module
+}
@@ -0,0 +1,107 @@
+//! Optimization passes run on the clif ir of a function before it is compiled by Cranelift.
+//!
+//! The passes to run depend on `-Copt-level` and can be overridden using the `passes` backend
+//! option, which is a comma separated list of pass names. For example
+//! `-Cllvm-args=passes=gvn,dce`. An empty list disables all passes.
+
+use rustc::session::config::OptLevel;
+
+use cranelift::codegen::isa::TargetIsa;
+
+use crate::prelude::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pass {
+    /// Constant folding and other peephole optimizations
+    Preopt,
+    /// Loop invariant code motion
+    Licm,
+    /// Global value numbering
+    Gvn,
+    /// Dead code elimination
+    Dce,
+}
+
+impl Pass {
+    fn from_name(name: &str) -> Option<Pass> {
+        match name {
+            "preopt" => Some(Pass::Preopt),
+            "licm" => Some(Pass::Licm),
+            "gvn" => Some(Pass::Gvn),
+            "dce" => Some(Pass::Dce),
+            _ => None,
+        }
+    }
+}
+
+pub fn passes(sess: &Session) -> Vec<Pass> {
+    if let Some(passes) = crate::backend_option(sess, "passes") {
+        return passes
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Pass::from_name(name).unwrap_or_else(|| {
+                    sess.fatal(&format!("Unknown pass `{}` in -Cllvm-args=passes", name))
+                })
+            })
+            .collect();
+    }
+
+    match sess.opts.optimize {
+        OptLevel::No | OptLevel::Less => vec![],
+        OptLevel::Default | OptLevel::Size | OptLevel::SizeMin => vec![Pass::Gvn, Pass::Dce],
+        OptLevel::Aggressive => vec![Pass::Licm, Pass::Gvn, Pass::Dce],
+    }
+}
+
+/// The Cranelift `opt_level` setting for `-Copt-level`.
+pub fn cranelift_opt_level(sess: &Session) -> &'static str {
+    match sess.opts.optimize {
+        OptLevel::No => "fastest",
+        // FIXME use `best` for `-Copt-level=3` once
+        // https://github.com/CraneStation/cranelift/issues/664 is fixed. Loop invariant code
+        // motion is run as a pass above instead.
+        OptLevel::Less
+        | OptLevel::Default
+        | OptLevel::Size
+        | OptLevel::SizeMin
+        | OptLevel::Aggressive => "default",
+    }
+}
+
+/// Run `passes` on the function of `ctx`. This doesn't use the `TyCtxt`, as it runs on the worker
+/// threads compiling the cgus, so errors are returned instead of reported.
+pub fn optimize_function(
+    ctx: &mut Context,
+    isa: &dyn TargetIsa,
+    passes: &[Pass],
+) -> Result<(), String> {
+    if passes.is_empty() {
+        return Ok(());
+    }
+
+    ctx.compute_cfg();
+    ctx.compute_domtree();
+
+    for &pass in passes {
+        let res = match pass {
+            Pass::Preopt => ctx.preopt(isa),
+            Pass::Licm => {
+                ctx.compute_loop_analysis();
+                ctx.licm(isa)
+            }
+            Pass::Gvn => ctx.simple_gvn(isa),
+            Pass::Dce => ctx.dce(isa),
+        };
+        if let Err(err) = res {
+            return Err(format!("cranelift {:?} pass failed: {:?}", pass, err));
+        }
+
+        // Passes may change the control flow graph
+        ctx.compute_cfg();
+        ctx.compute_domtree();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Thread local statics.
+//!
+//! Neither Cranelift nor faerie support TLS, so `#[thread_local]` statics are defined in the
+//! `.tdata` or `.tbss` section of the assembly of the cgu, see `inline_asm`. They are accessed by
+//! calling a small accessor function written in assembly, which returns the address of the static
+//! for the current thread.
+
+use std::fmt::Write;
+
+use rustc::hir::CodegenFnAttrFlags;
+use rustc::mir::interpret::{ConstValue, GlobalId};
+use rustc::session::config::CrateType;
+
+use crate::prelude::*;
+
+use crate::inline_asm::quoted_symbol;
+
+pub fn is_thread_local(tcx: TyCtxt, def_id: DefId) -> bool {
+    tcx.codegen_fn_attrs(def_id)
+        .flags
+        .contains(CodegenFnAttrFlags::THREAD_LOCAL)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TlsModel {
+    /// Works everywhere by calling `__tls_get_addr`. The linker relaxes it to a cheaper model
+    /// when possible.
+    GeneralDynamic,
+    /// Only works for statics defined in the executable itself
+    LocalExec,
+}
+
+fn tls_model(tcx: TyCtxt, def_id: DefId) -> TlsModel {
+    let only_executable = tcx
+        .sess
+        .crate_types
+        .get()
+        .iter()
+        .all(|&crate_type| crate_type == CrateType::Executable);
+    // When jitting, the thread locals are defined by a shared object, see `jit::load_global_asm`
+    let jit = std::env::var("SHOULD_RUN").is_ok();
+    if def_id.is_local() && only_executable && !jit {
+        TlsModel::LocalExec
+    } else {
+        TlsModel::GeneralDynamic
+    }
+}
+
+pub fn codegen_tls_static<'a, 'tcx: 'a>(
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    global_asm: &mut String,
+    def_id: DefId,
+) {
+    crate::inline_asm::check_target(tcx);
+
+    let symbol_name = tcx.symbol_name(Instance::mono(tcx, def_id)).as_str();
+    let const_ = tcx
+        .const_eval(ParamEnv::reveal_all().and(GlobalId {
+            instance: Instance::mono(tcx, def_id),
+            promoted: None,
+        }))
+        .unwrap();
+    let alloc = match const_.val {
+        ConstValue::ByRef(_alloc_id, alloc, n) if n.bytes() == 0 => alloc,
+        _ => bug!("static const eval returned {:#?}", const_),
+    };
+    if !alloc.relocations.is_empty() {
+        unimpl!("Thread local static {} contains pointers", symbol_name);
+    }
+
+    let symbol = quoted_symbol(&symbol_name);
+    if alloc.bytes.iter().all(|&byte| byte == 0) {
+        writeln!(global_asm, "    .section .tbss,\"awT\",@nobits").unwrap();
+    } else {
+        writeln!(global_asm, "    .section .tdata,\"awT\",@progbits").unwrap();
+    }
+    writeln!(global_asm, "    .globl {}", symbol).unwrap();
+    writeln!(global_asm, "    .type {},@object", symbol).unwrap();
+    writeln!(global_asm, "    .balign {}", alloc.align.bytes()).unwrap();
+    writeln!(global_asm, "{}:", symbol).unwrap();
+    if alloc.bytes.iter().all(|&byte| byte == 0) {
+        writeln!(global_asm, "    .zero {}", alloc.bytes.len()).unwrap();
+    } else {
+        for chunk in alloc.bytes.chunks(16) {
+            let bytes = chunk.iter().map(|byte| byte.to_string()).collect::<Vec<_>>();
+            writeln!(global_asm, "    .byte {}", bytes.join(",")).unwrap();
+        }
+    }
+    writeln!(global_asm, "    .size {}, {}", symbol, alloc.bytes.len()).unwrap();
+    writeln!(global_asm).unwrap();
+}
+
+pub fn codegen_tls_ref<'a, 'tcx: 'a>(
+    fx: &mut FunctionCx<'a, 'tcx, impl Backend>,
+    static_: &Static<'tcx>,
+) -> CPlace<'tcx> {
+    crate::inline_asm::check_target(fx.tcx);
+
+    let symbol_name = fx.tcx.symbol_name(Instance::mono(fx.tcx, static_.def_id)).as_str();
+    let accessor_name = format!("{}.tls_addr", symbol_name);
+
+    // Every cgu defines the accessors it needs. They are weak, so the definitions of
+    // different cgus and crates get merged by the linker.
+    let symbol = quoted_symbol(&symbol_name);
+    let accessor = quoted_symbol(&accessor_name);
+    let accessor_label = format!("{}:", accessor);
+    if !fx.global_asm.contains(&accessor_label) {
+        let global_asm = &mut *fx.global_asm;
+        writeln!(global_asm, "    .text").unwrap();
+        writeln!(global_asm, "    .weak {}", accessor).unwrap();
+        writeln!(global_asm, "    .hidden {}", accessor).unwrap();
+        writeln!(global_asm, "    .type {},@function", accessor).unwrap();
+        writeln!(global_asm, "{}", accessor_label).unwrap();
+        match tls_model(fx.tcx, static_.def_id) {
+            TlsModel::GeneralDynamic => {
+                // Align the stack for the call
+                writeln!(global_asm, "    push %rbp").unwrap();
+                // The linker recognizes this exact sequence, so it must not be changed
+                writeln!(global_asm, "    .byte 0x66").unwrap();
+                writeln!(global_asm, "    leaq {}@tlsgd(%rip), %rdi", symbol).unwrap();
+                writeln!(global_asm, "    .value 0x6666").unwrap();
+                writeln!(global_asm, "    rex64").unwrap();
+                writeln!(global_asm, "    call __tls_get_addr@PLT").unwrap();
+                writeln!(global_asm, "    pop %rbp").unwrap();
+            }
+            TlsModel::LocalExec => {
+                writeln!(global_asm, "    movq %fs:0, %rax").unwrap();
+                writeln!(global_asm, "    leaq {}@tpoff(%rax), %rax", symbol).unwrap();
+            }
+        }
+        writeln!(global_asm, "    ret").unwrap();
+        writeln!(global_asm, "    .size {name}, .-{name}", name = accessor).unwrap();
+        writeln!(global_asm).unwrap();
+    }
+
+    let pointer_type = fx.pointer_type;
+    let addr = fx.lib_call(&accessor_name, vec![], Some(pointer_type), &[]).unwrap();
+    let layout = fx.layout_of(fx.monomorphize(&static_.ty));
+    CPlace::Addr(addr, None, layout)
+}
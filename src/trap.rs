@@ -3,6 +3,9 @@ use cranelift::prelude::*;
 use cranelift::codegen::ir::TrapCode;
 
 /// Trap code: user0
+///
+/// Failed assertions only use it when `-Cllvm-args=trap-on-assert` is passed. Otherwise they
+/// call the `panic` or `panic_bounds_check` lang item.
 pub fn trap_panic(bcx: &mut FunctionBuilder) {
     bcx.ins().trap(TrapCode::User(0));
 }
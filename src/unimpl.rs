@@ -20,7 +20,7 @@ pub macro unimpl($($tt:tt)*) {
     panic!(NonFatal(format!($($tt)*)));
 }
 
-pub fn try_unimpl(tcx: TyCtxt, log: &mut Option<File>, f: impl FnOnce()) {
+pub fn try_unimpl(tcx: TyCtxt, log: &Option<File>, f: impl FnOnce()) {
     let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| f()));
 
     if let Err(err) = res {
@@ -29,7 +29,7 @@ pub fn try_unimpl(tcx: TyCtxt, log: &mut Option<File>, f: impl FnOnce()) {
                 Ok(non_fatal) => {
                     if cfg!(debug_assertions) {
                         writeln!(
-                            log.as_mut().unwrap(),
+                            &mut log.as_ref().unwrap(),
                             "{} at {:?}",
                             &non_fatal.0,
                             span_stack.borrow()
@@ -0,0 +1,519 @@
+//! Unwinding support: landing pads, the LSDA (`.gcc_except_table` contents) and `.eh_frame`.
+//!
+//! Cranelift has no notion of unwind edges. To still be able to codegen MIR cleanup blocks, every
+//! call that has a cleanup block is followed by a conditional branch to its landing pad. The
+//! condition is loaded from a stack slot which is always zero, so the branch is never taken at
+//! runtime, but it keeps everything the landing pad needs alive and in the same place as right
+//! after the call. This is where the personality routine resumes execution when unwinding.
+//!
+//! When panics abort, `.eh_frame` is still emitted, but without landing pads and LSDAs. Debuggers,
+//! profilers and the backtrace printer of std need it to walk the stack.
+
+use cranelift::codegen::ir::{InstructionData, Opcode, ValueLoc};
+use cranelift::codegen::isa::RegUnit;
+
+use gimli::write::{
+    Address, CallFrameInstruction, CieId, CommonInformationEntry, EhFrame, FrameDescriptionEntry,
+    FrameTable,
+};
+use gimli::{Encoding, Format, Register, RunTimeEndian, X86_64};
+
+use rustc_target::spec::PanicStrategy;
+
+use faerie::*;
+
+use crate::debuginfo::{declare_section, link_section, target_endian, WriterRelocate};
+use crate::prelude::*;
+
+/// Returns the exception pointer when called at the start of a landing pad.
+///
+/// The personality routine passes the exception pointer in `rax`. As this function consists of
+/// only a `ret` instruction, `rax` is returned unchanged.
+const GET_EXCEPTION_PTR: &str = "__cg_clif_get_exception_ptr";
+
+/// Cranelift register units of the x86_64 general purpose registers in the order of their
+/// encoding. Register unit `n` is the register with encoding `n`.
+const X86_64_GPRS: [Register; 16] = [
+    X86_64::RAX,
+    X86_64::RCX,
+    X86_64::RDX,
+    X86_64::RBX,
+    X86_64::RSP,
+    X86_64::RBP,
+    X86_64::RSI,
+    X86_64::RDI,
+    X86_64::R8,
+    X86_64::R9,
+    X86_64::R10,
+    X86_64::R11,
+    X86_64::R12,
+    X86_64::R13,
+    X86_64::R14,
+    X86_64::R15,
+];
+
+fn pcrel_sdata4() -> gimli::DwEhPe {
+    gimli::DwEhPe(gimli::DW_EH_PE_pcrel.0 | gimli::DW_EH_PE_sdata4.0)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CallSite {
+    call_inst: Inst,
+    landing_pad: Ebb,
+    /// `true` for the `try` intrinsic, `false` for cleanup blocks.
+    catch: bool,
+}
+
+pub struct LandingPads {
+    /// Always contains zero. Used as condition for the branches to the landing pads.
+    never_slot: StackSlot,
+    /// Stores the exception pointer between a landing pad and `Resume`.
+    exception_slot: StackSlot,
+    pub call_sites: Vec<CallSite>,
+}
+
+impl LandingPads {
+    pub fn new(bcx: &mut FunctionBuilder, pointer_type: Type) -> Self {
+        let never_slot = bcx.create_stack_slot(StackSlotData {
+            kind: StackSlotKind::ExplicitSlot,
+            size: 4,
+            offset: None,
+        });
+        let exception_slot = bcx.create_stack_slot(StackSlotData {
+            kind: StackSlotKind::ExplicitSlot,
+            size: pointer_type.bytes(),
+            offset: None,
+        });
+        LandingPads {
+            never_slot,
+            exception_slot,
+            call_sites: Vec::new(),
+        }
+    }
+}
+
+/// Must be called in the start ebb
+pub fn codegen_landing_pads_prelude(fx: &mut FunctionCx<impl Backend>) {
+    if let Some(never_slot) = fx.landing_pads.as_ref().map(|lp| lp.never_slot) {
+        let zero = fx.bcx.ins().iconst(types::I32, 0);
+        fx.bcx.ins().stack_store(zero, never_slot, 0);
+    }
+}
+
+/// Add an unwind edge from `call_inst` to a new landing pad.
+///
+/// Returns the landing pad and the ebb in which the normal return path continues. Neither ebb
+/// is selected afterwards.
+pub fn add_unwind_edge(
+    fx: &mut FunctionCx<impl Backend>,
+    call_inst: Inst,
+    catch: bool,
+) -> (Ebb, Ebb) {
+    let never_slot = fx
+        .landing_pads
+        .as_ref()
+        .expect("unwind edge without landing pads")
+        .never_slot;
+
+    let landing_pad = fx.bcx.create_ebb();
+    let continue_ebb = fx.bcx.create_ebb();
+
+    let never = fx.bcx.ins().stack_load(types::I32, never_slot, 0);
+    fx.bcx.ins().brnz(never, landing_pad, &[]);
+    fx.bcx.ins().jump(continue_ebb, &[]);
+
+    #[cfg(debug_assertions)]
+    fx.add_comment(call_inst, format!("landing pad {}", landing_pad));
+
+    fx.landing_pads.as_mut().unwrap().call_sites.push(CallSite {
+        call_inst,
+        landing_pad,
+        catch,
+    });
+
+    (landing_pad, continue_ebb)
+}
+
+/// Must be the first thing in a landing pad
+pub fn codegen_get_exception_ptr(fx: &mut FunctionCx<impl Backend>) -> Value {
+    let pointer_type = fx.pointer_type;
+    fx.lib_call(GET_EXCEPTION_PTR, vec![], Some(pointer_type), &[])
+        .unwrap()
+}
+
+/// Connect `call_inst` to the `cleanup` block when landing pads are enabled. Afterwards the
+/// normal return path of the call is selected.
+pub fn codegen_cleanup_edge(
+    fx: &mut FunctionCx<impl Backend>,
+    call_inst: Inst,
+    cleanup: Option<BasicBlock>,
+) {
+    let cleanup = match cleanup {
+        Some(cleanup) if fx.landing_pads.is_some() => cleanup,
+        _ => return,
+    };
+
+    let (landing_pad, continue_ebb) = add_unwind_edge(fx, call_inst, false);
+
+    fx.bcx.switch_to_block(landing_pad);
+    let exception = codegen_get_exception_ptr(fx);
+    let exception_slot = fx.landing_pads.as_ref().unwrap().exception_slot;
+    fx.bcx.ins().stack_store(exception, exception_slot, 0);
+    let cleanup_ebb = fx.get_ebb(cleanup);
+    fx.bcx.ins().jump(cleanup_ebb, &[]);
+
+    fx.bcx.switch_to_block(continue_ebb);
+}
+
+pub fn codegen_resume(fx: &mut FunctionCx<impl Backend>) {
+    if let Some(exception_slot) = fx.landing_pads.as_ref().map(|lp| lp.exception_slot) {
+        let pointer_type = fx.pointer_type;
+        let exception = fx.bcx.ins().stack_load(pointer_type, exception_slot, 0);
+        fx.lib_call("_Unwind_Resume", vec![pointer_type], None, &[exception]);
+    }
+    trap_unreachable(&mut fx.bcx);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnwindInfoKind {
+    /// Only call frame information
+    CallFrames,
+    /// Call frame information, landing pads and LSDAs
+    Unwinding,
+}
+
+/// The kind of unwind info to emit, or `None` if the target isn't supported.
+pub fn unwind_info_kind(sess: &Session) -> Option<UnwindInfoKind> {
+    let target = &sess.target.target;
+    let is_supported = target.arch == "x86_64"
+        && !target.options.is_like_osx
+        && !target.options.is_like_windows;
+
+    match (sess.panic_strategy(), is_supported) {
+        (PanicStrategy::Unwind, true) => Some(UnwindInfoKind::Unwinding),
+        (PanicStrategy::Abort, true) => Some(UnwindInfoKind::CallFrames),
+        (PanicStrategy::Unwind, false) => {
+            sess.warn("Unwinding is only supported on x86_64 ELF targets. Panics will abort instead.");
+            None
+        }
+        (PanicStrategy::Abort, false) => None,
+    }
+}
+
+pub struct UnwindContext {
+    endian: RunTimeEndian,
+    kind: UnwindInfoKind,
+    symbols: indexmap::IndexSet<String>,
+    frame_table: FrameTable,
+    cie_id: CieId,
+}
+
+impl UnwindContext {
+    pub fn new<'a, 'tcx: 'a>(
+        tcx: TyCtxt<'a, 'tcx, 'tcx>,
+        module: &mut Module<impl Backend>,
+        kind: UnwindInfoKind,
+    ) -> Self {
+        let pointer_type = module.target_config().pointer_type();
+        let mut symbols = indexmap::IndexSet::new();
+
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 1,
+            address_size: pointer_type.bytes() as u8,
+        };
+        let mut cie = CommonInformationEntry::new(encoding, 1, -8, X86_64::RA);
+        cie.fde_address_encoding = pcrel_sdata4();
+
+        let personality = match kind {
+            UnwindInfoKind::CallFrames => None,
+            UnwindInfoKind::Unwinding => {
+                cie.lsda_encoding = Some(pcrel_sdata4());
+                tcx.lang_items().eh_personality()
+            }
+        };
+
+        // The personality function may be defined in another shared object, so reference it
+        // through a pointer, like LLVM does.
+        if let Some(personality) = personality {
+            let personality = Instance::mono(tcx, personality);
+            let func_id = crate::abi::import_function(tcx, module, personality);
+
+            let name = "DW.ref.rust_eh_personality";
+            let data_id = module.declare_data(name, Linkage::Local, false).unwrap();
+            let mut data_ctx = DataContext::new();
+            data_ctx.define_zeroinit(pointer_type.bytes() as usize);
+            let func_ref = module.declare_func_in_data(func_id, &mut data_ctx);
+            data_ctx.write_function_addr(0, func_ref);
+            module.define_data(data_id, &data_ctx).unwrap();
+
+            let (symbol, _) = symbols.insert_full(name.to_string());
+            cie.personality = Some((
+                gimli::DwEhPe(gimli::DW_EH_PE_indirect.0 | pcrel_sdata4().0),
+                Address::Relative { symbol, addend: 0 },
+            ));
+        }
+
+        cie.add_instruction(CallFrameInstruction::Cfa(X86_64::RSP, 8));
+        cie.add_instruction(CallFrameInstruction::Offset(X86_64::RA, -8));
+
+        let mut frame_table = FrameTable::default();
+        let cie_id = frame_table.add_cie(cie);
+
+        UnwindContext {
+            endian: target_endian(tcx),
+            kind,
+            symbols,
+            frame_table,
+            cie_id,
+        }
+    }
+
+    pub fn has_landing_pads(&self) -> bool {
+        self.kind == UnwindInfoKind::Unwinding
+    }
+
+    pub fn add_function(
+        &mut self,
+        module: &mut Module<impl Backend>,
+        name: &str,
+        context: &Context,
+        call_sites: &[CallSite],
+    ) {
+        let func = &context.func;
+        let encinfo = module.isa().encoding_info();
+
+        let mut ebbs = func.layout.ebbs().collect::<Vec<_>>();
+        ebbs.sort_by_key(|ebb| func.offsets[*ebb]); // Ensure inst offsets always increase
+        let insts = ebbs
+            .into_iter()
+            .flat_map(|ebb| func.inst_offsets(ebb, &encinfo))
+            .collect::<Vec<_>>();
+        let end = insts.last().map(|&(offset, _, size)| offset + size).unwrap_or(0);
+
+        let (symbol, _) = self.symbols.insert_full(name.to_string());
+        let mut fde = FrameDescriptionEntry::new(Address::Relative { symbol, addend: 0 }, end);
+        add_cfi_for_func(&mut fde, func, &insts);
+
+        if !call_sites.is_empty() {
+            let lsda = lsda_for_call_sites(func, &insts, call_sites);
+            let lsda_name = format!("GCC_except_table_{}", name);
+            let data_id = module
+                .declare_data(&lsda_name, Linkage::Local, false)
+                .unwrap();
+            let mut data_ctx = DataContext::new();
+            data_ctx.define(lsda.into_boxed_slice());
+            module.define_data(data_id, &data_ctx).unwrap();
+
+            let (lsda_symbol, _) = self.symbols.insert_full(lsda_name);
+            fde.lsda = Some(Address::Relative {
+                symbol: lsda_symbol,
+                addend: 0,
+            });
+        }
+
+        self.frame_table.add_fde(self.cie_id, fde);
+    }
+
+    pub fn emit(self, artifact: &mut Artifact) {
+        let mut eh_frame = EhFrame(WriterRelocate::new(self.endian));
+        self.frame_table.write_eh_frame(&mut eh_frame).unwrap();
+
+        declare_section(artifact, ".eh_frame", &mut eh_frame.0);
+        link_section(artifact, &self.symbols, ".eh_frame", &eh_frame.0);
+
+        artifact
+            .declare_with(
+                GET_EXCEPTION_PTR,
+                Decl::Function { global: false },
+                vec![0xc3], // ret
+            )
+            .unwrap();
+    }
+}
+
+/// faerie can only emit `.eh_frame` as a debug section, which isn't loaded at runtime. Mark it as
+/// allocated in the emitted ELF object, so the unwinder and `.eh_frame_hdr` can find it.
+pub fn mark_eh_frame_allocated(obj: &mut [u8]) {
+    use goblin::elf::section_header::SHF_ALLOC;
+
+    // Offsets of `sh_flags` and `sh_addralign` in an ELF64 section header
+    const SH_FLAGS: usize = 8;
+    const SH_ADDRALIGN: usize = 48;
+
+    let (header_offset, flags, little_endian) = match goblin::elf::Elf::parse(obj) {
+        Ok(ref elf) if elf.is_64 => {
+            let index = elf.section_headers.iter().position(|section| {
+                match elf.shdr_strtab.get(section.sh_name) {
+                    Some(Ok(name)) => name == ".eh_frame",
+                    _ => false,
+                }
+            });
+            match index {
+                Some(index) => (
+                    elf.header.e_shoff as usize + index * elf.header.e_shentsize as usize,
+                    elf.section_headers[index].sh_flags | u64::from(SHF_ALLOC),
+                    elf.little_endian,
+                ),
+                None => return,
+            }
+        }
+        _ => return,
+    };
+
+    let write_u64 = |obj: &mut [u8], offset: usize, val: u64| {
+        let bytes = if little_endian { val.to_le_bytes() } else { val.to_be_bytes() };
+        obj[header_offset + offset..header_offset + offset + 8].copy_from_slice(&bytes);
+    };
+    write_u64(obj, SH_FLAGS, flags);
+    // The entries written by gimli are aligned to the address size
+    write_u64(obj, SH_ADDRALIGN, 8);
+}
+
+/// The DWARF register of a Cranelift register unit. Units 16 to 31 are `xmm0` to `xmm15`.
+pub fn dwarf_reg(reg: RegUnit) -> Register {
+    match reg {
+        0..=15 => X86_64_GPRS[reg as usize],
+        16..=31 => Register(X86_64::XMM0.0 + (reg - 16)),
+        _ => bug!("register unit {} has no DWARF register", reg),
+    }
+}
+
+fn dwarf_reg_of_value(func: &Function, value: Value) -> Register {
+    match func.locations[value] {
+        ValueLoc::Reg(reg) => dwarf_reg(reg),
+        loc => bug!("{} is not in a register: {:?}", value, loc),
+    }
+}
+
+/// Describe the prologue and epilogues generated by Cranelift.
+///
+/// The prologue is `push rbp; mov rbp, rsp; push <callee saved regs>; sub rsp, <frame size>`.
+/// Every epilogue is `add rsp, <frame size>; pop <callee saved regs>; pop rbp; ret`.
+fn add_cfi_for_func(
+    fde: &mut FrameDescriptionEntry,
+    func: &Function,
+    insts: &[(u32, Inst, u32)],
+) {
+    let mut in_prologue = true;
+    let mut cfa_reg = X86_64::RSP;
+    let mut cfa_offset = 8;
+    let mut remembered_state = false;
+
+    for &(offset, inst, size) in insts {
+        let next = offset + size;
+        let opcode = func.dfg[inst].opcode();
+
+        if in_prologue {
+            match func.dfg[inst] {
+                InstructionData::Unary {
+                    opcode: Opcode::X86Push,
+                    arg,
+                } => {
+                    cfa_offset += 8;
+                    if cfa_reg == X86_64::RSP {
+                        fde.add_instruction(next, CallFrameInstruction::CfaOffset(cfa_offset));
+                    }
+                    let reg = dwarf_reg_of_value(func, arg);
+                    fde.add_instruction(next, CallFrameInstruction::Offset(reg, -cfa_offset));
+                }
+                InstructionData::CopySpecial {
+                    opcode: Opcode::CopySpecial,
+                    src,
+                    dst,
+                } => {
+                    assert_eq!(dwarf_reg(src), X86_64::RSP);
+                    cfa_reg = dwarf_reg(dst);
+                    fde.add_instruction(next, CallFrameInstruction::CfaRegister(cfa_reg));
+                }
+                _ if opcode == Opcode::AdjustSpDownImm => {}
+                _ => in_prologue = false,
+            }
+        }
+
+        if !in_prologue {
+            match opcode {
+                Opcode::X86Pop => {
+                    let reg = dwarf_reg_of_value(func, func.dfg.first_result(inst));
+                    if reg == X86_64::RBP {
+                        // Code after the return still uses the state from the end of the prologue
+                        fde.add_instruction(offset, CallFrameInstruction::RememberState);
+                        fde.add_instruction(next, CallFrameInstruction::Cfa(X86_64::RSP, 8));
+                        remembered_state = true;
+                    }
+                }
+                Opcode::Return | Opcode::FallthroughReturn if remembered_state => {
+                    fde.add_instruction(next, CallFrameInstruction::RestoreState);
+                    remembered_state = false;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Build the language specific data area, which tells the personality function where the landing
+/// pads are.
+fn lsda_for_call_sites(
+    func: &Function,
+    insts: &[(u32, Inst, u32)],
+    call_sites: &[CallSite],
+) -> Vec<u8> {
+    use gimli::leb128::write::{signed, unsigned};
+
+    let mut call_sites = call_sites
+        .iter()
+        .map(|call_site| {
+            let &(offset, _, size) = insts
+                .iter()
+                .find(|&&(_, inst, _)| inst == call_site.call_inst)
+                .expect("call site not in layout");
+            let landing_pad = func.offsets[call_site.landing_pad];
+            (offset, size, landing_pad, call_site.catch)
+        })
+        .collect::<Vec<_>>();
+    // The personality function expects the call site table to be sorted
+    call_sites.sort_by_key(|&(offset, _, _, _)| offset);
+    let has_catch = call_sites.iter().any(|&(_, _, _, catch)| catch);
+
+    let mut call_site_table = Vec::new();
+    for (offset, size, landing_pad, catch) in call_sites {
+        unsigned(&mut call_site_table, offset.into()).unwrap();
+        unsigned(&mut call_site_table, size.into()).unwrap();
+        unsigned(&mut call_site_table, landing_pad.into()).unwrap();
+        // Action 0 is cleanup, action 1 is the first entry of the action table
+        unsigned(&mut call_site_table, if catch { 1 } else { 0 }).unwrap();
+    }
+
+    let mut actions_and_types = Vec::new();
+    if has_catch {
+        // Action table: catch type filter 1, no next action
+        signed(&mut actions_and_types, 1).unwrap();
+        signed(&mut actions_and_types, 0).unwrap();
+        // Type table: type filter 1 is a null pointer, which catches everything
+        actions_and_types.extend_from_slice(&[0; 4]);
+    }
+
+    let mut lsda = Vec::new();
+    lsda.push(gimli::DW_EH_PE_omit.0); // @LPStart encoding
+    if has_catch {
+        lsda.push(gimli::DW_EH_PE_udata4.0); // @TType encoding
+        let mut rest = Vec::new();
+        rest.push(gimli::DW_EH_PE_uleb128.0); // Call site encoding
+        unsigned(&mut rest, call_site_table.len() as u64).unwrap();
+        // @TType base offset
+        unsigned(
+            &mut lsda,
+            (rest.len() + call_site_table.len() + actions_and_types.len()) as u64,
+        )
+        .unwrap();
+        lsda.extend(rest);
+    } else {
+        lsda.push(gimli::DW_EH_PE_omit.0); // @TType encoding
+        lsda.push(gimli::DW_EH_PE_uleb128.0); // Call site encoding
+        unsigned(&mut lsda, call_site_table.len() as u64).unwrap();
+    }
+    lsda.extend(call_site_table);
+    lsda.extend(actions_and_types);
+    lsda
+}
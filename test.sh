@@ -13,10 +13,18 @@ $RUSTC example/example.rs --crate-type lib
 echo "[JIT] mini_core_hello_world"
 SHOULD_RUN=1 JIT_ARGS="abc bcd" $RUSTC --crate-type bin example/mini_core_hello_world.rs --cfg jit
 
+echo "[JIT-lazy] mini_core_hello_world"
+SHOULD_RUN=1 JIT_LAZY=1 JIT_ARGS="abc bcd" $RUSTC --crate-type bin example/mini_core_hello_world.rs --cfg jit
+
 echo "[AOT] mini_core_hello_world"
 $RUSTC example/mini_core_hello_world.rs --crate-name mini_core_hello_world --crate-type bin
 ./target/out/mini_core_hello_world abc bcd
 
+echo "[AOT] mini_core_hello_world with DWARF 5"
+$RUSTC example/mini_core_hello_world.rs --crate-name mini_core_hello_world_dwarf5 --crate-type bin -Cllvm-args=dwarf-version=5
+readelf --debug-dump=info target/out/mini_core_hello_world_dwarf5 | grep -q "Version: *5$"
+./target/out/mini_core_hello_world_dwarf5 abc bcd
+
 echo "[BUILD] sysroot"
 time ./build_sysroot/build_sysroot.sh
 
@@ -24,10 +32,98 @@ echo "[BUILD+RUN] alloc_example"
 $RUSTC --sysroot ./build_sysroot/sysroot example/alloc_example.rs --crate-type bin
 ./target/out/alloc_example
 
+echo "[JIT] alloc_example"
+SHOULD_RUN=1 $RUSTC --sysroot ./build_sysroot/sysroot example/alloc_example.rs --crate-type bin
+
 echo "[BUILD+RUN] std_example"
 $RUSTC --sysroot ./build_sysroot/sysroot example/std_example.rs --crate-type bin
 ./target/out/std_example
 
+echo "[JIT] std_example"
+SHOULD_RUN=1 $RUSTC --sysroot ./build_sysroot/sysroot example/std_example.rs --crate-type bin
+
+echo "[JIT-lazy] std_example"
+SHOULD_RUN=1 JIT_LAZY=1 $RUSTC --sysroot ./build_sysroot/sysroot example/std_example.rs --crate-type bin
+
+echo "[JIT-lazy] jit_thread_example"
+SHOULD_RUN=1 JIT_LAZY=1 $RUSTC --sysroot ./build_sysroot/sysroot example/jit_thread_example.rs --crate-type bin
+
+echo "[BUILD+RUN] panic_example"
+$RUSTC --sysroot ./build_sysroot/sysroot example/panic_example.rs --crate-type bin
+if ./target/out/panic_example 2> target/out/panic_example.stderr; then
+    echo "panic_example didn't panic"
+    exit 1
+fi
+cat target/out/panic_example.stderr
+grep -qF "panicked at 'index out of bounds: the len is 3 but the index is 3', example/panic_example.rs:7:17" target/out/panic_example.stderr
+
+echo "[BUILD+RUN] catch_unwind_example"
+$RUSTC_UNWIND --sysroot ./build_sysroot/sysroot example/catch_unwind_example.rs --crate-type bin
+./target/out/catch_unwind_example
+
+echo "[BUILD+RUN] debuginfo_example"
+$RUSTC --sysroot ./build_sysroot/sysroot example/debuginfo_example.rs --crate-type bin -O
+./target/out/debuginfo_example
+# Only look at the compilation units of the example, not those of the sysroot
+readelf --debug-dump=info target/out/debuginfo_example \
+    | awk '/Compilation Unit @/ { in_cu = 0 } /DW_AT_name.*debuginfo_example\.rs/ { in_cu = 1 } in_cu' \
+    > target/out/debuginfo_example_info.txt
+grep -q "DW_TAG_formal_parameter" target/out/debuginfo_example_info.txt
+grep -q "DW_TAG_variable" target/out/debuginfo_example_info.txt
+grep -q "DW_TAG_namespace" target/out/debuginfo_example_info.txt
+grep -q "DW_TAG_lexical_block" target/out/debuginfo_example_info.txt
+grep -q "(location list)" target/out/debuginfo_example_info.txt
+readelf --debug-dump=loc target/out/debuginfo_example | grep -q "DW_OP_"
+# `$RUSTC` uses panic=abort, but `.eh_frame` is still emitted
+main_addr=$(nm target/out/debuginfo_example | awk '$3 == "main" { print $1 }')
+readelf --debug-dump=frames target/out/debuginfo_example | grep -q "pc=$main_addr\.\."
+
+echo "[BUILD+RUN] incremental_example"
+rm -rf target/out/incr
+cp example/incremental_example.rs target/out/incremental_example.rs
+$RUSTC --sysroot ./build_sysroot/sysroot target/out/incremental_example.rs --crate-type bin -Cincremental=target/out/incr
+[[ "$(./target/out/incremental_example)" == "41" ]]
+sed -i 's/^        1$/        2/' target/out/incremental_example.rs
+$RUSTC --sysroot ./build_sysroot/sysroot target/out/incremental_example.rs --crate-type bin -Cincremental=target/out/incr -Zincremental-info > target/out/incremental_info.txt
+cat target/out/incremental_info.txt
+# The cgu of the `unchanged` module is reused, but not the one of `changed`
+grep -q "^\[incremental\] Re-using [1-9][0-9]* out of" target/out/incremental_info.txt
+if grep -q "^\[incremental\] Re-using \([0-9]*\) out of \1 modules" target/out/incremental_info.txt; then
+    echo "the changed cgu was reused"
+    exit 1
+fi
+[[ "$(./target/out/incremental_example)" == "42" ]]
+
+echo "[BUILD+RUN] thread_example"
+$RUSTC --sysroot ./build_sysroot/sysroot example/thread_example.rs --crate-type bin
+./target/out/thread_example
+
+echo "[JIT] thread_example"
+SHOULD_RUN=1 $RUSTC --sysroot ./build_sysroot/sysroot example/thread_example.rs --crate-type bin
+
+echo "[JIT-lazy] thread_example"
+SHOULD_RUN=1 JIT_LAZY=1 $RUSTC --sysroot ./build_sysroot/sysroot example/thread_example.rs --crate-type bin
+
+echo "[BUILD+RUN] ffi_lib staticlib"
+$RUSTC --sysroot ./build_sysroot/sysroot example/ffi_lib.rs --crate-type staticlib --print native-static-libs 2> target/out/ffi_lib_native_libs.txt
+cat target/out/ffi_lib_native_libs.txt
+native_static_libs=$(sed -n 's/^note: native-static-libs: //p' target/out/ffi_lib_native_libs.txt)
+cc example/ffi_lib_main.c target/out/libffi_lib.a -o target/out/ffi_lib_main $native_static_libs
+./target/out/ffi_lib_main
+
+echo "[BUILD] ffi_lib cdylib"
+$RUSTC --sysroot ./build_sysroot/sysroot example/ffi_lib.rs --crate-type cdylib
+nm -D --defined-only target/out/libffi_lib.so | grep -q ffi_lib_sum
+if nm -D --defined-only target/out/libffi_lib.so | grep -q 7ffi_lib3add; then
+    echo "cdylib exports non #[no_mangle] symbols"
+    exit 1
+fi
+
+echo "[BUILD+RUN] proc_macro_example"
+$RUSTC --sysroot ./build_sysroot/sysroot example/proc_macro_derive.rs --crate-type proc-macro
+$RUSTC --sysroot ./build_sysroot/sysroot example/proc_macro_example.rs --crate-type bin
+./target/out/proc_macro_example
+
 echo "[BUILD] mod_bench"
 $RUSTC --sysroot ./build_sysroot/sysroot example/mod_bench.rs --crate-type bin
 